@@ -0,0 +1,447 @@
+//! Craig, Landin, and Hagersten's (CLH) queueing spinlock.
+//!
+//! This has the same interface as the MCS `Mutex`, but instead of each waiter spinning on a flag that its predecessor
+//! will clear, each waiter spins directly on its predecessor's node. Releasing the lock is then always a single store
+//! into the holder's node, with no compare-and-swap and no waiting for a successor to finish queueing up.
+//!
+//! As in the textbook algorithm, nodes migrate between threads: a thread that gets the lock takes ownership of its
+//! predecessor's node, and leaves its own behind in the queue for its successor to take. A `Slot` is just a handle to
+//! whichever node it currently owns, so nodes are allocated on the heap, and a slot frees its node when it is dropped
+//! without ever having to wait for anyone. The first node is allocated lazily, so that `Mutex::new` can be `const`.
+//!
+//! `try_lock` cannot take its node back out of the queue once it is there, so if the lock is held, it abandons the node,
+//! leaving it pointing at its predecessor. Whoever comes next frees it and waits on that predecessor instead.
+//!
+//! This needs the `std` feature, since the nodes are allocated with `Box`.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+use std::boxed::Box;
+
+use pause::pause;
+
+// Node states. Any other value is the predecessor of a node abandoned by `try_lock`.
+const FREE: usize = 0;
+const LOCKED: usize = 1;
+
+struct Node {
+    state: AtomicUsize
+}
+
+/// A handle to the queue node used to lock a `Mutex`.
+///
+/// The node it owns changes every time it is used to acquire a contended lock, so a slot is not tied to any one mutex.
+pub struct Slot {
+    node: *mut Node,
+    // The node of the thread we were queued behind, which becomes ours once we release the lock.
+    pred: *mut Node
+}
+
+unsafe impl Send for Slot { }
+unsafe impl Sync for Slot { }
+
+/// An RAII implementation of a "scoped lock" of a mutex. When this structure is
+/// dropped (falls out of scope), the lock will be unlocked.
+///
+/// The data protected by the mutex can be access through this guard via its
+/// `Deref` and `DerefMut` implementations
+#[must_use]
+pub struct Guard<'a, T: ?Sized + 'a> {
+    lock: &'a Mutex<T>,
+    slot: &'a mut Slot
+}
+
+/// A mutual exclusion primitive useful for protecting shared data, based on the CLH lock.
+///
+/// This can be used exactly like `mcs::Mutex`, just with `mcs::clh::Slot` instead of `mcs::Slot`.
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::Arc;
+/// use std::thread;
+/// use mcs::clh::{Mutex, Slot};
+///
+/// let data = Arc::new(Mutex::new(0));
+///
+/// let threads: Vec<_> = (0..10).map(|_| {
+///     let data = data.clone();
+///     thread::spawn(move || {
+///         let mut slot = Slot::new();
+///         *data.lock(&mut slot) += 1;
+///     })
+/// }).collect();
+///
+/// for thread in threads {
+///     thread.join().unwrap();
+/// }
+///
+/// let mut slot = Slot::new();
+/// assert_eq!(*data.lock(&mut slot), 10);
+/// ```
+pub struct Mutex<T: ?Sized> {
+    tail: Tail,
+    data: UnsafeCell<T>
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> { }
+unsafe impl<T: Send> Send for Mutex<T> { }
+
+// The tail of the queue. Null until the lock is first used, after which it always points at the node that the next
+// thread to queue up will wait on. That node belongs to the queue until someone takes it.
+struct Tail(AtomicPtr<Node>);
+
+impl Node {
+    fn alloc() -> *mut Node {
+        Box::into_raw(Box::new(Node {
+            state: AtomicUsize::new(FREE)
+        }))
+    }
+}
+
+// Waits until `pred` is released, freeing any abandoned nodes along the way, and returns the node that was released,
+// which the caller now owns. If `pred` is null, there is nothing to wait for.
+unsafe fn wait_for(mut pred: *mut Node) -> *mut Node {
+    while !pred.is_null() {
+        match (*pred).state.load(Ordering::Acquire) {
+            FREE => break,
+            LOCKED => pause(),
+            abandoned => {
+                // No one else will ever look at an abandoned node but the thread queued right behind it.
+                drop(Box::from_raw(pred));
+                pred = abandoned as *mut Node;
+            }
+        }
+    }
+    pred
+}
+
+impl Slot {
+    pub const fn new() -> Slot {
+        Slot {
+            node: ptr::null_mut(),
+            pred: ptr::null_mut()
+        }
+    }
+
+    // Returns the node to queue up with, marked as locked.
+    fn enqueue(&mut self) -> *mut Node {
+        // A node that is still locked belongs to a guard that was leaked, and has to stay in its queue forever.
+        if self.node.is_null() || unsafe { (*self.node).state.load(Ordering::Relaxed) } == LOCKED {
+            self.node = Node::alloc();
+        }
+        unsafe { (*self.node).state.store(LOCKED, Ordering::Relaxed); }
+        self.node
+    }
+
+    // Releases the lock held with this slot, taking over our predecessor's node for next time.
+    fn release(&mut self) {
+        let node = self.node;
+        self.node = self.pred;
+        self.pred = ptr::null_mut();
+        unsafe { (*node).state.store(FREE, Ordering::Release); }
+    }
+}
+
+impl Default for Slot {
+    fn default() -> Slot {
+        Slot::new()
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        // Leak the node of a leaked guard, since its successor may still be waiting on it.
+        if !self.node.is_null() && unsafe { (*self.node).state.load(Ordering::Relaxed) } != LOCKED {
+            drop(unsafe { Box::from_raw(self.node) });
+        }
+    }
+}
+
+impl Drop for Tail {
+    fn drop(&mut self) {
+        // With the mutex gone, the node at the tail and any abandoned nodes before it belong to no one. A locked node
+        // belongs to a leaked guard's slot, which will leak it.
+        let mut node = *self.0.get_mut();
+        while !node.is_null() {
+            let state = unsafe { (*node).state.load(Ordering::Acquire) };
+            if state == LOCKED {
+                break;
+            }
+            drop(unsafe { Box::from_raw(node) });
+            node = if state == FREE { ptr::null_mut() } else { state as *mut Node };
+        }
+    }
+}
+
+impl<T> Mutex<T> {
+    /// Creates a new mutex in an unlocked state ready for use.
+    pub const fn new(value: T) -> Mutex<T> {
+        Mutex {
+            tail: Tail(AtomicPtr::new(ptr::null_mut())),
+            data: UnsafeCell::new(value)
+        }
+    }
+
+    /// Consumes this mutex, returning the underlying data.
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Attempts to acquire this lock.
+    ///
+    /// If the lock could not be acquired at this time, then `Err` is returned.
+    /// Otherwise, an RAII guard is returned. The lock will be unlocked when the
+    /// guard is dropped.
+    ///
+    /// This function does not block.
+    pub fn try_lock<'a>(&'a self, slot: &'a mut Slot) -> Result<Guard<'a, T>, ()> {
+        let node = slot.enqueue();
+        let mut pred = self.tail.0.swap(node, Ordering::AcqRel);
+        while !pred.is_null() {
+            match unsafe { (*pred).state.load(Ordering::Acquire) } {
+                FREE => break,
+                LOCKED => {
+                    // Leave our node for whoever comes next, which will wait on our predecessor instead.
+                    slot.node = ptr::null_mut();
+                    unsafe { (*node).state.store(pred as usize, Ordering::Release); }
+                    return Err(());
+                },
+                abandoned => {
+                    drop(unsafe { Box::from_raw(pred) });
+                    pred = abandoned as *mut Node;
+                }
+            }
+        }
+
+        slot.pred = pred;
+        Ok(Guard {
+            lock: self,
+            slot
+        })
+    }
+
+    /// Acquires a mutex, blocking the current thread until it is able to do so.
+    ///
+    /// This function will block the local thread until it is available to acquire
+    /// the mutex. Upon returning, the thread is the only thread with the mutex
+    /// held. An RAII guard is returned to allow scoped unlock of the lock. When
+    /// the guard goes out of scope, the mutex will be unlocked.
+    pub fn lock<'a>(&'a self, slot: &'a mut Slot) -> Guard<'a, T> {
+        let node = slot.enqueue();
+        let pred = self.tail.0.swap(node, Ordering::AcqRel);
+        slot.pred = unsafe { wait_for(pred) };
+
+        Guard {
+            lock: self,
+            slot
+        }
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    ///
+    /// Since this call borrows the `Mutex` mutably, no actual locking needs to
+    /// take place---the mutable borrow statically guarantees no locks exist.
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for Guard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for Guard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+guard_drop! {
+    impl<'a, T: ?Sized> Drop for Guard<'a, T> {
+        fn drop(&mut self) {
+            self.slot.release();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Mutex, Slot};
+
+    use std::boxed::Box;
+    use std::mem;
+    use std::sync::Arc;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::Duration;
+
+    #[derive(Eq, PartialEq, Debug)]
+    struct NonCopy(i32);
+
+    #[test]
+    fn smoke() {
+        let mut slot = Slot::new();
+        let m = Mutex::new(());
+        drop(m.lock(&mut slot));
+        drop(m.lock(&mut slot));
+    }
+
+    #[test]
+    fn lots_and_lots() {
+        static LOCK: Mutex<u32> = Mutex::new(0);
+
+        const ITERS: u32 = 1000;
+        const CONCURRENCY: u32 = 3;
+
+        fn inc() {
+            let mut slot = Slot::new();
+            for _ in 0..ITERS {
+                let mut g = LOCK.lock(&mut slot);
+                *g += 1;
+            }
+        }
+
+        let (tx, rx) = channel();
+        for _ in 0..CONCURRENCY {
+            let tx2 = tx.clone();
+            thread::spawn(move|| { inc(); tx2.send(()).unwrap(); });
+            let tx2 = tx.clone();
+            thread::spawn(move|| { inc(); tx2.send(()).unwrap(); });
+        }
+
+        drop(tx);
+        for _ in 0..2 * CONCURRENCY {
+            rx.recv().unwrap();
+        }
+        let mut slot = Slot::new();
+        assert_eq!(*LOCK.lock(&mut slot), ITERS * CONCURRENCY * 2);
+    }
+
+    #[test]
+    fn try_lock() {
+        let mut slot1 = Slot::new();
+        let mut slot2 = Slot::new();
+        let m = Mutex::new(());
+        let g = m.try_lock(&mut slot1).unwrap();
+        assert!(m.try_lock(&mut slot2).is_err());
+        drop(g);
+        *m.try_lock(&mut slot2).unwrap() = ();
+    }
+
+    #[test]
+    fn try_lock_while_queued() {
+        let m = Arc::new(Mutex::new(0));
+        let mut slot = Slot::new();
+        let g = m.lock(&mut slot);
+
+        let m2 = m.clone();
+        let (tx, rx) = channel();
+        let t = thread::spawn(move|| {
+            let mut slot1 = Slot::new();
+            let mut slot2 = Slot::new();
+            // Abandon a few nodes before and after queueing up for real.
+            assert!(m2.try_lock(&mut slot1).is_err());
+            tx.send(()).unwrap();
+            let mut g = m2.lock(&mut slot2);
+            assert!(m2.try_lock(&mut slot1).is_err());
+            *g += 1;
+        });
+
+        rx.recv().unwrap();
+        let mut slot2 = Slot::new();
+        for _ in 0..10 {
+            assert!(m.try_lock(&mut slot2).is_err());
+        }
+        drop(g);
+        t.join().unwrap();
+        assert_eq!(*m.try_lock(&mut slot2).unwrap(), 1);
+        assert_eq!(*m.lock(&mut slot), 1);
+    }
+
+    #[test]
+    fn slot_dropped_while_successor_waits() {
+        let m = Arc::new(Mutex::new(0));
+        let mut slot = Box::new(Slot::new());
+        let g = m.lock(&mut slot);
+
+        let m2 = m.clone();
+        let t = thread::spawn(move|| {
+            let mut slot = Slot::new();
+            *m2.lock(&mut slot) += 1;
+        });
+
+        // The waiter may be spinning on our node, but dropping the slot still doesn't wait for it.
+        thread::sleep(Duration::from_millis(10));
+        drop(g);
+        drop(slot);
+        t.join().unwrap();
+
+        let mut slot = Slot::new();
+        assert_eq!(*m.lock(&mut slot), 1);
+    }
+
+    #[test]
+    fn leaked_guard() {
+        let m = Mutex::new(());
+        let mut slot = Slot::new();
+        mem::forget(m.lock(&mut slot));
+        let mut slot2 = Slot::new();
+        assert!(m.try_lock(&mut slot2).is_err());
+
+        // The slot can still be used with another mutex.
+        let m2 = Mutex::new(1);
+        assert_eq!(*m2.lock(&mut slot), 1);
+    }
+
+    #[test]
+    fn test_into_inner() {
+        let m = Mutex::new(NonCopy(10));
+        assert_eq!(m.into_inner(), NonCopy(10));
+    }
+
+    #[test]
+    fn test_get_mut() {
+        let mut m = Mutex::new(NonCopy(10));
+        *m.get_mut() = NonCopy(20);
+        assert_eq!(m.into_inner(), NonCopy(20));
+    }
+
+    #[test]
+    fn test_lock_arc_nested() {
+        let arc = Arc::new(Mutex::new(1));
+        let arc2 = Arc::new(Mutex::new(arc));
+        let (tx, rx) = channel();
+        let _t = thread::spawn(move|| {
+            let mut slot1 = Slot::new();
+            let mut slot2 = Slot::new();
+
+            let lock = arc2.lock(&mut slot1);
+            let lock2 = lock.lock(&mut slot2);
+            assert_eq!(*lock2, 1);
+            tx.send(()).unwrap();
+        });
+        rx.recv().unwrap();
+    }
+
+    #[test]
+    fn test_lock_unsized() {
+        let mut slot = Slot::new();
+        let lock: &Mutex<[i32]> = &Mutex::new([1, 2, 3]);
+        {
+            let b = &mut *lock.lock(&mut slot);
+            b[0] = 4;
+            b[2] = 5;
+        }
+        let comp: &[i32] = &[4, 2, 5];
+        assert_eq!(&*lock.lock(&mut slot), comp);
+    }
+}
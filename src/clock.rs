@@ -0,0 +1,35 @@
+/// A source of time for the timed locking operations, such as `Mutex::lock_timeout`.
+///
+/// This lets timeouts work without `std`: implement it on top of whatever timer the platform has. With the `std`
+/// feature, `StdClock` is provided, which uses `std::time::Instant`.
+pub trait Clock {
+    /// A point in time.
+    type Instant: Ord;
+    /// A span of time.
+    type Duration;
+
+    /// Returns the current time.
+    fn now(&self) -> Self::Instant;
+
+    /// Returns the point in time that is `timeout` from now.
+    fn after(&self, timeout: Self::Duration) -> Self::Instant;
+}
+
+/// A `Clock` backed by `std::time::Instant`.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdClock;
+
+#[cfg(feature = "std")]
+impl Clock for StdClock {
+    type Instant = ::std::time::Instant;
+    type Duration = ::std::time::Duration;
+
+    fn now(&self) -> ::std::time::Instant {
+        ::std::time::Instant::now()
+    }
+
+    fn after(&self, timeout: ::std::time::Duration) -> ::std::time::Instant {
+        ::std::time::Instant::now() + timeout
+    }
+}
@@ -0,0 +1,497 @@
+//! The compact NUMA-aware (CNA) lock, as described by Dice and Kogan in "Compact NUMA-aware Locks".
+//!
+//! This is an MCS lock with the same one-word footprint as `mcs::Mutex`, but when the lock is released, the holder looks
+//! through the queue for a waiter on its own NUMA node and hands the lock to that waiter first. The waiters it skips are
+//! moved to a secondary queue, which is passed along with the lock, and is put back in front of the main queue once no
+//! waiter on the holder's node is left, or once the lock has been passed around within one node `MAX_HANDOFFS` times
+//! in a row.
+//!
+//! The node that a thread is on comes from a `NodeId` implementation. Unlike the cohort lock, node ids are compared
+//! directly, so there is no limit on their number.
+
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU32, AtomicUsize, Ordering, fence};
+
+use numa::NodeId;
+use pause::pause;
+
+/// The number of times in a row that the lock can be handed to a thread on the same node while threads on other nodes
+/// are waiting in the secondary queue.
+pub const MAX_HANDOFFS: u32 = 64;
+
+// Values of `Slot::state`. Any other value is the head of the secondary queue, which also means that the lock is held.
+const WAITING: usize = 0;
+const GRANTED: usize = 1;
+
+// The node of a thread that acquired the lock without waiting, which is only looked up if it turns out to be needed.
+const UNKNOWN_NODE: usize = !0;
+
+pub struct Slot {
+    next: AtomicPtr<Slot>,
+    state: AtomicUsize,
+    node: AtomicUsize,
+    // Only meaningful at the head of the secondary queue.
+    secondary_tail: AtomicPtr<Slot>,
+    // How many times in a row the lock has been handed over within the same node.
+    handoffs: AtomicU32
+}
+
+/// An RAII implementation of a "scoped lock" of a mutex. When this structure is
+/// dropped (falls out of scope), the lock will be unlocked.
+///
+/// The data protected by the mutex can be access through this guard via its
+/// `Deref` and `DerefMut` implementations
+#[must_use]
+pub struct Guard<'a, T: ?Sized + 'a, N: NodeId + 'a> {
+    lock: &'a Mutex<T, N>,
+    slot: &'a Slot
+}
+
+/// A mutual exclusion primitive useful for protecting shared data, which prefers to hand the lock to threads on the same
+/// NUMA node as the previous holder.
+///
+/// This is used just like `mcs::Mutex`, just with `mcs::cna::Slot` instead of `mcs::Slot`. `N` tells the mutex which
+/// node each thread is running on.
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::Arc;
+/// use std::thread;
+/// use mcs::NodeId;
+/// use mcs::cna::{Mutex, Slot};
+///
+/// // A single node, for machines with only one socket.
+/// struct OneNode;
+///
+/// impl NodeId for OneNode {
+///     fn node_id() -> usize {
+///         0
+///     }
+/// }
+///
+/// let data = Arc::new(Mutex::<_, OneNode>::new(0));
+///
+/// let threads: Vec<_> = (0..10).map(|_| {
+///     let data = data.clone();
+///     thread::spawn(move || {
+///         let mut slot = Slot::new();
+///         *data.lock(&mut slot) += 1;
+///     })
+/// }).collect();
+///
+/// for thread in threads {
+///     thread.join().unwrap();
+/// }
+///
+/// let mut slot = Slot::new();
+/// assert_eq!(*data.lock(&mut slot), 10);
+/// ```
+pub struct Mutex<T: ?Sized, N> {
+    queue: AtomicPtr<Slot>,
+    _node_id: PhantomData<fn() -> N>,
+    data: UnsafeCell<T>
+}
+
+unsafe impl<T: Send, N> Sync for Mutex<T, N> { }
+unsafe impl<T: Send, N> Send for Mutex<T, N> { }
+
+impl Slot {
+    pub const fn new() -> Slot {
+        Slot {
+            next: AtomicPtr::new(ptr::null_mut()),
+            state: AtomicUsize::new(WAITING),
+            node: AtomicUsize::new(UNKNOWN_NODE),
+            secondary_tail: AtomicPtr::new(ptr::null_mut()),
+            handoffs: AtomicU32::new(0)
+        }
+    }
+
+    fn reset(&self) {
+        self.next.store(ptr::null_mut(), Ordering::Relaxed);
+        self.state.store(WAITING, Ordering::Relaxed);
+        self.node.store(UNKNOWN_NODE, Ordering::Relaxed);
+        self.handoffs.store(0, Ordering::Relaxed);
+    }
+}
+
+impl Default for Slot {
+    fn default() -> Slot {
+        Slot::new()
+    }
+}
+
+impl<T, N> Mutex<T, N> {
+    /// Creates a new mutex in an unlocked state ready for use.
+    pub const fn new(value: T) -> Mutex<T, N> {
+        Mutex {
+            queue: AtomicPtr::new(ptr::null_mut()),
+            _node_id: PhantomData,
+            data: UnsafeCell::new(value)
+        }
+    }
+
+    /// Consumes this mutex, returning the underlying data.
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized, N: NodeId> Mutex<T, N> {
+    /// Attempts to acquire this lock.
+    ///
+    /// If the lock could not be acquired at this time, then `Err` is returned.
+    /// Otherwise, an RAII guard is returned. The lock will be unlocked when the
+    /// guard is dropped.
+    ///
+    /// This function does not block.
+    pub fn try_lock<'a>(&'a self, slot: &'a mut Slot) -> Result<Guard<'a, T, N>, ()> {
+        slot.reset();
+        slot.state.store(GRANTED, Ordering::Relaxed);
+
+        if self.queue.compare_exchange(ptr::null_mut(), slot, Ordering::AcqRel, Ordering::Relaxed).is_ok() {
+            Ok(Guard {
+                lock: self,
+                slot
+            })
+        } else {
+            Err(())
+        }
+    }
+
+    /// Acquires a mutex, blocking the current thread until it is able to do so.
+    ///
+    /// This function will block the local thread until it is available to acquire
+    /// the mutex. Upon returning, the thread is the only thread with the mutex
+    /// held. An RAII guard is returned to allow scoped unlock of the lock. When
+    /// the guard goes out of scope, the mutex will be unlocked.
+    pub fn lock<'a>(&'a self, slot: &'a mut Slot) -> Guard<'a, T, N> {
+        slot.reset();
+
+        let pred = self.queue.swap(slot, Ordering::AcqRel);
+        if pred.is_null() {
+            slot.state.store(GRANTED, Ordering::Relaxed);
+        } else {
+            // Our predecessor might need to know where we are before it hands off the lock.
+            slot.node.store(N::node_id(), Ordering::Relaxed);
+            unsafe { (*pred).next.store(slot, Ordering::Release); }
+            while slot.state.load(Ordering::Relaxed) == WAITING {
+                pause();
+            }
+            fence(Ordering::Acquire);
+        }
+
+        Guard {
+            lock: self,
+            slot
+        }
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    ///
+    /// Since this call borrows the `Mutex` mutably, no actual locking needs to
+    /// take place---the mutable borrow statically guarantees no locks exist.
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+
+    // Releases the lock held with `slot`, preferring to hand it to a waiter on the same node.
+    unsafe fn unlock(&self, slot: &Slot) {
+        let this_slot = slot as *const _ as *mut Slot;
+        let state = slot.state.load(Ordering::Relaxed);
+        let mut next = slot.next.load(Ordering::Acquire);
+        if next.is_null() {
+            // Everyone still waiting is in the secondary queue, if there is one, so it becomes the main queue.
+            let (new_tail, head) = if state == GRANTED {
+                (ptr::null_mut(), ptr::null_mut())
+            } else {
+                let head = state as *mut Slot;
+                ((*head).secondary_tail.load(Ordering::Relaxed), head)
+            };
+            if self.queue.load(Ordering::Relaxed) == this_slot &&
+               self.queue.compare_exchange(this_slot, new_tail, Ordering::Release, Ordering::Relaxed).is_ok() {
+                if !head.is_null() {
+                    Self::grant(head, GRANTED, 0);
+                }
+                return;
+            }
+
+            // Some thread is waiting, but hasn't registered yet.
+            loop {
+                next = slot.next.load(Ordering::Acquire);
+                if !next.is_null() {
+                    break;
+                }
+                pause();
+            }
+        }
+
+        let handoffs = slot.handoffs.load(Ordering::Relaxed);
+        if handoffs < MAX_HANDOFFS {
+            if let Some(succ) = self.find_successor(slot, next) {
+                // `find_successor` may have added to the secondary queue, so look at our state again.
+                Self::grant(succ, slot.state.load(Ordering::Relaxed), handoffs + 1);
+                return;
+            }
+        }
+
+        if state == GRANTED {
+            Self::grant(next, GRANTED, 0);
+        } else {
+            // Put the secondary queue back in front of the main queue.
+            let head = state as *mut Slot;
+            (*(*head).secondary_tail.load(Ordering::Relaxed)).next.store(next, Ordering::Relaxed);
+            Self::grant(head, GRANTED, 0);
+        }
+    }
+
+    // Looks for a waiter on the same node as the holder, starting at `next`. Any waiters in front of the one found are
+    // moved to the end of the secondary queue.
+    unsafe fn find_successor(&self, slot: &Slot, next: *mut Slot) -> Option<*mut Slot> {
+        let mut node = slot.node.load(Ordering::Relaxed);
+        if node == UNKNOWN_NODE {
+            node = N::node_id();
+            slot.node.store(node, Ordering::Relaxed);
+        }
+
+        if (*next).node.load(Ordering::Relaxed) == node {
+            return Some(next);
+        }
+
+        // The last waiter in the queue is never moved, since new waiters may be about to link themselves to it.
+        let skipped_head = next;
+        let mut skipped_tail = next;
+        let mut current = (*next).next.load(Ordering::Acquire);
+        while !current.is_null() {
+            if (*current).node.load(Ordering::Relaxed) == node {
+                let state = slot.state.load(Ordering::Relaxed);
+                if state == GRANTED {
+                    slot.state.store(skipped_head as usize, Ordering::Relaxed);
+                } else {
+                    let head = state as *mut Slot;
+                    (*(*head).secondary_tail.load(Ordering::Relaxed)).next.store(skipped_head, Ordering::Relaxed);
+                }
+                (*skipped_tail).next.store(ptr::null_mut(), Ordering::Relaxed);
+                (*(slot.state.load(Ordering::Relaxed) as *mut Slot)).secondary_tail.store(skipped_tail, Ordering::Relaxed);
+                return Some(current);
+            }
+
+            skipped_tail = current;
+            current = (*current).next.load(Ordering::Acquire);
+        }
+
+        None
+    }
+
+    // Hands the lock to a waiter, along with the secondary queue (or `GRANTED` if there is none).
+    unsafe fn grant(succ: *mut Slot, state: usize, handoffs: u32) {
+        (*succ).handoffs.store(handoffs, Ordering::Relaxed);
+        (*succ).state.store(state, Ordering::Release);
+    }
+}
+
+impl<'a, T: ?Sized, N: NodeId> Deref for Guard<'a, T, N> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized, N: NodeId> DerefMut for Guard<'a, T, N> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+guard_drop! {
+    impl<'a, T: ?Sized, N: NodeId> Drop for Guard<'a, T, N> {
+        fn drop(&mut self) {
+            unsafe { self.lock.unlock(self.slot); }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Mutex, Slot};
+    use numa::NodeId;
+
+    use std::cell::Cell;
+    use std::mem;
+    use std::sync::{Arc, Barrier};
+    use std::sync::atomic::Ordering;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::thread_local;
+    use std::vec::Vec;
+
+    thread_local! {
+        static NODE: Cell<usize> = const { Cell::new(0) };
+    }
+
+    // A made-up topology, where each thread says which node it is on.
+    struct TestNodes;
+
+    impl NodeId for TestNodes {
+        fn node_id() -> usize {
+            NODE.with(|node| node.get())
+        }
+    }
+
+    fn set_node(node: usize) {
+        NODE.with(|n| n.set(node));
+    }
+
+    #[derive(Eq, PartialEq, Debug)]
+    struct NonCopy(i32);
+
+    #[test]
+    fn smoke() {
+        let mut slot = Slot::new();
+        let m = Mutex::<_, TestNodes>::new(());
+        drop(m.lock(&mut slot));
+        drop(m.lock(&mut slot));
+        assert_eq!(mem::size_of::<Mutex<(), TestNodes>>(), mem::size_of::<usize>());
+    }
+
+    #[test]
+    fn lots_and_lots() {
+        static LOCK: Mutex<u32, TestNodes> = Mutex::new(0);
+
+        const ITERS: u32 = 1000;
+        const CONCURRENCY: u32 = 3;
+
+        fn inc(node: usize) {
+            set_node(node);
+            let mut slot = Slot::new();
+            for _ in 0..ITERS {
+                let mut g = LOCK.lock(&mut slot);
+                *g += 1;
+            }
+        }
+
+        let (tx, rx) = channel();
+        for _ in 0..CONCURRENCY {
+            let tx2 = tx.clone();
+            thread::spawn(move|| { inc(0); tx2.send(()).unwrap(); });
+            let tx2 = tx.clone();
+            thread::spawn(move|| { inc(1); tx2.send(()).unwrap(); });
+        }
+
+        drop(tx);
+        for _ in 0..2 * CONCURRENCY {
+            rx.recv().unwrap();
+        }
+        let mut slot = Slot::new();
+        assert_eq!(*LOCK.lock(&mut slot), ITERS * CONCURRENCY * 2);
+    }
+
+    #[test]
+    fn try_lock() {
+        let mut slot1 = Slot::new();
+        let mut slot2 = Slot::new();
+        let m = Mutex::<_, TestNodes>::new(());
+        let g = m.try_lock(&mut slot1).unwrap();
+        assert!(m.try_lock(&mut slot2).is_err());
+        drop(g);
+        *m.try_lock(&mut slot2).unwrap() = ();
+    }
+
+    #[test]
+    fn prefers_same_node() {
+        let m = Arc::new(Mutex::<_, TestNodes>::new(Vec::new()));
+        let mut slot = Slot::new();
+        let g = m.lock(&mut slot);
+
+        // Queue up a thread on another node, then one on our node, then another one on the other node.
+        let mut threads = Vec::new();
+        let mut last: *const Slot = g.slot;
+        for &node in &[1, 0, 1] {
+            let m = m.clone();
+            threads.push(thread::spawn(move|| {
+                set_node(node);
+                let mut slot = Slot::new();
+                m.lock(&mut slot).push(node);
+            }));
+
+            loop {
+                let next = unsafe { (*last).next.load(Ordering::Acquire) };
+                if !next.is_null() {
+                    last = next;
+                    break;
+                }
+                thread::yield_now();
+            }
+        }
+
+        drop(g);
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*m.lock(&mut slot), [0, 1, 1]);
+    }
+
+    #[test]
+    fn handoffs_are_bounded() {
+        const THREADS: usize = 2;
+
+        // Two threads on node 0 keep handing the lock back and forth. Without a bound on the number of handoffs, they
+        // would do so forever, and the thread on node 1 would never get a turn.
+        let m = Arc::new(Mutex::<_, TestNodes>::new(false));
+        let barrier = Arc::new(Barrier::new(THREADS + 1));
+        let threads: Vec<_> = (0..THREADS).map(|_| {
+            let m = m.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move|| {
+                let mut slot = Slot::new();
+                barrier.wait();
+                while !*m.lock(&mut slot) {
+                    thread::yield_now();
+                }
+            })
+        }).collect();
+
+        barrier.wait();
+        thread::spawn({
+            let m = m.clone();
+            move|| {
+                set_node(1);
+                let mut slot = Slot::new();
+                *m.lock(&mut slot) = true;
+            }
+        }).join().unwrap();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_into_inner() {
+        let m = Mutex::<_, TestNodes>::new(NonCopy(10));
+        assert_eq!(m.into_inner(), NonCopy(10));
+    }
+
+    #[test]
+    fn test_get_mut() {
+        let mut m = Mutex::<_, TestNodes>::new(NonCopy(10));
+        *m.get_mut() = NonCopy(20);
+        assert_eq!(m.into_inner(), NonCopy(20));
+    }
+
+    #[test]
+    fn test_lock_unsized() {
+        let mut slot = Slot::new();
+        let lock: &Mutex<[i32], TestNodes> = &Mutex::new([1, 2, 3]);
+        {
+            let b = &mut *lock.lock(&mut slot);
+            b[0] = 4;
+            b[2] = 5;
+        }
+        let comp: &[i32] = &[4, 2, 5];
+        assert_eq!(&*lock.lock(&mut slot), comp);
+    }
+}
@@ -0,0 +1,471 @@
+//! A NUMA-aware cohort lock, as described by Dice, Marathe, and Shavit in "Lock Cohorting: A General Technique for
+//! Designing NUMA Locks".
+//!
+//! Each NUMA node has its own MCS lock, and there is one global ticket lock. A thread first takes its node's lock, then
+//! the global lock, unless a thread from its node handed it over along with the node's lock. When releasing the lock
+//! while other threads from the same node are queued up, the holder keeps the global lock and hands both to the next
+//! thread on its node, so the protected data stays in that node's caches. To keep other nodes from starving, this only
+//! happens `MAX_HANDOFFS` times in a row before the global lock is released.
+//!
+//! The node that a thread is on comes from a `NodeId` implementation. Node ids are taken modulo `MAX_NODES`.
+
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use mutex::{Queue, Slot};
+use numa::NodeId;
+use park;
+use relax::SpinThenYield;
+
+/// The number of nodes that get a local lock of their own.
+pub const MAX_NODES: usize = 8;
+
+/// The number of times in a row that the lock can be handed to a thread on the same node while threads on other nodes
+/// might be waiting.
+pub const MAX_HANDOFFS: u32 = 64;
+
+// A ticket lock, since the thread that releases the global lock is not necessarily the one that acquired it.
+struct TicketLock {
+    next: AtomicUsize,
+    serving: AtomicUsize
+}
+
+// A node's local lock. This is a bare MCS queue, so that the cohort lock shows up in stats, observers, and lock order
+// checks as nothing but itself.
+struct Local {
+    queue: Queue,
+    // Only accessed with `queue` locked.
+    cohort: UnsafeCell<Cohort>
+}
+
+unsafe impl Sync for Local { }
+
+// The state of a node's cohort, protected by the node's local lock.
+struct Cohort {
+    // Whether the cohort owns the global lock.
+    global_held: bool,
+    // How many times in a row the lock has been handed to a thread on this node.
+    handoffs: u32
+}
+
+/// An RAII implementation of a "scoped lock" of a mutex. When this structure is
+/// dropped (falls out of scope), the lock will be unlocked.
+///
+/// The data protected by the mutex can be access through this guard via its
+/// `Deref` and `DerefMut` implementations
+#[must_use]
+pub struct Guard<'a, T: ?Sized + 'a, N: 'a> {
+    lock: &'a Mutex<T, N>,
+    local: &'a Local,
+    slot: &'a Slot
+}
+
+/// A mutual exclusion primitive useful for protecting shared data, which keeps the lock on one NUMA node for a while
+/// before passing it to another.
+///
+/// This is used just like `mcs::Mutex`, with an `mcs::Slot` for queueing up on the local lock. `N` tells the mutex which
+/// node each thread is running on.
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::Arc;
+/// use std::thread;
+/// use mcs::{NodeId, Slot};
+/// use mcs::cohort::Mutex;
+///
+/// // A single node, for machines with only one socket.
+/// struct OneNode;
+///
+/// impl NodeId for OneNode {
+///     fn node_id() -> usize {
+///         0
+///     }
+/// }
+///
+/// let data = Arc::new(Mutex::<_, OneNode>::new(0));
+///
+/// let threads: Vec<_> = (0..10).map(|_| {
+///     let data = data.clone();
+///     thread::spawn(move || {
+///         let mut slot = Slot::new();
+///         *data.lock(&mut slot) += 1;
+///     })
+/// }).collect();
+///
+/// for thread in threads {
+///     thread.join().unwrap();
+/// }
+///
+/// let mut slot = Slot::new();
+/// assert_eq!(*data.lock(&mut slot), 10);
+/// ```
+pub struct Mutex<T: ?Sized, N> {
+    global: TicketLock,
+    locals: [Local; MAX_NODES],
+    _node_id: PhantomData<fn() -> N>,
+    data: UnsafeCell<T>
+}
+
+unsafe impl<T: Send, N> Sync for Mutex<T, N> { }
+unsafe impl<T: Send, N> Send for Mutex<T, N> { }
+
+// The local locks, all unlocked and without the global lock.
+macro_rules! locals {
+    () => {
+        [
+            Local::new(),
+            Local::new(),
+            Local::new(),
+            Local::new(),
+            Local::new(),
+            Local::new(),
+            Local::new(),
+            Local::new()
+        ]
+    }
+}
+
+impl Local {
+    const fn new() -> Local {
+        Local {
+            queue: Queue::new(),
+            cohort: UnsafeCell::new(Cohort {
+                global_held: false,
+                handoffs: 0
+            })
+        }
+    }
+}
+
+impl TicketLock {
+    fn lock(&self) {
+        let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut spins = 0;
+        while self.serving.load(Ordering::Acquire) != ticket {
+            park::snooze(&mut spins);
+        }
+    }
+
+    fn try_lock(&self) -> bool {
+        let serving = self.serving.load(Ordering::Relaxed);
+        self.next.compare_exchange(serving, serving.wrapping_add(1), Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+
+    fn unlock(&self) {
+        let serving = self.serving.load(Ordering::Relaxed);
+        self.serving.store(serving.wrapping_add(1), Ordering::Release);
+    }
+}
+
+impl<T, N> Mutex<T, N> {
+    /// Creates a new mutex in an unlocked state ready for use.
+    pub const fn new(value: T) -> Mutex<T, N> {
+        Mutex {
+            global: TicketLock {
+                next: AtomicUsize::new(0),
+                serving: AtomicUsize::new(0)
+            },
+            locals: locals!(),
+            _node_id: PhantomData,
+            data: UnsafeCell::new(value)
+        }
+    }
+
+    /// Consumes this mutex, returning the underlying data.
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized, N: NodeId> Mutex<T, N> {
+    /// Attempts to acquire this lock.
+    ///
+    /// If the lock could not be acquired at this time, then `Err` is returned.
+    /// Otherwise, an RAII guard is returned. The lock will be unlocked when the
+    /// guard is dropped.
+    ///
+    /// This function does not block.
+    pub fn try_lock<'a>(&'a self, slot: &'a mut Slot) -> Result<Guard<'a, T, N>, ()> {
+        let local = self.local();
+        if !local.queue.try_lock(slot) {
+            return Err(());
+        }
+        let cohort = unsafe { &mut *local.cohort.get() };
+        if !cohort.global_held {
+            if !self.global.try_lock() {
+                unsafe { local.queue.unlock::<SpinThenYield>(slot); }
+                return Err(());
+            }
+            cohort.global_held = true;
+        }
+
+        Ok(Guard {
+            lock: self,
+            local,
+            slot
+        })
+    }
+
+    /// Acquires a mutex, blocking the current thread until it is able to do so.
+    ///
+    /// This function will block the local thread until it is available to acquire
+    /// the mutex. Upon returning, the thread is the only thread with the mutex
+    /// held. An RAII guard is returned to allow scoped unlock of the lock. When
+    /// the guard goes out of scope, the mutex will be unlocked.
+    pub fn lock<'a>(&'a self, slot: &'a mut Slot) -> Guard<'a, T, N> {
+        let local = self.local();
+        local.queue.lock::<SpinThenYield>(slot);
+        let cohort = unsafe { &mut *local.cohort.get() };
+        if !cohort.global_held {
+            self.global.lock();
+            cohort.global_held = true;
+        }
+
+        Guard {
+            lock: self,
+            local,
+            slot
+        }
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    ///
+    /// Since this call borrows the `Mutex` mutably, no actual locking needs to
+    /// take place---the mutable borrow statically guarantees no locks exist.
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+
+    fn local(&self) -> &Local {
+        &self.locals[N::node_id() % MAX_NODES]
+    }
+}
+
+impl<'a, T: ?Sized, N> Deref for Guard<'a, T, N> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized, N> DerefMut for Guard<'a, T, N> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized, N> Guard<'a, T, N> {
+    // Releases the local lock, and the global lock too unless the local lock is being passed to another thread on the
+    // same node.
+    fn release(&mut self) {
+        let cohort = unsafe { &mut *self.local.cohort.get() };
+        if cohort.handoffs < MAX_HANDOFFS && self.local.queue.has_waiters(self.slot) {
+            cohort.handoffs += 1;
+        } else {
+            cohort.handoffs = 0;
+            cohort.global_held = false;
+            self.lock.global.unlock();
+        }
+        unsafe { self.local.queue.unlock::<SpinThenYield>(self.slot); }
+    }
+}
+
+guard_drop! {
+    impl<'a, T: ?Sized, N> Drop for Guard<'a, T, N> {
+        fn drop(&mut self) {
+            self.release();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Mutex;
+    use mutex::Slot;
+    use numa::NodeId;
+
+    use std::cell::Cell;
+    use std::sync::{Arc, Barrier};
+    use std::sync::atomic::Ordering;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::thread_local;
+    use std::vec::Vec;
+
+    thread_local! {
+        static NODE: Cell<usize> = const { Cell::new(0) };
+    }
+
+    // A made-up topology, where each thread says which node it is on.
+    struct TestNodes;
+
+    impl NodeId for TestNodes {
+        fn node_id() -> usize {
+            NODE.with(|node| node.get())
+        }
+    }
+
+    fn set_node(node: usize) {
+        NODE.with(|n| n.set(node));
+    }
+
+    #[derive(Eq, PartialEq, Debug)]
+    struct NonCopy(i32);
+
+    #[test]
+    fn smoke() {
+        let mut slot = Slot::new();
+        let m = Mutex::<_, TestNodes>::new(());
+        drop(m.lock(&mut slot));
+        drop(m.lock(&mut slot));
+    }
+
+    #[test]
+    fn lots_and_lots() {
+        static LOCK: Mutex<u32, TestNodes> = Mutex::new(0);
+
+        const ITERS: u32 = 1000;
+        const CONCURRENCY: u32 = 3;
+
+        fn inc(node: usize) {
+            set_node(node);
+            let mut slot = Slot::new();
+            for _ in 0..ITERS {
+                let mut g = LOCK.lock(&mut slot);
+                *g += 1;
+            }
+        }
+
+        let (tx, rx) = channel();
+        for _ in 0..CONCURRENCY {
+            let tx2 = tx.clone();
+            thread::spawn(move|| { inc(0); tx2.send(()).unwrap(); });
+            let tx2 = tx.clone();
+            thread::spawn(move|| { inc(1); tx2.send(()).unwrap(); });
+        }
+
+        drop(tx);
+        for _ in 0..2 * CONCURRENCY {
+            rx.recv().unwrap();
+        }
+        let mut slot = Slot::new();
+        assert_eq!(*LOCK.lock(&mut slot), ITERS * CONCURRENCY * 2);
+    }
+
+    #[test]
+    fn try_lock() {
+        let mut slot1 = Slot::new();
+        let mut slot2 = Slot::new();
+        let m = Arc::new(Mutex::<_, TestNodes>::new(()));
+        let g = m.try_lock(&mut slot1).unwrap();
+        assert!(m.try_lock(&mut slot2).is_err());
+
+        // The global lock is still taken by node 0.
+        let m2 = m.clone();
+        thread::spawn(move|| {
+            set_node(1);
+            let mut slot = Slot::new();
+            assert!(m2.try_lock(&mut slot).is_err());
+        }).join().unwrap();
+
+        drop(g);
+        *m.try_lock(&mut slot2).unwrap() = ();
+    }
+
+    #[test]
+    fn prefers_same_node() {
+        let m = Arc::new(Mutex::<_, TestNodes>::new(Vec::new()));
+        let mut slot = Slot::new();
+        let g = m.lock(&mut slot);
+
+        // A thread on another node waits for the global lock first...
+        let m1 = m.clone();
+        let remote = thread::spawn(move|| {
+            set_node(1);
+            let mut slot = Slot::new();
+            m1.lock(&mut slot).push(1);
+        });
+        while m.global.next.load(Ordering::Relaxed) != 2 {
+            thread::yield_now();
+        }
+
+        // ...but a thread on our node gets the lock before it.
+        let m0 = m.clone();
+        let local = thread::spawn(move|| {
+            let mut slot = Slot::new();
+            m0.lock(&mut slot).push(0);
+        });
+        while !g.local.queue.has_waiters(g.slot) {
+            thread::yield_now();
+        }
+
+        drop(g);
+        remote.join().unwrap();
+        local.join().unwrap();
+        assert_eq!(*m.lock(&mut slot), [0, 1]);
+    }
+
+    #[test]
+    fn handoffs_are_bounded() {
+        const THREADS: usize = 2;
+
+        // Two threads on node 0 keep handing the lock back and forth. Without a bound on the number of handoffs, they
+        // would do so forever, and the thread on node 1 would never get a turn.
+        let m = Arc::new(Mutex::<_, TestNodes>::new(false));
+        let barrier = Arc::new(Barrier::new(THREADS + 1));
+        let threads: Vec<_> = (0..THREADS).map(|_| {
+            let m = m.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move|| {
+                let mut slot = Slot::new();
+                barrier.wait();
+                while !*m.lock(&mut slot) {
+                    thread::yield_now();
+                }
+            })
+        }).collect();
+
+        barrier.wait();
+        thread::spawn({
+            let m = m.clone();
+            move|| {
+                set_node(1);
+                let mut slot = Slot::new();
+                *m.lock(&mut slot) = true;
+            }
+        }).join().unwrap();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_into_inner() {
+        let m = Mutex::<_, TestNodes>::new(NonCopy(10));
+        assert_eq!(m.into_inner(), NonCopy(10));
+    }
+
+    #[test]
+    fn test_get_mut() {
+        let mut m = Mutex::<_, TestNodes>::new(NonCopy(10));
+        *m.get_mut() = NonCopy(20);
+        assert_eq!(m.into_inner(), NonCopy(20));
+    }
+
+    #[test]
+    fn test_lock_unsized() {
+        let mut slot = Slot::new();
+        let lock: &Mutex<[i32], TestNodes> = &Mutex::new([1, 2, 3]);
+        {
+            let b = &mut *lock.lock(&mut slot);
+            b[0] = 4;
+            b[2] = 5;
+        }
+        let comp: &[i32] = &[4, 2, 5];
+        assert_eq!(&*lock.lock(&mut slot), comp);
+    }
+}
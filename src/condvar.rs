@@ -0,0 +1,348 @@
+use core::cell::Cell;
+use core::ptr;
+use core::sync::atomic::{AtomicU32, Ordering, fence};
+
+use k42;
+use mutex::Guard;
+use park::{self, Parker};
+use poison::LockResult;
+use relax::Relax;
+use watchdog;
+
+// Waiter states
+const WAITING: u32 = 0;
+const NOTIFIED: u32 = 1;
+const PARKED: u32 = 2;
+
+// A thread blocked in `Condvar::wait`. Lives on that thread's stack until it is notified.
+struct Waiter {
+    state: AtomicU32,
+    parker: Parker,
+    // Only accessed with the condition variable's queue locked.
+    next: Cell<*const Waiter>
+}
+
+// The waiting threads, in the order that they started waiting.
+struct WaitQueue {
+    head: *const Waiter,
+    tail: *const Waiter
+}
+
+// The waiters are only touched with the queue locked, or after being removed from it.
+unsafe impl Send for WaitQueue { }
+
+/// A condition variable, for use with `Mutex`.
+///
+/// Condition variables represent the ability to block a thread while waiting for an event to occur. Waiting on a
+/// condition variable atomically releases a held `Guard`, handing the mutex to the next thread in its queue, and the
+/// guard's slot is used to queue up for the mutex again once the thread has been notified.
+///
+/// Waiting threads are kept in a FIFO queue, so `notify_one` always wakes up the thread that has been waiting longest.
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::Arc;
+/// use std::thread;
+/// use mcs::{Condvar, Mutex, Slot};
+///
+/// let pair = Arc::new((Mutex::new(false), Condvar::new()));
+/// let pair2 = pair.clone();
+///
+/// // Inside of our lock, spawn a new thread, and then wait for it to start.
+/// thread::spawn(move|| {
+///     let &(ref lock, ref cvar) = &*pair2;
+///     let mut slot = Slot::new();
+///     let mut started = lock.lock(&mut slot).unwrap();
+///     *started = true;
+///     // We notify the condvar that the value has changed.
+///     cvar.notify_one();
+/// });
+///
+/// // Wait for the thread to start up.
+/// let &(ref lock, ref cvar) = &*pair;
+/// let mut slot = Slot::new();
+/// let mut started = lock.lock(&mut slot).unwrap();
+/// while !*started {
+///     started = cvar.wait(started).unwrap();
+/// }
+/// ```
+pub struct Condvar {
+    // A plain K42 lock, so that the queue's own locking never shows up in the stats, observers, or lock order checks of
+    // the mutexes that users wait with.
+    waiters: k42::Mutex<WaitQueue>
+}
+
+impl Waiter {
+    fn new() -> Waiter {
+        Waiter {
+            state: AtomicU32::new(WAITING),
+            parker: Parker::new(),
+            next: Cell::new(ptr::null())
+        }
+    }
+
+    fn wait<R: Relax>(&self) {
+        let mut relax = R::default();
+        let mut spins = 0;
+        while self.state.load(Ordering::Relaxed) == WAITING {
+            if spins < park::SPIN_LIMIT {
+                spins += 1;
+                relax.relax();
+            } else {
+                self.parker.park(&self.state, WAITING, PARKED, R::FUTEX);
+            }
+        }
+        fence(Ordering::Acquire);
+    }
+
+    // Wakes up a waiter that has been removed from the queue. The waiter may be gone as soon as this returns.
+    unsafe fn notify(waiter: *const Waiter) {
+        (*waiter).parker.set(&(*waiter).state, NOTIFIED, PARKED);
+    }
+}
+
+impl Condvar {
+    /// Creates a new condition variable which is ready to be waited on and notified.
+    pub const fn new() -> Condvar {
+        Condvar {
+            waiters: k42::Mutex::new(WaitQueue {
+                head: ptr::null(),
+                tail: ptr::null()
+            })
+        }
+    }
+
+    /// Blocks the current thread until this condition variable receives a notification.
+    ///
+    /// This function will atomically unlock the mutex specified (represented by `guard`) and block the current thread.
+    /// This means that any calls to `notify_one` or `notify_all` which happen logically after the mutex is unlocked are
+    /// candidates to wake this thread up. When this function call returns, the lock will have been re-acquired.
+    ///
+    /// Note that this function is susceptible to spurious wakeups, as with any condition variable, and so should be
+    /// used in a loop that checks the condition being waited for.
+    ///
+    /// # Errors
+    ///
+    /// If the mutex was poisoned by another thread while this thread was waiting, an error is returned once the lock
+    /// has been re-acquired.
+    #[cfg_attr(any(feature = "profile", all(feature = "lockdep", debug_assertions)), track_caller)]
+    pub fn wait<'a, T: ?Sized, R: Relax>(&self, mut guard: Guard<'a, T, R>) -> LockResult<Guard<'a, T, R>> {
+        let waiter = Waiter::new();
+        {
+            let mut waiters = self.waiters.lock();
+            if waiters.tail.is_null() {
+                waiters.head = &waiter;
+            } else {
+                unsafe { (*waiters.tail).next.set(&waiter); }
+            }
+            waiters.tail = &waiter;
+        }
+
+        guard.unlocked(|| {
+            let _watch = watchdog::watch_notify(self as *const Condvar as usize);
+            waiter.wait::<R>()
+        });
+        guard.into_result()
+    }
+
+    /// Blocks the current thread until this condition variable receives a notification and `condition` returns
+    /// `false`.
+    ///
+    /// `condition` is checked with the lock held, both before waiting for the first time and after every wakeup.
+    ///
+    /// # Errors
+    ///
+    /// Like `wait`, this returns an error if the mutex was poisoned while this thread was waiting.
+    #[cfg_attr(any(feature = "profile", all(feature = "lockdep", debug_assertions)), track_caller)]
+    pub fn wait_while<'a, T: ?Sized, R: Relax, F>(&self, mut guard: Guard<'a, T, R>, mut condition: F) -> LockResult<Guard<'a, T, R>>
+        where F: FnMut(&mut T) -> bool
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard)?;
+        }
+        Ok(guard)
+    }
+
+    /// Wakes up the thread that has been blocked on this condition variable the longest, if there is one.
+    pub fn notify_one(&self) {
+        let waiter = {
+            let mut waiters = self.waiters.lock();
+            let waiter = waiters.head;
+            if waiter.is_null() {
+                return;
+            }
+            waiters.head = unsafe { (*waiter).next.get() };
+            if waiters.head.is_null() {
+                waiters.tail = ptr::null();
+            }
+            waiter
+        };
+
+        unsafe { Waiter::notify(waiter); }
+    }
+
+    /// Wakes up all threads blocked on this condition variable, in the order that they started waiting.
+    pub fn notify_all(&self) {
+        let mut waiter = {
+            let mut waiters = self.waiters.lock();
+            let head = waiters.head;
+            waiters.head = ptr::null();
+            waiters.tail = ptr::null();
+            head
+        };
+
+        while !waiter.is_null() {
+            unsafe {
+                let next = (*waiter).next.get();
+                Waiter::notify(waiter);
+                waiter = next;
+            }
+        }
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Condvar {
+        Condvar::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Condvar;
+    use mutex::{Mutex, Slot};
+
+    use std::sync::Arc;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::vec::Vec;
+
+    #[test]
+    fn smoke() {
+        let c = Condvar::new();
+        c.notify_one();
+        c.notify_all();
+    }
+
+    #[test]
+    fn notify_one() {
+        let m = Arc::new(Mutex::new(false));
+        let c = Arc::new(Condvar::new());
+        let m2 = m.clone();
+        let c2 = c.clone();
+
+        let mut slot = Slot::new();
+        let mut g = m.lock(&mut slot).unwrap();
+        let _t = thread::spawn(move|| {
+            let mut slot = Slot::new();
+            *m2.lock(&mut slot).unwrap() = true;
+            c2.notify_one();
+        });
+        while !*g {
+            g = c.wait(g).unwrap();
+        }
+    }
+
+    #[test]
+    fn notify_all() {
+        const N: usize = 10;
+
+        let data = Arc::new((Mutex::new(0), Condvar::new()));
+        let (tx, rx) = channel();
+        for _ in 0..N {
+            let data = data.clone();
+            let tx = tx.clone();
+            thread::spawn(move|| {
+                let (lock, cond) = &*data;
+                let mut slot = Slot::new();
+                let mut cnt = lock.lock(&mut slot).unwrap();
+                *cnt += 1;
+                if *cnt == N {
+                    tx.send(()).unwrap();
+                }
+                while *cnt != 0 {
+                    cnt = cond.wait(cnt).unwrap();
+                }
+                tx.send(()).unwrap();
+            });
+        }
+        drop(tx);
+
+        let (lock, cond) = &*data;
+        rx.recv().unwrap();
+        let mut slot = Slot::new();
+        let mut cnt = lock.lock(&mut slot).unwrap();
+        *cnt = 0;
+        cond.notify_all();
+        drop(cnt);
+
+        for _ in 0..N {
+            rx.recv().unwrap();
+        }
+    }
+
+    #[test]
+    fn wait_while() {
+        let pair = Arc::new((Mutex::new(false), Condvar::new()));
+        let pair2 = pair.clone();
+
+        thread::spawn(move|| {
+            let (lock, cvar) = &*pair2;
+            let mut slot = Slot::new();
+            *lock.lock(&mut slot).unwrap() = true;
+            cvar.notify_one();
+        });
+
+        let (lock, cvar) = &*pair;
+        let mut slot = Slot::new();
+        let guard = cvar.wait_while(lock.lock(&mut slot).unwrap(), |started| !*started).unwrap();
+        assert!(*guard);
+    }
+
+    #[test]
+    fn notify_one_is_fifo() {
+        const N: usize = 4;
+
+        // (number of threads waiting, order in which they woke up)
+        let data = Arc::new((Mutex::new((0, Vec::new())), Condvar::new()));
+        let (lock, cond) = &*data;
+        let mut slot = Slot::new();
+
+        let mut threads = Vec::new();
+        for i in 0..N {
+            let data = data.clone();
+            threads.push(thread::spawn(move|| {
+                let (lock, cond) = &*data;
+                let mut slot = Slot::new();
+                let mut state = lock.lock(&mut slot).unwrap();
+                state.0 += 1;
+                state = cond.wait(state).unwrap();
+                state.1.push(i);
+            }));
+
+            // Once we see the count go up, the thread is definitely waiting.
+            loop {
+                if lock.lock(&mut slot).unwrap().0 == i + 1 {
+                    break;
+                }
+                thread::yield_now();
+            }
+        }
+
+        for i in 0..N {
+            cond.notify_one();
+            loop {
+                if lock.lock(&mut slot).unwrap().1.len() == i + 1 {
+                    break;
+                }
+                thread::yield_now();
+            }
+        }
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(lock.lock(&mut slot).unwrap().1, (0..N).collect::<Vec<_>>());
+    }
+}
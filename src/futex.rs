@@ -0,0 +1,62 @@
+// Raw futex(2) calls, so that blocked waiters can sleep on Linux without `std` or libc.
+
+use core::arch::asm;
+use core::sync::atomic::AtomicU32;
+
+#[cfg(target_arch = "x86_64")]
+const SYS_FUTEX: usize = 202;
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+const SYS_FUTEX: usize = 98;
+
+const FUTEX_WAIT: usize = 0;
+const FUTEX_WAKE: usize = 1;
+// All of our futex words are in ordinary process memory, so the kernel can use the cheaper process-local lookups.
+const FUTEX_PRIVATE_FLAG: usize = 128;
+
+#[cfg(target_arch = "x86_64")]
+unsafe fn syscall4(n: usize, a1: usize, a2: usize, a3: usize, a4: usize) -> isize {
+    let ret: isize;
+    asm!("syscall",
+         inlateout("rax") n as isize => ret,
+         in("rdi") a1, in("rsi") a2, in("rdx") a3, in("r10") a4,
+         lateout("rcx") _, lateout("r11") _,
+         options(nostack));
+    ret
+}
+
+#[cfg(target_arch = "aarch64")]
+unsafe fn syscall4(n: usize, a1: usize, a2: usize, a3: usize, a4: usize) -> isize {
+    let ret: isize;
+    asm!("svc 0",
+         in("x8") n,
+         inlateout("x0") a1 as isize => ret, in("x1") a2, in("x2") a3, in("x3") a4,
+         options(nostack));
+    ret
+}
+
+#[cfg(target_arch = "riscv64")]
+unsafe fn syscall4(n: usize, a1: usize, a2: usize, a3: usize, a4: usize) -> isize {
+    let ret: isize;
+    asm!("ecall",
+         in("a7") n,
+         inlateout("a0") a1 as isize => ret, in("a1") a2, in("a2") a3, in("a3") a4,
+         options(nostack));
+    ret
+}
+
+// Sleeps as long as `word` contains `expected`. This can return spuriously, so the caller has to check again.
+pub fn wait(word: &AtomicU32, expected: u32) {
+    unsafe {
+        syscall4(SYS_FUTEX, word as *const _ as usize, FUTEX_WAIT | FUTEX_PRIVATE_FLAG, expected as usize, 0);
+    }
+}
+
+// Wakes up a thread sleeping on `word`, if there is one.
+//
+// `word` might have been freed by the time this is called, if the woken thread already saw its new value. At worst,
+// this then wakes up some unrelated thread, which has to deal with spurious wakeups anyway.
+pub fn wake_one(word: *const AtomicU32) {
+    unsafe {
+        syscall4(SYS_FUTEX, word as usize, FUTEX_WAKE | FUTEX_PRIVATE_FLAG, 1, 0);
+    }
+}
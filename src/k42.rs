@@ -0,0 +1,317 @@
+//! The K42 variant of the MCS lock, which needs no caller-supplied slot.
+//!
+//! The lock holds a queue node of its own. While the lock is held, the queue's tail points either at that node or at
+//! the last waiter, and the lock's node records the holder's successor. A waiter only needs a node of its own while it
+//! is waiting: once it gets the lock, it moves its successor (if any) into the lock's node, so its own node can go away.
+//! This is what lets `lock` and `try_lock` take nothing but `&self`, at the cost of a slightly longer acquire path than
+//! `mcs::Mutex`.
+//!
+//! The algorithm is from IBM's K42 operating system, as described by Scott in "Shared-Memory Synchronization".
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering, fence};
+
+use pause::pause;
+
+// The value of a waiter's `tail` while it is waiting.
+const WAITING: usize = 1;
+
+// In the lock itself, `tail` is the tail of the queue and `next` is the successor of the holder. In a waiter, `tail`
+// is only used as a flag that is cleared when the lock is handed over.
+struct Node {
+    tail: AtomicPtr<Node>,
+    next: AtomicPtr<Node>
+}
+
+/// An RAII implementation of a "scoped lock" of a mutex. When this structure is
+/// dropped (falls out of scope), the lock will be unlocked.
+///
+/// The data protected by the mutex can be access through this guard via its
+/// `Deref` and `DerefMut` implementations
+#[must_use]
+pub struct Guard<'a, T: ?Sized + 'a> {
+    lock: &'a Mutex<T>
+}
+
+/// A mutual exclusion primitive useful for protecting shared data, based on the K42 variant of the MCS lock.
+///
+/// This is used like `mcs::Mutex`, except that no `Slot` is needed.
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::Arc;
+/// use std::thread;
+/// use mcs::k42::Mutex;
+///
+/// let data = Arc::new(Mutex::new(0));
+///
+/// let threads: Vec<_> = (0..10).map(|_| {
+///     let data = data.clone();
+///     thread::spawn(move || {
+///         *data.lock() += 1;
+///     })
+/// }).collect();
+///
+/// for thread in threads {
+///     thread.join().unwrap();
+/// }
+///
+/// assert_eq!(*data.lock(), 10);
+/// ```
+pub struct Mutex<T: ?Sized> {
+    node: Node,
+    data: UnsafeCell<T>
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> { }
+unsafe impl<T: Send> Send for Mutex<T> { }
+
+impl<T> Mutex<T> {
+    /// Creates a new mutex in an unlocked state ready for use.
+    pub const fn new(value: T) -> Mutex<T> {
+        Mutex {
+            node: Node {
+                tail: AtomicPtr::new(ptr::null_mut()),
+                next: AtomicPtr::new(ptr::null_mut())
+            },
+            data: UnsafeCell::new(value)
+        }
+    }
+
+    /// Consumes this mutex, returning the underlying data.
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Attempts to acquire this lock.
+    ///
+    /// If the lock could not be acquired at this time, then `Err` is returned.
+    /// Otherwise, an RAII guard is returned. The lock will be unlocked when the
+    /// guard is dropped.
+    ///
+    /// This function does not block.
+    pub fn try_lock<'a>(&'a self) -> Result<Guard<'a, T>, ()> {
+        let own = &self.node as *const _ as *mut Node;
+        if self.node.tail.compare_exchange(ptr::null_mut(), own, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+            Ok(Guard {
+                lock: self
+            })
+        } else {
+            Err(())
+        }
+    }
+
+    /// Acquires a mutex, blocking the current thread until it is able to do so.
+    ///
+    /// This function will block the local thread until it is available to acquire
+    /// the mutex. Upon returning, the thread is the only thread with the mutex
+    /// held. An RAII guard is returned to allow scoped unlock of the lock. When
+    /// the guard goes out of scope, the mutex will be unlocked.
+    pub fn lock<'a>(&'a self) -> Guard<'a, T> {
+        let own = &self.node as *const _ as *mut Node;
+        loop {
+            let pred = self.node.tail.load(Ordering::Relaxed);
+            if pred.is_null() {
+                // The lock looks free, so try to take it with the lock's own node as the tail.
+                if self.node.tail.compare_exchange(ptr::null_mut(), own, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+                    break;
+                }
+                continue;
+            }
+
+            let node = Node {
+                tail: AtomicPtr::new(WAITING as *mut Node),
+                next: AtomicPtr::new(ptr::null_mut())
+            };
+            let this = &node as *const _ as *mut Node;
+            if self.node.tail.compare_exchange(pred, this, Ordering::AcqRel, Ordering::Relaxed).is_err() {
+                continue;
+            }
+
+            unsafe { (*pred).next.store(this, Ordering::Release); }
+            while node.tail.load(Ordering::Relaxed) as usize == WAITING {
+                pause();
+            }
+            fence(Ordering::Acquire);
+
+            // We have the lock, but our node is about to go away. Move our successor into the lock's node, or if we
+            // don't have one, make the lock's node the tail again.
+            let mut succ = node.next.load(Ordering::Acquire);
+            if succ.is_null() {
+                self.node.next.store(ptr::null_mut(), Ordering::Relaxed);
+                if self.node.tail.compare_exchange(this, own, Ordering::AcqRel, Ordering::Relaxed).is_ok() {
+                    break;
+                }
+
+                // Someone queued up behind us in the meantime. Wait for them to finish registering.
+                loop {
+                    succ = node.next.load(Ordering::Acquire);
+                    if !succ.is_null() {
+                        break;
+                    }
+                    pause();
+                }
+            }
+            self.node.next.store(succ, Ordering::Relaxed);
+            break;
+        }
+
+        Guard {
+            lock: self
+        }
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    ///
+    /// Since this call borrows the `Mutex` mutably, no actual locking needs to
+    /// take place---the mutable borrow statically guarantees no locks exist.
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+
+    // Releases the lock, handing it to the next waiter if there is one.
+    pub(crate) fn unlock(&self) {
+        let own = &self.node as *const _ as *mut Node;
+        let mut succ = self.node.next.load(Ordering::Acquire);
+        if succ.is_null() {
+            if self.node.tail.load(Ordering::Relaxed) == own &&
+               self.node.tail.compare_exchange(own, ptr::null_mut(), Ordering::Release, Ordering::Relaxed).is_ok() {
+                return;
+            }
+
+            // Some thread is waiting, but hasn't registered yet.
+            loop {
+                succ = self.node.next.load(Ordering::Acquire);
+                if !succ.is_null() {
+                    break;
+                }
+                pause();
+            }
+        }
+
+        // Announce to the next waiter that the lock is free.
+        unsafe { (*succ).tail.store(ptr::null_mut(), Ordering::Release); }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for Guard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for Guard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+guard_drop! {
+    impl<'a, T: ?Sized> Drop for Guard<'a, T> {
+        fn drop(&mut self) {
+            self.lock.unlock();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Mutex;
+
+    use std::sync::Arc;
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    #[derive(Eq, PartialEq, Debug)]
+    struct NonCopy(i32);
+
+    #[test]
+    fn smoke() {
+        let m = Mutex::new(());
+        drop(m.lock());
+        drop(m.lock());
+    }
+
+    #[test]
+    fn lots_and_lots() {
+        static LOCK: Mutex<u32> = Mutex::new(0);
+
+        const ITERS: u32 = 1000;
+        const CONCURRENCY: u32 = 3;
+
+        fn inc() {
+            for _ in 0..ITERS {
+                let mut g = LOCK.lock();
+                *g += 1;
+            }
+        }
+
+        let (tx, rx) = channel();
+        for _ in 0..CONCURRENCY {
+            let tx2 = tx.clone();
+            thread::spawn(move|| { inc(); tx2.send(()).unwrap(); });
+            let tx2 = tx.clone();
+            thread::spawn(move|| { inc(); tx2.send(()).unwrap(); });
+        }
+
+        drop(tx);
+        for _ in 0..2 * CONCURRENCY {
+            rx.recv().unwrap();
+        }
+        assert_eq!(*LOCK.lock(), ITERS * CONCURRENCY * 2);
+    }
+
+    #[test]
+    fn try_lock() {
+        let m = Mutex::new(());
+        let g = m.try_lock().unwrap();
+        assert!(m.try_lock().is_err());
+        drop(g);
+        *m.try_lock().unwrap() = ();
+    }
+
+    #[test]
+    fn test_into_inner() {
+        let m = Mutex::new(NonCopy(10));
+        assert_eq!(m.into_inner(), NonCopy(10));
+    }
+
+    #[test]
+    fn test_get_mut() {
+        let mut m = Mutex::new(NonCopy(10));
+        *m.get_mut() = NonCopy(20);
+        assert_eq!(m.into_inner(), NonCopy(20));
+    }
+
+    #[test]
+    fn test_lock_arc_nested() {
+        let arc = Arc::new(Mutex::new(1));
+        let arc2 = Arc::new(Mutex::new(arc));
+        let (tx, rx) = channel();
+        let _t = thread::spawn(move|| {
+            let lock = arc2.lock();
+            let lock2 = lock.lock();
+            assert_eq!(*lock2, 1);
+            tx.send(()).unwrap();
+        });
+        rx.recv().unwrap();
+    }
+
+    #[test]
+    fn test_lock_unsized() {
+        let lock: &Mutex<[i32]> = &Mutex::new([1, 2, 3]);
+        {
+            let b = &mut *lock.lock();
+            b[0] = 4;
+            b[2] = 5;
+        }
+        let comp: &[i32] = &[4, 2, 5];
+        assert_eq!(&*lock.lock(), comp);
+    }
+}
@@ -0,0 +1,427 @@
+// Lock ordering checked by the type system. Every `LeveledMutex` has a level, and locking one takes a `Token` for the
+// level of the last lock the thread took, which the level of the mutex has to be declared to come after. The token is
+// borrowed for as long as the guard lives, and a token for the new level is handed back, so the only way to lock
+// something else while holding the guard is through the new token. Tokens are zero-sized, so all of this compiles
+// away to a plain `Mutex::lock`.
+
+use core::marker::PhantomData;
+#[cfg(feature = "std")]
+use core::ops::{Deref, DerefMut};
+#[cfg(feature = "std")]
+use core::cell::Cell;
+
+#[cfg(feature = "std")]
+use std::thread_local;
+
+use mutex::{Guard, Mutex, Slot};
+use poison::{LockResult, PoisonError, TryLockError, TryLockResult};
+use relax::{Relax, SpinThenYield};
+
+/// A level in a lock hierarchy. Levels are usually uninhabited types, like `enum Config {}`.
+///
+/// Which levels may be locked after which is declared with `LockAfter`, usually through `lock_order!`.
+pub trait Level { }
+
+/// Declares that a mutex at level `Self` may be locked while one at level `A` is held.
+///
+/// This is not transitive by itself, so every pair has to be declared, including `Unlocked` for the levels that may be
+/// locked first. `lock_order!` does this for a whole chain of levels.
+pub trait LockAfter<A: Level>: Level { }
+
+/// The level of a thread that holds no leveled locks.
+pub enum Unlocked { }
+
+impl Level for Unlocked { }
+
+/// Declares a chain of levels, each of which may be locked after all the ones before it.
+///
+/// ```
+/// # #[macro_use] extern crate mcs;
+/// use mcs::{Level, Unlocked};
+///
+/// enum Config { }
+/// enum Cache { }
+/// enum Io { }
+/// impl Level for Config { }
+/// impl Level for Cache { }
+/// impl Level for Io { }
+///
+/// lock_order!(Unlocked => Config => Cache => Io);
+/// # fn main() { }
+/// ```
+#[macro_export]
+macro_rules! lock_order {
+    ($low:ty => $high:ty) => {
+        impl $crate::LockAfter<$low> for $high { }
+    };
+    ($low:ty => $high:ty $(=> $rest:ty)+) => {
+        impl $crate::LockAfter<$low> for $high { }
+        $( impl $crate::LockAfter<$low> for $rest { } )+
+        $crate::lock_order!($high $(=> $rest)+);
+    };
+}
+
+/// Proof that the highest level of lock the current thread holds is `L`.
+///
+/// A thread starts with an unlocked token, at level `Unlocked`, and gets tokens for higher levels by locking
+/// `LeveledMutex`es. It has to pass that token (or tokens derived from it) down to everything it calls, since a second
+/// one would know nothing about the locks taken with the first. With `std`, `Token::for_thread` hands out at most one
+/// unlocked token per thread at a time; otherwise `Token::new` is `unsafe`, and keeping to one is up to the caller.
+///
+/// Minting a second token to get around the order does not compile without `unsafe`:
+///
+/// ```compile_fail,E0133
+/// # use mcs::{Token, Unlocked};
+/// let token: Token<Unlocked> = Token::new();
+/// ```
+pub struct Token<'a, L: Level> {
+    _borrow: PhantomData<&'a mut ()>,
+    _level: PhantomData<fn() -> L>
+}
+
+impl Token<'static, Unlocked> {
+    /// Creates the token for a thread that holds no leveled locks.
+    ///
+    /// # Safety
+    ///
+    /// The current thread must not hold any `LeveledMutex` guards, and must not use any other unlocked token (or a
+    /// token derived from one) for as long as this token or any token derived from it is in use. Otherwise, locks can
+    /// be taken out of order, and the thread can deadlock.
+    pub const unsafe fn new() -> Token<'static, Unlocked> {
+        Token::forge()
+    }
+
+    /// Returns the unlocked token for the current thread, or `None` if the thread already has one.
+    ///
+    /// The token is handed back when the returned `ThreadToken` is dropped, and every guard locked with it must have
+    /// been dropped by then, so a thread can never hold two unlocked tokens at once.
+    #[cfg(feature = "std")]
+    pub fn for_thread() -> Option<ThreadToken> {
+        if TAKEN.with(|taken| taken.replace(true)) {
+            return None;
+        }
+        Some(ThreadToken {
+            token: Token::forge(),
+            _not_send: PhantomData
+        })
+    }
+}
+
+impl<'a, L: Level> Token<'a, L> {
+    // Only for a lock at level `L` that was just acquired.
+    const fn forge() -> Token<'a, L> {
+        Token {
+            _borrow: PhantomData,
+            _level: PhantomData
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+thread_local!(static TAKEN: Cell<bool> = const { Cell::new(false) });
+
+/// The unlocked token of a thread, from `Token::for_thread`. It dereferences to the token, and lets the thread take
+/// another one once it is dropped.
+#[cfg(feature = "std")]
+pub struct ThreadToken {
+    token: Token<'static, Unlocked>,
+    // The token belongs to the thread that took it.
+    _not_send: PhantomData<*mut ()>
+}
+
+#[cfg(feature = "std")]
+impl Deref for ThreadToken {
+    type Target = Token<'static, Unlocked>;
+
+    fn deref(&self) -> &Token<'static, Unlocked> {
+        &self.token
+    }
+}
+
+#[cfg(feature = "std")]
+impl DerefMut for ThreadToken {
+    fn deref_mut(&mut self) -> &mut Token<'static, Unlocked> {
+        &mut self.token
+    }
+}
+
+#[cfg(feature = "std")]
+impl Drop for ThreadToken {
+    fn drop(&mut self) {
+        // The thread's TLS may already be gone if the token is dropped during its teardown, and then there is nothing
+        // left to hand the token back to.
+        let _ = TAKEN.try_with(|taken| taken.set(false));
+    }
+}
+
+/// A `Mutex` at level `L` of a lock hierarchy, which can only be locked in the declared order.
+///
+/// Locking takes the `Token` for the level the thread is at, which must be one that `L` is declared to come after, and
+/// returns the guard together with a token for level `L`. The old token stays borrowed until the guard is dropped.
+///
+/// ```
+/// # #[macro_use] extern crate mcs;
+/// use mcs::{LeveledMutex, Level, Slot, Token, Unlocked};
+///
+/// enum Accounts { }
+/// enum Log { }
+/// impl Level for Accounts { }
+/// impl Level for Log { }
+/// lock_order!(Unlocked => Accounts => Log);
+///
+/// static ACCOUNTS: LeveledMutex<u32, Accounts> = LeveledMutex::new(0);
+/// static LOG: LeveledMutex<u32, Log> = LeveledMutex::new(0);
+///
+/// # fn main() {
+/// // Safety: this thread holds no leveled locks and has no other token.
+/// let mut token = unsafe { Token::new() };
+/// let mut slot1 = Slot::new();
+/// let mut slot2 = Slot::new();
+/// let (mut accounts, mut token) = ACCOUNTS.lock(&mut slot1, &mut token).unwrap();
+/// let (mut log, _) = LOG.lock(&mut slot2, &mut token).unwrap();
+/// *accounts += 1;
+/// *log += 1;
+/// # }
+/// ```
+///
+/// Locking them the other way around does not compile:
+///
+/// ```compile_fail
+/// # #[macro_use] extern crate mcs;
+/// # use mcs::{LeveledMutex, Level, Slot, Token, Unlocked};
+/// # enum Accounts { }
+/// # enum Log { }
+/// # impl Level for Accounts { }
+/// # impl Level for Log { }
+/// # lock_order!(Unlocked => Accounts => Log);
+/// # static ACCOUNTS: LeveledMutex<u32, Accounts> = LeveledMutex::new(0);
+/// # static LOG: LeveledMutex<u32, Log> = LeveledMutex::new(0);
+/// # fn main() {
+/// # let mut token = unsafe { Token::new() };
+/// # let mut slot1 = Slot::new();
+/// # let mut slot2 = Slot::new();
+/// let (log, mut token) = LOG.lock(&mut slot1, &mut token).unwrap();
+/// let (accounts, _) = ACCOUNTS.lock(&mut slot2, &mut token).unwrap();
+/// # }
+/// ```
+///
+/// Neither does using the old token while the guard is alive:
+///
+/// ```compile_fail,E0499
+/// # #[macro_use] extern crate mcs;
+/// # use mcs::{LeveledMutex, Level, Slot, Token, Unlocked};
+/// # enum Accounts { }
+/// # enum Log { }
+/// # impl Level for Accounts { }
+/// # impl Level for Log { }
+/// # lock_order!(Unlocked => Accounts => Log);
+/// # static ACCOUNTS: LeveledMutex<u32, Accounts> = LeveledMutex::new(0);
+/// # static LOG: LeveledMutex<u32, Log> = LeveledMutex::new(0);
+/// # fn main() {
+/// # let mut token = unsafe { Token::new() };
+/// # let mut slot1 = Slot::new();
+/// # let mut slot2 = Slot::new();
+/// let (log, _) = LOG.lock(&mut slot1, &mut token).unwrap();
+/// let (accounts, _) = ACCOUNTS.lock(&mut slot2, &mut token).unwrap();
+/// drop(log);
+/// # }
+/// ```
+///
+/// `Guard::unlocked` reacquires the lock at the same level, so the function it runs must release any leveled locks it
+/// takes before returning.
+pub struct LeveledMutex<T: ?Sized, L: Level, R = SpinThenYield> {
+    _level: PhantomData<fn() -> L>,
+    inner: Mutex<T, R>
+}
+
+impl<T, L: Level> LeveledMutex<T, L> {
+    /// Creates a new mutex at level `L` in an unlocked state ready for use.
+    pub const fn new(value: T) -> LeveledMutex<T, L> {
+        LeveledMutex {
+            _level: PhantomData,
+            inner: Mutex::new(value)
+        }
+    }
+}
+
+impl<T, L: Level, R> LeveledMutex<T, L, R> {
+    /// Creates a new mutex at level `L` in an unlocked state ready for use, which waits using the strategy `R`.
+    pub const fn with_relax(value: T) -> LeveledMutex<T, L, R> {
+        LeveledMutex {
+            _level: PhantomData,
+            inner: Mutex::with_relax(value)
+        }
+    }
+
+    /// Consumes this mutex, returning the underlying data.
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized, L: Level, R: Relax> LeveledMutex<T, L, R> {
+    /// Acquires the mutex like `Mutex::lock`, given a token for a level that `L` may be locked after. Returns the guard
+    /// and a token for level `L`, which borrows `token` for as long as the guard lives.
+    ///
+    /// # Errors
+    ///
+    /// If another user of this mutex panicked while holding the mutex, then this call will return an error once the
+    /// mutex is acquired.
+    #[cfg_attr(any(feature = "profile", all(feature = "lockdep", debug_assertions)), track_caller)]
+    pub fn lock<'a, A: Level>(&'a self, slot: &'a mut Slot, _token: &'a mut Token<A>) -> LockResult<(Guard<'a, T, R>, Token<'a, L>)>
+        where L: LockAfter<A> {
+        match self.inner.lock(slot) {
+            Ok(guard) => Ok((guard, Token::forge())),
+            Err(err) => Err(PoisonError::new((err.into_inner(), Token::forge())))
+        }
+    }
+
+    /// Attempts to acquire the mutex like `Mutex::try_lock`, given a token for a level that `L` may be locked after.
+    ///
+    /// # Errors
+    ///
+    /// Like `Mutex::try_lock`, this returns `Poisoned` if the mutex was poisoned, and `WouldBlock` if it is locked.
+    #[cfg_attr(any(feature = "profile", all(feature = "lockdep", debug_assertions)), track_caller)]
+    pub fn try_lock<'a, A: Level>(&'a self, slot: &'a mut Slot, _token: &'a mut Token<A>) -> TryLockResult<(Guard<'a, T, R>, Token<'a, L>)>
+        where L: LockAfter<A> {
+        match self.inner.try_lock(slot) {
+            Ok(guard) => Ok((guard, Token::forge())),
+            Err(TryLockError::Poisoned(err)) => {
+                Err(TryLockError::Poisoned(PoisonError::new((err.into_inner(), Token::forge()))))
+            },
+            Err(TryLockError::WouldBlock) => Err(TryLockError::WouldBlock)
+        }
+    }
+
+    /// Determines whether the mutex is poisoned. See `Mutex::is_poisoned`.
+    pub fn is_poisoned(&self) -> bool {
+        self.inner.is_poisoned()
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    ///
+    /// Since this call borrows the mutex mutably, no actual locking needs to take place, and no token is needed.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LeveledMutex, Level, Token, Unlocked};
+    use mutex::Slot;
+
+    #[cfg(feature = "std")]
+    use std::sync::Arc;
+    use std::thread;
+    use std::vec::Vec;
+
+    enum Outer { }
+    enum Middle { }
+    enum Inner { }
+    impl Level for Outer { }
+    impl Level for Middle { }
+    impl Level for Inner { }
+    lock_order!(Unlocked => Outer => Middle => Inner);
+
+    #[test]
+    fn smoke() {
+        let m = LeveledMutex::<_, Outer>::new(1);
+        let mut token = unsafe { Token::new() };
+        let mut slot = Slot::new();
+        let (mut g, _) = m.lock(&mut slot, &mut token).unwrap();
+        *g += 1;
+        drop(g);
+        assert_eq!(m.into_inner(), 2);
+    }
+
+    #[test]
+    fn skipping_levels() {
+        let outer = LeveledMutex::<_, Outer>::new(0);
+        let inner = LeveledMutex::<_, Inner>::new(0);
+        let mut token = unsafe { Token::new() };
+        let mut slot1 = Slot::new();
+        let mut slot2 = Slot::new();
+        {
+            let (mut g1, mut token) = outer.lock(&mut slot1, &mut token).unwrap();
+            let (mut g2, _) = inner.lock(&mut slot2, &mut token).unwrap();
+            *g1 += 1;
+            *g2 += 1;
+        }
+        // Inner locks can be taken on their own too.
+        let (g, _) = inner.try_lock(&mut slot2, &mut token).unwrap();
+        assert_eq!(*g, 1);
+    }
+
+    #[test]
+    fn lots_and_lots() {
+        static OUTER: LeveledMutex<u32, Outer> = LeveledMutex::new(0);
+        static MIDDLE: LeveledMutex<u32, Middle> = LeveledMutex::new(0);
+
+        const J: u32 = 200;
+        const K: u32 = 3;
+
+        let threads: Vec<_> = (0..K).map(|_| {
+            thread::spawn(move|| {
+                let mut token = unsafe { Token::new() };
+                let mut slot1 = Slot::new();
+                let mut slot2 = Slot::new();
+                for _ in 0..J {
+                    let (mut outer, mut token) = OUTER.lock(&mut slot1, &mut token).unwrap();
+                    let (mut middle, _) = MIDDLE.lock(&mut slot2, &mut token).unwrap();
+                    *outer += 1;
+                    *middle += 1;
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let mut token = unsafe { Token::new() };
+        let mut slot = Slot::new();
+        assert_eq!(*OUTER.lock(&mut slot, &mut token).unwrap().0, J * K);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn poison() {
+        let m = Arc::new(LeveledMutex::<_, Middle>::new(1));
+        let m2 = m.clone();
+        let _ = thread::spawn(move|| {
+            let mut token = unsafe { Token::new() };
+            let mut slot = Slot::new();
+            let _g = m2.lock(&mut slot, &mut token).unwrap();
+            panic!("test panic in inner thread to poison mutex");
+        }).join();
+        assert!(m.is_poisoned());
+
+        let mut token = unsafe { Token::new() };
+        let mut slot = Slot::new();
+        match m.lock(&mut slot, &mut token) {
+            Err(err) => assert_eq!(*err.into_inner().0, 1),
+            Ok(_) => panic!("lock should be poisoned")
+        };
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn one_token_per_thread() {
+        let m = LeveledMutex::<_, Outer>::new(1);
+        let mut token = Token::for_thread().unwrap();
+        let mut slot = Slot::new();
+        {
+            let (mut g, _) = m.lock(&mut slot, &mut token).unwrap();
+            *g += 1;
+            // A second unlocked token would let this thread lock `Outer` again.
+            assert!(Token::for_thread().is_none());
+        }
+        assert!(Token::for_thread().is_none());
+
+        // Other threads have their own.
+        thread::spawn(|| assert!(Token::for_thread().is_some())).join().unwrap();
+
+        drop(token);
+        assert!(Token::for_thread().is_some());
+    }
+}
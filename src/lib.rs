@@ -1,14 +1,90 @@
-#![cfg_attr(feature = "unstable", feature(asm, const_fn, generic_param_attrs, dropck_eyepatch))]
+#![cfg_attr(feature = "unstable", feature(dropck_eyepatch))]
 
 #![no_std]
 
-#[cfg(test)]
-#[macro_use]
-extern crate lazy_static;
-#[cfg(test)]
+// The `try_lock` methods of the simple spinlocks have nothing to say about why they failed.
+#![allow(clippy::result_unit_err)]
+
+#[cfg(any(test, feature = "std"))]
 extern crate std;
+#[cfg(feature = "lock_api")]
+extern crate lock_api;
+
+// Implements `Drop` for a guard. With the `unstable` feature, the protected data's type parameter is marked
+// `#[may_dangle]`, since dropping a guard only releases the lock and never touches the data. Attributes on generic
+// parameters are themselves unstable, so without the feature the same impl is generated without it.
+macro_rules! guard_drop {
+    (impl<$lt:lifetime, $t:ident: ?Sized $(, $p:ident $(: $bound:path)?)*> Drop for $guard:ty {
+        fn drop(&mut $this:ident) $body:block
+    }) => {
+        #[cfg(feature = "unstable")]
+        unsafe impl<$lt, #[may_dangle] $t: ?Sized $(, $p $(: $bound)?)*> Drop for $guard {
+            fn drop(&mut $this) $body
+        }
+
+        #[cfg(not(feature = "unstable"))]
+        impl<$lt, $t: ?Sized $(, $p $(: $bound)?)*> Drop for $guard {
+            fn drop(&mut $this) $body
+        }
+    };
+}
 
+#[cfg(feature = "std")]
+pub mod clh;
+pub mod cna;
+pub mod cohort;
+mod clock;
+mod condvar;
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "riscv64")))]
+mod futex;
+pub mod k42;
+mod leveled;
+mod lockdep;
 mod mutex;
+mod numa;
+mod observer;
+mod park;
 mod pause;
+mod poison;
+#[cfg(feature = "profile")]
+pub mod profile;
+#[cfg(not(feature = "profile"))]
+mod profile;
+#[cfg(feature = "std")]
+pub mod qspinlock;
+#[cfg(feature = "lock_api")]
+pub mod raw;
+mod reentrant;
+pub mod relax;
+mod rwlock;
+mod stats;
+#[cfg(feature = "trace")]
+pub mod trace;
+#[cfg(not(feature = "trace"))]
+mod trace;
+#[cfg(feature = "watchdog")]
+pub mod watchdog;
+#[cfg(not(feature = "watchdog"))]
+mod watchdog;
 
-pub use mutex::{Slot, Mutex, Guard};
+pub use clock::Clock;
+#[cfg(feature = "std")]
+pub use clock::StdClock;
+pub use condvar::Condvar;
+pub use mutex::{Slot, Mutex, Guard, MappedGuard, LockFuture};
+pub use leveled::{Level, LockAfter, Unlocked, Token, LeveledMutex};
+#[cfg(feature = "std")]
+pub use leveled::ThreadToken;
+pub use numa::NodeId;
+#[cfg(feature = "observer")]
+pub use observer::{LockObserver, set_global_observer};
+pub use poison::{PoisonError, TryLockError, LockResult, TryLockResult};
+#[cfg(feature = "lock_api")]
+pub use raw::RawMcs;
+pub use relax::Relax;
+pub use reentrant::{ReentrantMutex, ReentrantGuard, ThreadId};
+#[cfg(feature = "std")]
+pub use reentrant::StdThreadId;
+pub use rwlock::{RwSlot, RwLock, ReadGuard, WriteGuard};
+#[cfg(feature = "stats")]
+pub use stats::Stats;
@@ -0,0 +1,415 @@
+// A lock order validator for `Mutex`, enabled by the `lockdep` feature in debug builds. In release builds, or without
+// the feature, it does nothing and takes up no space.
+//
+// Every mutex gets a class the first time it is locked. Each thread keeps a stack of the classes it holds, and every
+// time it blocks on a lock while holding others, we note in a global graph that those were acquired before it. If the
+// new edges would close a cycle in the graph, some interleaving of the threads involved can deadlock, so we panic
+// right then, before queueing up, with the chain of acquisitions that this thread is making and the chain that was
+// seen before in the opposite order.
+//
+// Only blocking acquisitions (`lock`, and the reacquisition in `Guard::unlocked`) are checked, since `try_lock` and the
+// timed locks can't wait forever. Locks taken with `try_lock` or a timed lock still count as held for later
+// acquisitions. Locks taken with `lock_async` are not tracked, since a task can move between threads while it holds
+// them.
+//
+// Guards can be sent to other threads, so a lock remembers the stack of the thread that acquired it, and is removed
+// from that stack when it is released, wherever that happens. When a mutex is dropped, its class is removed from the
+// graph, so programs that keep making new mutexes don't keep growing it.
+//
+// A thread that is already panicking, like one reacquiring a lock while unwinding out of `Guard::unlocked`, only
+// prints what it found, since panicking again would abort the process.
+
+#[cfg(all(feature = "lockdep", debug_assertions))]
+use core::cell::UnsafeCell;
+#[cfg(all(feature = "lockdep", debug_assertions))]
+use core::fmt::Write;
+#[cfg(all(feature = "lockdep", debug_assertions))]
+use core::panic::Location;
+#[cfg(all(feature = "lockdep", debug_assertions))]
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(all(feature = "lockdep", debug_assertions))]
+use std::collections::{BTreeMap, BTreeSet};
+#[cfg(all(feature = "lockdep", debug_assertions))]
+use std::eprintln;
+#[cfg(all(feature = "lockdep", debug_assertions))]
+use std::string::String;
+#[cfg(all(feature = "lockdep", debug_assertions))]
+use std::sync::{self, Arc};
+#[cfg(all(feature = "lockdep", debug_assertions))]
+use std::thread;
+#[cfg(all(feature = "lockdep", debug_assertions))]
+use std::thread_local;
+#[cfg(all(feature = "lockdep", debug_assertions))]
+use std::vec::Vec;
+
+use profile::Wait;
+
+// Where the lock at the start of an edge was acquired, and where the one at the end was then acquired.
+#[cfg(all(feature = "lockdep", debug_assertions))]
+#[derive(Clone, Copy)]
+struct Edge {
+    from: &'static Location<'static>,
+    to: &'static Location<'static>
+}
+
+// For each class, the classes that were acquired while it was held.
+#[cfg(all(feature = "lockdep", debug_assertions))]
+static GRAPH: sync::Mutex<BTreeMap<usize, BTreeMap<usize, Edge>>> = sync::Mutex::new(BTreeMap::new());
+
+#[cfg(all(feature = "lockdep", debug_assertions))]
+static NEXT_CLASS: AtomicUsize = AtomicUsize::new(1);
+
+// The classes a thread holds, in the order it acquired them, and where it did.
+#[cfg(all(feature = "lockdep", debug_assertions))]
+type Held = sync::Mutex<Vec<(usize, &'static Location<'static>)>>;
+
+#[cfg(all(feature = "lockdep", debug_assertions))]
+thread_local! {
+    static HELD: Arc<Held> = Arc::new(sync::Mutex::new(Vec::new()));
+}
+
+#[cfg(all(feature = "lockdep", debug_assertions))]
+fn lock_held(held: &Held) -> sync::MutexGuard<'_, Vec<(usize, &'static Location<'static>)>> {
+    held.lock().unwrap_or_else(sync::PoisonError::into_inner)
+}
+
+// Finds a path from `from` to `to` in `graph`, returning its edges.
+#[cfg(all(feature = "lockdep", debug_assertions))]
+fn path(graph: &BTreeMap<usize, BTreeMap<usize, Edge>>, from: usize, to: usize) -> Option<Vec<(usize, usize, Edge)>> {
+    let mut seen = BTreeSet::new();
+    let mut stack = Vec::new();
+    let mut edges = graph.get(&from)?.iter();
+    let mut at = from;
+    seen.insert(from);
+    loop {
+        match edges.next() {
+            Some((&next, &edge)) => {
+                if next == to {
+                    let mut path: Vec<_> = stack.iter().map(|&(from, to, edge, _)| (from, to, edge)).collect();
+                    path.push((at, next, edge));
+                    return Some(path);
+                }
+                if seen.insert(next) {
+                    if let Some(next_edges) = graph.get(&next) {
+                        stack.push((at, next, edge, edges));
+                        edges = next_edges.iter();
+                        at = next;
+                    }
+                }
+            },
+            None => {
+                let (prev, _, _, prev_edges) = stack.pop()?;
+                edges = prev_edges;
+                at = prev;
+            }
+        }
+    }
+}
+
+// The lock order class of a mutex.
+pub struct Class {
+    #[cfg(all(feature = "lockdep", debug_assertions))]
+    id: AtomicUsize,
+    // The stack of the thread holding the lock, if it is tracked. Only touched by the holder.
+    #[cfg(all(feature = "lockdep", debug_assertions))]
+    held: UnsafeCell<Option<Arc<Held>>>
+}
+
+#[cfg(all(feature = "lockdep", debug_assertions))]
+unsafe impl Sync for Class { }
+
+#[cfg(all(feature = "lockdep", debug_assertions))]
+impl Class {
+    #[allow(clippy::declare_interior_mutable_const)]
+    pub const INIT: Class = Class {
+        id: AtomicUsize::new(0),
+        held: UnsafeCell::new(None)
+    };
+
+    fn id(&self) -> usize {
+        let id = self.id.load(Ordering::Relaxed);
+        if id != 0 {
+            return id;
+        }
+        let new = NEXT_CLASS.fetch_add(1, Ordering::Relaxed);
+        match self.id.compare_exchange(0, new, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => new,
+            Err(id) => id
+        }
+    }
+
+    // Called before the current thread blocks to acquire a lock of this class. Panics if that could deadlock.
+    pub fn check(&self, wait: Wait) {
+        let class = self.id();
+        let location = wait.location();
+        // This fails if a lock is acquired from a thread-local destructor after ours has run, and then nothing is held.
+        if let Ok(Some(report)) = HELD.try_with(|held| check_held(&lock_held(held), class, location)) {
+            // Both locks are released by now, so that the panic doesn't poison them.
+            fail(report);
+        }
+    }
+
+    // Called by the current thread after it acquires a lock of this class.
+    pub fn acquired(&self, wait: Wait) {
+        let class = self.id();
+        // This fails if a lock is acquired from a thread-local destructor after ours has run.
+        let held = HELD.try_with(|held| {
+            lock_held(held).push((class, wait.location()));
+            held.clone()
+        });
+        unsafe { *self.held.get() = held.ok(); }
+    }
+
+    // Called by the holder, which might not be the thread that acquired it, before it releases a lock of this class.
+    pub fn releasing(&self) {
+        // A lock acquired with `lock_async` is not on any stack.
+        if let Some(held) = unsafe { (*self.held.get()).take() } {
+            let class = self.id.load(Ordering::Relaxed);
+            let mut held = lock_held(&held);
+            // Guards can be dropped in any order.
+            if let Some(index) = held.iter().rposition(|&(held, _)| held == class) {
+                held.remove(index);
+            }
+        }
+    }
+}
+
+#[cfg(all(feature = "lockdep", debug_assertions))]
+impl Drop for Class {
+    fn drop(&mut self) {
+        let class = *self.id.get_mut();
+        if class == 0 {
+            return;
+        }
+        let mut graph = GRAPH.lock().unwrap_or_else(sync::PoisonError::into_inner);
+        graph.remove(&class);
+        graph.retain(|_, edges| {
+            edges.remove(&class);
+            !edges.is_empty()
+        });
+    }
+}
+
+// Checks that acquiring `class` at `location` while holding the locks in `held` can't deadlock, adding the new edges to
+// the graph. Returns a report of the problem if it can.
+#[cfg(all(feature = "lockdep", debug_assertions))]
+fn check_held(held: &[(usize, &'static Location<'static>)], class: usize, location: &'static Location<'static>) -> Option<String> {
+    if held.is_empty() {
+        return None;
+    }
+    if let Some(&(_, at)) = held.iter().find(|&&(held, _)| held == class) {
+        let mut report = String::new();
+        let _ = write!(report, "possible deadlock: lock #{} is acquired at {} while this thread already holds it, since {}",
+                       class, location, at);
+        return Some(report);
+    }
+
+    let mut graph = GRAPH.lock().unwrap_or_else(sync::PoisonError::into_inner);
+    for &(held, at) in held {
+        if graph.get(&held).is_some_and(|edges| edges.contains_key(&class)) {
+            continue;
+        }
+        if let Some(path) = path(&graph, class, held) {
+            return Some(inversion(class, location, held, at, &path));
+        }
+        graph.entry(held).or_default().insert(class, Edge { from: at, to: location });
+    }
+    None
+}
+
+// Reports a problem that could deadlock.
+#[cfg(all(feature = "lockdep", debug_assertions))]
+fn fail(report: String) {
+    if thread::panicking() {
+        eprintln!("{}", report);
+    } else {
+        panic!("{}", report);
+    }
+}
+
+// Describes acquiring `class` at `location` while holding `held`, which was acquired at `at`, when `path` leads from
+// `class` back to `held`.
+#[cfg(all(feature = "lockdep", debug_assertions))]
+fn inversion(class: usize, location: &Location, held: usize, at: &Location, path: &[(usize, usize, Edge)]) -> String {
+    let mut report = String::new();
+    let _ = writeln!(report, "possible deadlock: lock order inversion between lock #{} and lock #{}", held, class);
+    let _ = writeln!(report, "this thread holds lock #{}, acquired at {},", held, at);
+    let _ = writeln!(report, "  and is acquiring lock #{} at {}", class, location);
+    let _ = writeln!(report, "but locks were previously acquired in the opposite order:");
+    for &(from, to, edge) in path {
+        let _ = writeln!(report, "  lock #{}, acquired at {}, was held while acquiring lock #{} at {}", from, edge.from, to, edge.to);
+    }
+    report
+}
+
+#[cfg(not(all(feature = "lockdep", debug_assertions)))]
+impl Class {
+    pub const INIT: Class = Class { };
+
+    #[inline(always)]
+    pub fn check(&self, _wait: Wait) { }
+
+    #[inline(always)]
+    pub fn acquired(&self, _wait: Wait) { }
+
+    #[inline(always)]
+    pub fn releasing(&self) { }
+}
+
+#[cfg(all(test, feature = "lockdep", debug_assertions))]
+mod test {
+    use super::{Class, GRAPH};
+    use mutex::{Mutex, Slot};
+    use poison::TryLockError;
+    use profile::Wait;
+
+    use core::mem;
+    use core::sync::atomic::Ordering;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::string::String;
+    use std::thread;
+
+    // Runs `f`, returning the message it panics with.
+    fn panic_message<F: FnOnce()>(f: F) -> String {
+        let err = panic::catch_unwind(AssertUnwindSafe(f)).unwrap_err();
+        err.downcast::<String>().map(|s| *s).unwrap()
+    }
+
+    #[test]
+    fn consistent_order() {
+        let a = Mutex::new(());
+        let b = Mutex::new(());
+        let mut slot_a = Slot::new();
+        let mut slot_b = Slot::new();
+        for _ in 0..2 {
+            let _ga = a.lock(&mut slot_a).unwrap();
+            let _gb = b.lock(&mut slot_b).unwrap();
+        }
+        // Releasing in either order is fine.
+        let ga = a.lock(&mut slot_a).unwrap();
+        let gb = b.lock(&mut slot_b).unwrap();
+        drop(ga);
+        drop(gb);
+    }
+
+    #[test]
+    fn inversion() {
+        let a = Mutex::new(());
+        let b = Mutex::new(());
+        let mut slot_a = Slot::new();
+        let mut slot_b = Slot::new();
+        {
+            let _ga = a.lock(&mut slot_a).unwrap();
+            let _gb = b.lock(&mut slot_b).unwrap();
+        }
+        let message = panic_message(|| {
+            let _gb = b.lock(&mut slot_b).unwrap();
+            let _ga = a.lock(&mut slot_a).unwrap();
+        });
+        assert!(message.contains("lock order inversion"), "{}", message);
+        assert!(message.contains(file!()), "{}", message);
+        assert_eq!(message.lines().count(), 5, "{}", message);
+
+        // The panic happened before queueing up, so nothing is left locked.
+        assert!(!matches!(a.try_lock(&mut slot_a), Err(TryLockError::WouldBlock)));
+        assert!(!matches!(b.try_lock(&mut slot_b), Err(TryLockError::WouldBlock)));
+    }
+
+    #[test]
+    fn longer_cycle() {
+        let locks = [Mutex::new(()), Mutex::new(()), Mutex::new(())];
+        let mut slots = [Slot::new(), Slot::new()];
+        for i in 0..2 {
+            let (first, second) = slots.split_at_mut(1);
+            let _g1 = locks[i].lock(&mut first[0]).unwrap();
+            let _g2 = locks[i + 1].lock(&mut second[0]).unwrap();
+        }
+        let message = panic_message(|| {
+            let (first, second) = slots.split_at_mut(1);
+            let _g1 = locks[2].lock(&mut first[0]).unwrap();
+            let _g2 = locks[0].lock(&mut second[0]).unwrap();
+        });
+        assert!(message.contains("lock order inversion"), "{}", message);
+        assert_eq!(message.lines().count(), 6, "{}", message);
+    }
+
+    #[test]
+    fn recursive() {
+        let a = Mutex::new(());
+        let mut slot1 = Slot::new();
+        let mut slot2 = Slot::new();
+        let message = panic_message(|| {
+            let _g1 = a.lock(&mut slot1).unwrap();
+            let _g2 = a.lock(&mut slot2).unwrap();
+        });
+        assert!(message.contains("already holds it"), "{}", message);
+    }
+
+    #[test]
+    fn try_lock_is_not_checked() {
+        let a = Mutex::new(());
+        let b = Mutex::new(());
+        let mut slot_a = Slot::new();
+        let mut slot_b = Slot::new();
+        {
+            let _ga = a.lock(&mut slot_a).unwrap();
+            let _gb = b.lock(&mut slot_b).unwrap();
+        }
+        let _gb = b.lock(&mut slot_b).unwrap();
+        let _ga = a.try_lock(&mut slot_a).unwrap();
+    }
+
+    #[test]
+    fn guard_released_on_another_thread() {
+        let a = Mutex::new(());
+        let mut slot = Slot::new();
+        let g = a.lock(&mut slot).unwrap();
+        thread::scope(|scope| {
+            scope.spawn(move || drop(g));
+        });
+        // This thread no longer holds it.
+        drop(a.lock(&mut slot).unwrap());
+    }
+
+    #[test]
+    fn no_double_panic_while_unwinding() {
+        let a = Mutex::new(());
+        let b = Mutex::new(());
+        let mut slot_a = Slot::new();
+        let mut slot_b = Slot::new();
+        {
+            let _ga = a.lock(&mut slot_a).unwrap();
+            let _gb = b.lock(&mut slot_b).unwrap();
+        }
+        let message = panic_message(|| {
+            let mut ga = a.lock(&mut slot_a).unwrap();
+            ga.unlocked(|| {
+                // Reacquiring `a` while still holding `b` is an inversion, found while unwinding.
+                mem::forget(b.lock(&mut slot_b).unwrap());
+                panic::panic_any(String::from("in the closure"));
+            });
+        });
+        assert_eq!(message, "in the closure");
+    }
+
+    #[test]
+    fn dropped_classes_leave_the_graph() {
+        let a = Class::INIT;
+        let b = Class::INIT;
+        b.check(Wait::start());
+        b.acquired(Wait::start());
+        a.check(Wait::start());
+        a.acquired(Wait::start());
+        a.releasing();
+        b.releasing();
+        let (a_id, b_id) = (a.id.load(Ordering::Relaxed), b.id.load(Ordering::Relaxed));
+        assert!(GRAPH.lock().unwrap()[&b_id].contains_key(&a_id));
+
+        drop(a);
+        drop(b);
+        let graph = GRAPH.lock().unwrap();
+        assert!(!graph.contains_key(&b_id));
+        assert!(graph.values().all(|edges| !edges.contains_key(&a_id)));
+    }
+}
@@ -1,12 +1,84 @@
 use core::cell::UnsafeCell;
+use core::future::Future;
+use core::marker::{PhantomData, PhantomPinned};
+use core::mem::ManuallyDrop;
 use core::ops::{Deref, DerefMut};
+use core::pin::Pin;
 use core::ptr;
-use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering, fence};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicUsize, Ordering, fence};
+use core::task::{Context, Poll, Waker};
 
+use clock::Clock;
+use lockdep;
+use observer;
+#[cfg(feature = "observer")]
+use observer::LockObserver;
+use park::{self, Parker};
 use pause::pause;
+use profile;
+use poison::{self, LockResult, PoisonError, TryLockError, TryLockResult};
+use relax::{Relax, SpinThenYield};
+use stats;
+use trace;
+use watchdog;
+#[cfg(feature = "stats")]
+use stats::Stats;
 
 pub struct Slot {
-    next: AtomicPtr<AtomicBool>
+    next: AtomicPtr<Waiter>,
+    // The number of `MappedGuard`s left from `map_split` that hold the lock taken with this slot. Only the last one to
+    // be dropped releases the lock.
+    split: AtomicUsize
+}
+
+/// The node a thread spins on while it is queued behind another slot. Unlike the `Slot`, this only lives for as long as
+/// the thread is waiting.
+struct Waiter {
+    state: AtomicU32,
+    parker: Parker,
+    // For `lock_async`. Owned by the waiting task unless the state is `REGISTERED`, and by the releasing thread while
+    // the state is `WAKING`.
+    waker: UnsafeCell<Option<Waker>>,
+    // The slot that this waiter has registered itself with. This only changes when a waiter in front of us times out,
+    // and is only touched while holding `LEAVE_LOCK`.
+    prev: AtomicPtr<Slot>
+}
+
+// Waiter states
+const WAITING: u32 = 0;
+const GRANTED: u32 = 1;
+const LEAVING: u32 = 2;
+const PARKED: u32 = 3;
+const REGISTERED: u32 = 4;
+const WAKING: u32 = 5;
+
+// Values of `Slot::next` that are not waiters. While handing off the lock, the holder replaces its successor with
+// `CLAIMED` so that the successor can tell that it is too late to time out. If it was trying to anyway, it answers with
+// `ACKED` once it will no longer touch the holder's slot.
+const CLAIMED: usize = 1;
+const ACKED: usize = 2;
+
+// Waiters that time out unlink themselves one at a time. This means that the neighbours of a leaving waiter are never
+// leaving themselves, so they stay put while we relink around the leaving waiter. Timeouts are expected to be rare, so
+// this is shared by all mutexes instead of taking up space in each one.
+static LEAVE_LOCK: AtomicBool = AtomicBool::new(false);
+
+/// A future that resolves to a `Guard` once the lock has been acquired, or to a `PoisonError` if the lock was
+/// poisoned. Created by `Mutex::lock_async`.
+///
+/// Dropping this before it completes takes it out of the queue for the lock, without disturbing anyone else waiting.
+#[must_use]
+pub struct LockFuture<'a, T: ?Sized + 'a, R: Relax + 'a = SpinThenYield> {
+    lock: &'a Mutex<T, R>,
+    slot: &'a Slot,
+    wait: profile::Wait,
+    queued: bool,
+    done: bool,
+    waiter: Waiter,
+    // Watches the task's wait while it is queued.
+    watch: watchdog::Watch,
+    // Once we are queued, our predecessor points at `waiter`.
+    _pinned: PhantomPinned
 }
 
 /// An RAII implementation of a "scoped lock" of a mutex. When this structure is
@@ -15,9 +87,47 @@ pub struct Slot {
 /// The data protected by the mutex can be access through this guard via its
 /// `Deref` and `DerefMut` implementations
 #[must_use]
-pub struct Guard<'a, T: ?Sized + 'a> {
-    lock: &'a Mutex<T>,
-    slot: &'a Slot
+pub struct Guard<'a, T: ?Sized + 'a, R: Relax + 'a = SpinThenYield> {
+    lock: &'a Mutex<T, R>,
+    slot: &'a Slot,
+    poison: poison::Panicking
+}
+
+/// An RAII guard for a part of the data protected by a mutex, created by `Guard::map` and friends. When this structure
+/// is dropped, the lock will be unlocked, unless it was split with `map_split` and the other parts are still alive.
+///
+/// The part of the data can be accessed through this guard via its `Deref` and `DerefMut` implementations.
+///
+/// A `MappedGuard` can't be sent to another thread:
+///
+/// ```compile_fail,E0277
+/// use std::thread;
+/// use mcs::{Guard, Mutex, Slot};
+///
+/// static LOCK: Mutex<(u32, u32)> = Mutex::new((0, 0));
+///
+/// let mut slot = Slot::new();
+/// let first = Guard::map(LOCK.lock(&mut slot).unwrap(), |pair| &mut pair.0);
+/// thread::scope(|scope| {
+///     scope.spawn(move || drop(first));
+/// });
+/// ```
+#[must_use]
+pub struct MappedGuard<'a, U: ?Sized + 'a, R: Relax + 'a = SpinThenYield> {
+    held: Held<'a, R>,
+    data: *mut U,
+    _marker: PhantomData<&'a mut U>
+}
+
+// The lock behind a `MappedGuard`. Dropping this releases the lock, unless it was split and the other parts are still
+// alive.
+struct Held<'a, R: Relax> {
+    raw: &'a Raw,
+    slot: &'a Slot,
+    poison: poison::Panicking,
+    // Whether the lock is shared with other parts through `slot.split`.
+    shared: bool,
+    _relax: PhantomData<fn() -> R>
 }
 
 /// A mutual exclusion primitive useful for protecting shared data
@@ -58,7 +168,7 @@ pub struct Guard<'a, T: ?Sized + 'a> {
 ///         //
 ///         // We unwrap() the return value to assert that we are not expecting
 ///         // threads to ever fail while holding the lock.
-///         let mut data = data.lock(&mut slot);
+///         let mut data = data.lock(&mut slot).unwrap();
 ///         *data += 1;
 ///         if *data == N {
 ///             tx.send(()).unwrap();
@@ -69,58 +179,205 @@ pub struct Guard<'a, T: ?Sized + 'a> {
 ///
 /// rx.recv().unwrap();
 /// ```
-pub struct Mutex<T: ?Sized> {
-    queue: AtomicPtr<Slot>,
+///
+/// # Waiting
+///
+/// Threads wait for the lock by spinning, and with the `std` or `futex` feature, park once they have been spinning for
+/// a while. How they spin is up to the `Relax` strategy `R`, which can be picked with `Mutex::with_relax`.
+///
+/// # Poisoning
+///
+/// With the `std` feature, the mutex is poisoned if a thread panics while holding it, just like `std::sync::Mutex`.
+/// From then on, every attempt to acquire it returns an error, which still gives access to the data through
+/// `PoisonError::into_inner`. Without `std`, there is no way to tell whether a thread is panicking, so the mutex is never
+/// poisoned.
+pub struct Mutex<T: ?Sized, R = SpinThenYield> {
+    raw: Raw,
+    _relax: PhantomData<fn() -> R>,
     data: UnsafeCell<T>
 }
 
-unsafe impl<T: Send> Sync for Mutex<T> { }
-unsafe impl<T: Send> Send for Mutex<T> { }
+// The part of a `Mutex` that does not depend on the type of the data, so that a `MappedGuard` can release the lock
+// without knowing what it was originally protecting.
+struct Raw {
+    queue: AtomicPtr<Slot>,
+    poison: poison::Flag,
+    stats: stats::Counters,
+    observers: observer::Observers,
+    profile: profile::Holder,
+    lockdep: lockdep::Class,
+    watchdog: watchdog::Holder
+}
+
+// A bare MCS lock on the same slots, with none of the poisoning, statistics, observers, or checks of `Mutex`, for locks
+// that are part of another lock, like the per-node locks of `cohort::Mutex`. Waiters cannot time out.
+pub(crate) struct Queue {
+    tail: AtomicPtr<Slot>
+}
+
+unsafe impl<T: Send, R> Sync for Mutex<T, R> { }
+unsafe impl<T: Send, R> Send for Mutex<T, R> { }
+
+unsafe impl<'a, U: ?Sized + Sync, R: Relax> Sync for MappedGuard<'a, U, R> { }
 
 impl Slot {
-    #[cfg(feature = "unstable")]
     pub const fn new() -> Slot {
         Slot {
-            next: AtomicPtr::new(ptr::null_mut())
+            next: AtomicPtr::new(ptr::null_mut()),
+            split: AtomicUsize::new(0)
         }
     }
+}
 
-    #[cfg(not(feature = "unstable"))]
-    pub fn new() -> Slot {
-        Slot {
-            next: AtomicPtr::new(ptr::null_mut())
+impl Default for Slot {
+    fn default() -> Slot {
+        Slot::new()
+    }
+}
+
+impl Waiter {
+    fn new(prev: *mut Slot) -> Waiter {
+        Waiter {
+            state: AtomicU32::new(WAITING),
+            parker: Parker::new(),
+            waker: UnsafeCell::new(None),
+            prev: AtomicPtr::new(prev)
+        }
+    }
+
+    // Waits for the lock to be handed to us, spinning for a while and then parking. Returns how many times it had to
+    // wait again, including the times it parked (or, without anything to park on, kept spinning).
+    fn wait<R: Relax>(&self) -> usize {
+        let mut relax = R::default();
+        let mut spins = 0;
+        while self.state.load(Ordering::Relaxed) == WAITING {
+            if spins < park::SPIN_LIMIT as usize {
+                relax.relax();
+            } else {
+                self.parker.park(&self.state, WAITING, PARKED, R::FUTEX);
+            }
+            spins += 1;
+        }
+        fence(Ordering::Acquire);
+        spins
+    }
+
+    // Hands the lock to this waiter, returning its previous state. The waiter may be gone as soon as this returns.
+    unsafe fn grant(&self) -> u32 {
+        // Acquire, so that a parked or registered waiter's thread handle or waker is visible to us.
+        let mut current = self.state.load(Ordering::Acquire);
+        loop {
+            let result = match current {
+                // Once parked, the waiter won't change its own state again.
+                PARKED => return self.parker.set(&self.state, GRANTED, PARKED),
+                REGISTERED => {
+                    // Lock out the waiting task, so that we can take its waker.
+                    match self.state.compare_exchange_weak(REGISTERED, WAKING, Ordering::Acquire, Ordering::Acquire) {
+                        Ok(_) => {
+                            let waker = (*self.waker.get()).take();
+                            self.state.store(GRANTED, Ordering::Release);
+                            if let Some(waker) = waker {
+                                waker.wake();
+                            }
+                            return REGISTERED;
+                        },
+                        Err(actual) => actual
+                    }
+                },
+                _ => match self.state.compare_exchange_weak(current, GRANTED, Ordering::AcqRel, Ordering::Acquire) {
+                    Ok(_) => return current,
+                    Err(actual) => actual
+                }
+            };
+            current = result;
+        }
+    }
+
+    // Makes sure that the lock will be handed to us while we are not looking, then returns `true`. Returns `false`
+    // if we already have the lock. Must be called by the waiting task.
+    fn unregister(&self) -> bool {
+        loop {
+            match self.state.load(Ordering::Relaxed) {
+                WAITING => return true,
+                REGISTERED => {
+                    if self.state.compare_exchange_weak(REGISTERED, WAITING, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+                        return true;
+                    }
+                },
+                GRANTED => {
+                    fence(Ordering::Acquire);
+                    return false;
+                },
+                // Someone is waking us up right now.
+                _ => pause()
+            }
         }
     }
 }
 
 impl<T> Mutex<T> {
-    #[cfg(feature = "unstable")]
     /// Creates a new mutex in an unlocked state ready for use.
     pub const fn new(value: T) -> Mutex<T> {
-        Mutex {
-            queue: AtomicPtr::new(ptr::null_mut()),
-            data: UnsafeCell::new(value)
-        }
+        Mutex::with_relax(value)
     }
+}
 
-    #[cfg(not(feature = "unstable"))]
-    /// Creates a new mutex in an unlocked state ready for use.
-    pub fn new(value: T) -> Mutex<T> {
+impl<T, R> Mutex<T, R> {
+    /// Creates a new mutex in an unlocked state ready for use, which waits using the strategy `R`.
+    ///
+    /// See the `relax` module for the available strategies.
+    pub const fn with_relax(value: T) -> Mutex<T, R> {
         Mutex {
-            queue: AtomicPtr::new(ptr::null_mut()),
+            raw: Raw::UNLOCKED,
+            _relax: PhantomData,
             data: UnsafeCell::new(value)
         }
     }
 
+    /// Attaches `observer` to this mutex, to be told about everything that happens to the lock. This is meant to be
+    /// chained onto a constructor, which still works in a `static`:
+    ///
+    /// ```
+    /// # extern crate mcs;
+    /// use mcs::{LockObserver, Mutex};
+    ///
+    /// struct PrintReleases;
+    ///
+    /// impl LockObserver for PrintReleases {
+    ///     fn on_release(&self, lock: usize) {
+    ///         println!("released {:#x}", lock);
+    ///     }
+    /// }
+    ///
+    /// static LOCK: Mutex<u32> = Mutex::new(0).with_observer(&PrintReleases);
+    /// # fn main() {
+    /// # let mut slot = mcs::Slot::new();
+    /// # *LOCK.lock(&mut slot).unwrap() += 1;
+    /// # }
+    /// ```
+    ///
+    /// An observer set with `set_global_observer` is told about this mutex as well.
+    #[cfg(feature = "observer")]
+    pub const fn with_observer(mut self, observer: &'static dyn LockObserver) -> Mutex<T, R> {
+        self.raw.observers = observer::Observers::new(observer);
+        self
+    }
+
     /// Consumes this mutex, returning the underlying data.
     pub fn into_inner(self) -> T {
-        unsafe {
-            self.data.into_inner()
-        }
+        self.data.into_inner()
+    }
+}
+
+#[cfg(feature = "lock_api")]
+impl Mutex<()> {
+    // Whether any thread holds or is waiting for the lock.
+    pub(crate) fn is_locked(&self) -> bool {
+        !self.raw.queue.load(Ordering::Relaxed).is_null()
     }
 }
 
-impl<T: ?Sized> Mutex<T> {
+impl<T: ?Sized, R: Relax> Mutex<T, R> {
     /// Attempts to acquire this lock.
     ///
     /// If the lock could not be acquired at this time, then `Err` is returned.
@@ -128,16 +385,25 @@ impl<T: ?Sized> Mutex<T> {
     /// guard is dropped.
     ///
     /// This function does not block.
-    pub fn try_lock<'a>(&'a self, slot: &'a mut Slot) -> Result<Guard<'a, T>, ()> {
+    ///
+    /// # Errors
+    ///
+    /// If another user of this mutex panicked while holding the mutex, then
+    /// this call will return the `Poisoned` error if the mutex would
+    /// otherwise be acquired. If the mutex is already locked, the
+    /// `WouldBlock` error is returned.
+    #[cfg_attr(any(feature = "profile", all(feature = "lockdep", debug_assertions)), track_caller)]
+    pub fn try_lock<'a>(&'a self, slot: &'a mut Slot) -> TryLockResult<Guard<'a, T, R>> {
+        let wait = profile::Wait::start();
         slot.next = AtomicPtr::new(ptr::null_mut());
 
-        if self.queue.compare_and_swap(ptr::null_mut(), slot, Ordering::AcqRel).is_null() {
-            Ok(Guard {
-                lock: self,
-                slot: slot
-            })
+        if self.raw.queue.compare_exchange(ptr::null_mut(), slot, Ordering::AcqRel, Ordering::Relaxed).is_ok() {
+            self.raw.acquired(false, 0, wait);
+            self.raw.lockdep.acquired(wait);
+            Ok(self.guard(slot)?)
         } else {
-            Err(())
+            self.raw.stats.failed();
+            Err(TryLockError::WouldBlock)
         }
     }
 
@@ -147,25 +413,134 @@ impl<T: ?Sized> Mutex<T> {
     /// the mutex. Upon returning, the thread is the only thread with the mutex
     /// held. An RAII guard is returned to allow scoped unlock of the lock. When
     /// the guard goes out of scope, the mutex will be unlocked.
-    pub fn lock<'a>(&'a self, slot: &'a mut Slot) -> Guard<'a, T> {
+    ///
+    /// # Errors
+    ///
+    /// If another user of this mutex panicked while holding the mutex, then
+    /// this call will return an error once the mutex is acquired.
+    #[cfg_attr(any(feature = "profile", all(feature = "lockdep", debug_assertions)), track_caller)]
+    pub fn lock<'a>(&'a self, slot: &'a mut Slot) -> LockResult<Guard<'a, T, R>> {
+        self.raw.acquire::<R>(slot, profile::Wait::start());
+        self.guard(slot)
+    }
+
+    /// Acquires a mutex, blocking the current thread until it is able to do so or until `deadline` passes.
+    ///
+    /// This joins the queue just like `lock`, but if `clock` reaches `deadline` before the lock is handed over, the
+    /// thread leaves the queue again and the `WouldBlock` error is returned. Threads queued behind it keep their place in
+    /// line.
+    ///
+    /// The waiting thread keeps checking `clock`, so unlike `lock`, it never parks.
+    #[cfg_attr(any(feature = "profile", all(feature = "lockdep", debug_assertions)), track_caller)]
+    pub fn lock_timeout<'a, C: Clock>(&'a self, slot: &'a mut Slot, clock: &C, deadline: C::Instant) -> TryLockResult<Guard<'a, T, R>> {
+        let wait = profile::Wait::start();
         slot.next = AtomicPtr::new(ptr::null_mut());
-        let pred = self.queue.swap(slot, Ordering::AcqRel);
+        let pred = self.raw.queue.swap(slot, Ordering::AcqRel);
+        let mut spins = 0;
         if !pred.is_null() {
-            let pred = unsafe { &*pred };
-            let locked = AtomicBool::new(true);
-            pred.next.store(&locked as *const _ as *mut _, Ordering::Release);
-            while locked.load(Ordering::Relaxed) {
-                pause();
+            self.raw.enqueued();
+            let waiter = Waiter::new(pred);
+            unsafe { (*pred).next.store(&waiter as *const _ as *mut _, Ordering::Release); }
+            let _watch = self.raw.watchdog.watch_acquire(self.raw.id());
+            let mut relax = R::default();
+            while waiter.state.load(Ordering::Relaxed) == WAITING {
+                if clock.now() >= deadline {
+                    if unsafe { self.raw.abandon(slot, &waiter) } {
+                        self.raw.abandoned(spins);
+                        self.raw.stats.failed();
+                        return Err(TryLockError::WouldBlock);
+                    }
+                    // We were handed the lock before we could leave.
+                    break;
+                }
+                spins += 1;
+                relax.relax();
             }
             fence(Ordering::Acquire);
         }
+        self.raw.acquired(!pred.is_null(), spins, wait);
+        self.raw.lockdep.acquired(wait);
+
+        Ok(self.guard(slot)?)
+    }
+
+    /// Attempts to acquire this lock, waiting at most `timeout` for it to become available.
+    ///
+    /// See `lock_timeout`.
+    #[cfg_attr(any(feature = "profile", all(feature = "lockdep", debug_assertions)), track_caller)]
+    pub fn try_lock_for<'a, C: Clock>(&'a self, slot: &'a mut Slot, clock: &C, timeout: C::Duration) -> TryLockResult<Guard<'a, T, R>> {
+        let deadline = clock.after(timeout);
+        self.lock_timeout(slot, clock, deadline)
+    }
 
-        Guard {
+    /// Acquires a mutex asynchronously.
+    ///
+    /// This returns a future that queues up for the lock when first polled, and resolves to an RAII guard once the lock
+    /// has been handed over, just like `lock`. Instead of spinning, the waiting task is woken up by whoever releases
+    /// the lock before it.
+    ///
+    /// Dropping the future before it completes gives up on acquiring the lock.
+    #[cfg_attr(any(feature = "profile", all(feature = "lockdep", debug_assertions)), track_caller)]
+    pub fn lock_async<'a>(&'a self, slot: &'a mut Slot) -> LockFuture<'a, T, R> {
+        LockFuture {
             lock: self,
-            slot: slot
+            slot,
+            wait: profile::Wait::start(),
+            queued: false,
+            done: false,
+            waiter: Waiter::new(ptr::null_mut()),
+            watch: watchdog::Watch::NONE,
+            _pinned: PhantomPinned
         }
     }
 
+    /// Determines whether the mutex is poisoned.
+    ///
+    /// If another thread is active, the mutex can still become poisoned at any
+    /// time. You should not trust a `false` value for program correctness
+    /// without additional synchronization.
+    pub fn is_poisoned(&self) -> bool {
+        self.raw.poison.get()
+    }
+
+    /// Clears the poisoned state from the mutex.
+    ///
+    /// If the mutex is poisoned, it will remain poisoned until this function is called. This allows recovering from a
+    /// poisoned state and marking that it has recovered.
+    pub fn clear_poison(&self) {
+        self.raw.poison.clear();
+    }
+
+    /// Returns a snapshot of the contention statistics of this mutex.
+    ///
+    /// The counters start at zero when the mutex is created, and keep counting for as long as it exists.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> Stats {
+        self.raw.stats.snapshot()
+    }
+
+    // Wraps up the lock, which was just acquired with `slot`, in a guard.
+    fn guard<'a>(&'a self, slot: &'a Slot) -> LockResult<Guard<'a, T, R>> {
+        poison::map_result(self.raw.poison.borrow(), |poison| {
+            Guard {
+                lock: self,
+                slot,
+                poison
+            }
+        })
+    }
+
+    // Identifies this mutex in traces.
+    #[cfg(all(test, any(feature = "trace", feature = "watchdog")))]
+    pub(crate) fn id(&self) -> usize {
+        self.raw.id()
+    }
+
+    // Releases the lock held with `slot`, handing it to the next waiter if there is one.
+    pub(crate) unsafe fn unlock(&self, slot: &Slot) {
+        self.raw.unlock::<R>(slot);
+    }
+
     /// Returns a mutable reference to the underlying data.
     ///
     /// Since this call borrows the `Mutex` mutably, no actual locking needs to
@@ -175,191 +550,698 @@ impl<T: ?Sized> Mutex<T> {
     }
 }
 
-impl<'a, T: ?Sized> Deref for Guard<'a, T> {
-    type Target = T;
-    fn deref(&self) -> &T {
-        unsafe { &*self.lock.data.get() }
+impl Raw {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const UNLOCKED: Raw = Raw {
+        queue: AtomicPtr::new(ptr::null_mut()),
+        poison: poison::Flag::INIT,
+        stats: stats::Counters::INIT,
+        observers: observer::Observers::INIT,
+        profile: profile::Holder::INIT,
+        lockdep: lockdep::Class::INIT,
+        watchdog: watchdog::Holder::INIT
+    };
+
+    // Identifies this lock to observers.
+    fn id(&self) -> usize {
+        self as *const Raw as usize
     }
-}
 
-impl<'a, T: ?Sized> DerefMut for Guard<'a, T> {
-    fn deref_mut(&mut self) -> &mut T {
-        unsafe { &mut *self.lock.data.get() }
+    // Called when a thread joins the queue behind another one.
+    fn enqueued(&self) {
+        self.stats.enqueued();
+        self.observers.enqueue(self.id());
+        trace::enqueue(self.id());
     }
-}
 
-// Unforturnately, since just putting attributes on generic parameters is unstable, we have to duplicate the whole Drop impl
-#[cfg(feature = "unstable")]
-unsafe impl<'a, #[may_dangle] T: ?Sized> Drop for Guard<'a, T> {
-    fn drop(&mut self) {
-        let mut succ = self.slot.next.load(Ordering::Relaxed);
-        if succ.is_null() {
-            // No one has registered as waiting.
-            if self.lock.queue.compare_exchange(self.slot as *const _ as *mut _, ptr::null_mut(), Ordering::Release, Ordering::Relaxed).is_ok() {
-                // No one was waiting.
-                return;
+    // Called when the lock is acquired by the attempt `wait`. If it was `contended`, the thread had called `enqueued`
+    // first.
+    fn acquired(&self, contended: bool, spins: usize, wait: profile::Wait) {
+        self.stats.acquired(contended, spins);
+        self.observers.acquire(self.id(), contended);
+        unsafe { self.profile.acquired(wait, contended); }
+        self.watchdog.acquired();
+        trace::acquire(self.id());
+    }
+
+    // Called when a thread that called `enqueued` leaves the queue without taking the lock.
+    fn abandoned(&self, spins: usize) {
+        self.stats.abandoned(spins);
+        self.observers.abandon(self.id());
+        trace::abandon(self.id());
+    }
+
+    // Called by the holder when it starts releasing the lock.
+    unsafe fn releasing(&self) {
+        self.observers.release(self.id());
+        self.profile.releasing();
+        self.lockdep.releasing();
+        self.watchdog.releasing();
+        trace::release(self.id());
+    }
+
+    // Called by the holder just before it hands the lock to the next waiter.
+    fn handing_off(&self) {
+        self.observers.handoff(self.id());
+        trace::handoff(self.id());
+    }
+
+    // Queues up for the lock with `slot` and waits for it to be handed over.
+    fn acquire<R: Relax>(&self, slot: &Slot, wait: profile::Wait) {
+        self.lockdep.check(wait);
+        slot.next.store(ptr::null_mut(), Ordering::Relaxed);
+        let pred = self.queue.swap(slot as *const _ as *mut _, Ordering::AcqRel);
+        if pred.is_null() {
+            self.acquired(false, 0, wait);
+        } else {
+            self.enqueued();
+            let waiter = Waiter::new(pred);
+            unsafe { (*pred).next.store(&waiter as *const _ as *mut _, Ordering::Release); }
+            let spins = {
+                let _watch = self.watchdog.watch_acquire(self.id());
+                waiter.wait::<R>()
+            };
+            self.acquired(true, spins, wait);
+        }
+        self.lockdep.acquired(wait);
+    }
+
+    // Removes a queued waiter from the queue. Returns `false` if the lock was handed to the waiter instead.
+    unsafe fn abandon(&self, slot: &Slot, waiter: &Waiter) -> bool {
+        while LEAVE_LOCK.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            if waiter.state.load(Ordering::Relaxed) != WAITING {
+                return false;
             }
+            pause();
+        }
+        let left = self.unlink(slot, waiter);
+        LEAVE_LOCK.store(false, Ordering::Release);
+        left
+    }
 
-            // Some thread is waiting, but hasn't registered yet. Spin waiting for them to register themselves.
-            loop {
-                succ = self.slot.next.load(Ordering::Relaxed);
-                if !succ.is_null() {
-                    break;
-                }
+    // Must be called with `LEAVE_LOCK` held.
+    unsafe fn unlink(&self, slot: &Slot, waiter: &Waiter) -> bool {
+        if waiter.state.compare_exchange(WAITING, LEAVING, Ordering::AcqRel, Ordering::Relaxed).is_err() {
+            return false;
+        }
+
+        // From here on, our predecessor cannot finish handing off the lock without hearing from us, so its slot stays
+        // valid.
+        let pred = &*waiter.prev.load(Ordering::Relaxed);
+        let this = waiter as *const _ as *mut Waiter;
+        if pred.next.compare_exchange(this, ptr::null_mut(), Ordering::AcqRel, Ordering::Relaxed).is_err() {
+            // Our predecessor has already claimed us and is in the middle of handing us the lock. Let it finish, then
+            // tell it that we are done with its slot.
+            while waiter.state.load(Ordering::Relaxed) != GRANTED {
                 pause();
             }
+            pred.next.store(ACKED as *mut Waiter, Ordering::Release);
+            return false;
         }
 
-        // Announce to the next waiter that the lock is free.
-        fence(Ordering::Acquire);
-        let succ = unsafe { &*succ };
-        succ.store(false, Ordering::Release);
+        // Our predecessor no longer knows about us, and it will wait until it is given a new successor or becomes the
+        // tail again. Give it our successor, if we have one.
+        let this_slot = slot as *const _ as *mut Slot;
+        let pred_slot = pred as *const _ as *mut Slot;
+        loop {
+            if self.queue.load(Ordering::Relaxed) == this_slot &&
+               self.queue.compare_exchange(this_slot, pred_slot, Ordering::AcqRel, Ordering::Relaxed).is_ok() {
+                return true;
+            }
+
+            let succ = slot.next.load(Ordering::Acquire);
+            if !succ.is_null() {
+                (*succ).prev.store(pred_slot, Ordering::Relaxed);
+                pred.next.store(succ, Ordering::Release);
+                return true;
+            }
+            pause();
+        }
     }
-}
 
-#[cfg(not(feature = "unstable"))]
-impl<'a, T: ?Sized> Drop for Guard<'a, T> {
-    fn drop(&mut self) {
-        let mut succ = self.slot.next.load(Ordering::Relaxed);
-        if succ.is_null() {
-            // No one has registered as waiting.
-            if self.lock.queue.compare_exchange(self.slot as *const _ as *mut _, ptr::null_mut(), Ordering::Release, Ordering::Relaxed).is_ok() {
-                // No one was waiting.
-                return;
+    // Releases the lock held with `slot`, handing it to the next waiter if there is one.
+    unsafe fn unlock<R: Relax>(&self, slot: &Slot) {
+        self.releasing();
+        let this_slot = slot as *const _ as *mut Slot;
+        let mut relax = R::default();
+        let mut spins = 0;
+        let mut watch = None;
+        loop {
+            let succ = slot.next.load(Ordering::Relaxed);
+            if succ.is_null() {
+                // No one has registered as waiting.
+                if self.queue.load(Ordering::Relaxed) == this_slot &&
+                   self.queue.compare_exchange(this_slot, ptr::null_mut(), Ordering::Release, Ordering::Relaxed).is_ok() {
+                    // No one was waiting.
+                    self.stats.released(spins);
+                    return;
+                }
+
+                // Some thread is waiting, but hasn't registered yet. Spin waiting for them to register themselves. If
+                // the only waiter times out instead, we become the tail again and can retry the above.
+                if watch.is_none() {
+                    watch = Some(self.watchdog.watch_release(self.id()));
+                }
+                spins += 1;
+                relax.relax();
+                continue;
             }
 
-            // Some thread is waiting, but hasn't registered yet. Spin waiting for them to register themselves.
-            loop {
-                succ = self.slot.next.load(Ordering::Relaxed);
-                if !succ.is_null() {
-                    break;
+            // Stop the next waiter from timing out from under us.
+            if slot.next.compare_exchange(succ, CLAIMED as *mut Waiter, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+                // Announce to the next waiter that the lock is free.
+                self.handing_off();
+                if (*succ).grant() == LEAVING {
+                    // It was trying to time out, and might still be looking at our slot.
+                    while slot.next.load(Ordering::Relaxed) as usize != ACKED {
+                        if watch.is_none() {
+                            watch = Some(self.watchdog.watch_release(self.id()));
+                        }
+                        spins += 1;
+                        relax.relax();
+                    }
                 }
-                pause();
+                self.stats.released(spins);
+                return;
             }
         }
-
-        // Announce to the next waiter that the lock is free.
-        fence(Ordering::Acquire);
-        let succ = unsafe { &*succ };
-        succ.store(false, Ordering::Release);
     }
 }
 
-#[cfg(test)]
-mod test {
-    use super::{Mutex, Slot};
-
-    // Mostly stoled from the Rust standard Mutex implementation's tests, so
-
-    // Copyright 2014 The Rust Project Developers. See the COPYRIGHT
-    // file at http://rust-lang.org/COPYRIGHT.
-    //
-    // Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
-    // http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
-    // <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
-    // option. This file may not be copied, modified, or distributed
-    // except according to those terms.
+impl Queue {
+    pub(crate) const fn new() -> Queue {
+        Queue {
+            tail: AtomicPtr::new(ptr::null_mut())
+        }
+    }
 
-    use std::sync::Arc;
-    use std::sync::mpsc::channel;
-    use std::sync::atomic::{AtomicUsize, Ordering};
-    use std::thread;
+    // Takes the lock with `slot` if no one holds it. Returns whether it did.
+    pub(crate) fn try_lock(&self, slot: &Slot) -> bool {
+        slot.next.store(ptr::null_mut(), Ordering::Relaxed);
+        self.tail.compare_exchange(ptr::null_mut(), slot as *const _ as *mut _, Ordering::AcqRel, Ordering::Relaxed).is_ok()
+    }
 
-    #[derive(Eq, PartialEq, Debug)]
-    struct NonCopy(i32);
+    // Queues up for the lock with `slot` and waits for it to be handed over.
+    pub(crate) fn lock<R: Relax>(&self, slot: &Slot) {
+        slot.next.store(ptr::null_mut(), Ordering::Relaxed);
+        let pred = self.tail.swap(slot as *const _ as *mut _, Ordering::AcqRel);
+        if !pred.is_null() {
+            let waiter = Waiter::new(pred);
+            unsafe { (*pred).next.store(&waiter as *const _ as *mut _, Ordering::Release); }
+            waiter.wait::<R>();
+        }
+    }
 
-    #[test]
-    fn smoke() {
-        let mut slot = Slot::new();
-        let m = Mutex::new(());
-        drop(m.lock(&mut slot));
-        drop(m.lock(&mut slot));
+    // Whether another thread has queued up behind the holder of the lock, `slot`.
+    pub(crate) fn has_waiters(&self, slot: &Slot) -> bool {
+        !ptr::eq(self.tail.load(Ordering::Relaxed), slot)
     }
 
-    #[test]
-    fn lots_and_lots() {
-        lazy_static! {
-            static ref LOCK: Mutex<u32> = Mutex::new(0);
+    // Releases the lock held with `slot`, handing it to the next waiter if there is one.
+    pub(crate) unsafe fn unlock<R: Relax>(&self, slot: &Slot) {
+        let this_slot = slot as *const _ as *mut Slot;
+        let mut relax = R::default();
+        loop {
+            let succ = slot.next.load(Ordering::Acquire);
+            if !succ.is_null() {
+                (*succ).grant();
+                return;
+            }
+            if self.tail.load(Ordering::Relaxed) == this_slot &&
+               self.tail.compare_exchange(this_slot, ptr::null_mut(), Ordering::Release, Ordering::Relaxed).is_ok() {
+                return;
+            }
+            // Someone is queued up behind us, but hasn't told us yet.
+            relax.relax();
         }
+    }
+}
 
-        const ITERS: u32 = 1000;
-        const CONCURRENCY: u32 = 3;
+impl<'a, T: ?Sized, R: Relax> Future for LockFuture<'a, T, R> {
+    type Output = LockResult<Guard<'a, T, R>>;
 
-        fn inc() {
-            let mut slot = Slot::new();
-            for _ in 0..ITERS {
-                let mut g = LOCK.lock(&mut slot);
-                *g += 1;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<LockResult<Guard<'a, T, R>>> {
+        // We never move out of `this`, and in particular never move `waiter`.
+        let this = unsafe { self.get_unchecked_mut() };
+        assert!(!this.done, "`LockFuture` polled after completion");
+
+        if !this.queued {
+            this.queued = true;
+            this.slot.next.store(ptr::null_mut(), Ordering::Relaxed);
+            let pred = this.lock.raw.queue.swap(this.slot as *const _ as *mut _, Ordering::AcqRel);
+            if pred.is_null() {
+                this.done = true;
+                this.lock.raw.acquired(false, 0, this.wait);
+                return Poll::Ready(this.lock.guard(this.slot));
             }
-        };
 
-        let (tx, rx) = channel();
-        for _ in 0..CONCURRENCY {
-            let tx2 = tx.clone();
-            thread::spawn(move|| { inc(); tx2.send(()).unwrap(); });
-            let tx2 = tx.clone();
-            thread::spawn(move|| { inc(); tx2.send(()).unwrap(); });
+            this.lock.raw.enqueued();
+            this.watch = this.lock.raw.watchdog.watch_async(this.lock.raw.id());
+            this.waiter.prev.store(pred, Ordering::Relaxed);
+            unsafe { (*pred).next.store(&this.waiter as *const _ as *mut _, Ordering::Release); }
         }
 
-        drop(tx);
-        for _ in 0..2 * CONCURRENCY {
-            rx.recv().unwrap();
+        // Make sure the waker that the lock will be handed over with is the current one.
+        let registered = this.waiter.state.load(Ordering::Relaxed) == REGISTERED &&
+                         unsafe { (*this.waiter.waker.get()).as_ref().is_some_and(|waker| waker.will_wake(cx.waker())) };
+        if !registered && this.waiter.unregister() {
+            unsafe { *this.waiter.waker.get() = Some(cx.waker().clone()); }
+            if this.waiter.state.compare_exchange(WAITING, REGISTERED, Ordering::Release, Ordering::Relaxed).is_ok() {
+                return Poll::Pending;
+            }
         }
-        let mut slot = Slot::new();
-        assert_eq!(*LOCK.lock(&mut slot), ITERS * CONCURRENCY * 2);
-    }
 
-    #[test]
-    fn try_lock() {
-        let mut slot = Slot::new();
-        let m = Mutex::new(());
-        *m.try_lock(&mut slot).unwrap() = ();
-    }
+        // Our waker might still be in the middle of being taken.
+        while this.waiter.state.load(Ordering::Relaxed) != GRANTED {
+            if this.waiter.state.load(Ordering::Relaxed) != WAKING {
+                return Poll::Pending;
+            }
+            pause();
+        }
+        fence(Ordering::Acquire);
 
-    #[test]
-    fn test_into_inner() {
-        let m = Mutex::new(NonCopy(10));
-        assert_eq!(m.into_inner(), NonCopy(10));
+        this.done = true;
+        this.watch = watchdog::Watch::NONE;
+        this.lock.raw.acquired(true, 0, this.wait);
+        Poll::Ready(this.lock.guard(this.slot))
     }
+}
 
-    #[test]
-    fn test_into_inner_drop() {
-        struct Foo(Arc<AtomicUsize>);
-        impl Drop for Foo {
-            fn drop(&mut self) {
-                self.0.fetch_add(1, Ordering::SeqCst);
+impl<'a, T: ?Sized, R: Relax> Drop for LockFuture<'a, T, R> {
+    fn drop(&mut self) {
+        if !self.queued || self.done {
+            return;
+        }
+
+        unsafe {
+            if self.waiter.unregister() && self.lock.raw.abandon(self.slot, &self.waiter) {
+                self.lock.raw.abandoned(0);
+            } else {
+                // We got the lock while trying to give up on it, so pass it along.
+                fence(Ordering::Acquire);
+                self.lock.raw.acquired(true, 0, self.wait);
+                self.lock.unlock(self.slot);
             }
         }
-        let num_drops = Arc::new(AtomicUsize::new(0));
-        let m = Mutex::new(Foo(num_drops.clone()));
-        assert_eq!(num_drops.load(Ordering::SeqCst), 0);
-        {
-            let _inner = m.into_inner();
-            assert_eq!(num_drops.load(Ordering::SeqCst), 0);
+    }
+}
+
+impl<'a, T: ?Sized, R: Relax> Guard<'a, T, R> {
+    /// Temporarily unlocks the mutex to run `f`, then queues up for it again with the same slot.
+    ///
+    /// The lock is handed to the next waiter just as if the guard was dropped, and this thread goes to the back of the
+    /// queue. If `f` panics, the lock is acquired again before the panic continues, so the guard is always valid.
+    ///
+    /// Since the lock is not held while `f` runs, another thread may poison it in the meantime. This is not reported
+    /// here; use `Mutex::is_poisoned` to check.
+    #[cfg_attr(any(feature = "profile", all(feature = "lockdep", debug_assertions)), track_caller)]
+    pub fn unlocked<F: FnOnce() -> U, U>(&mut self, f: F) -> U {
+        let wait = profile::Wait::start();
+        self.lock.raw.poison.done(&self.poison);
+        unsafe { self.lock.raw.unlock::<R>(self.slot); }
+        let _relock = Relock {
+            guard: self,
+            wait
+        };
+        f()
+    }
+
+    /// Lets waiting threads take the lock before continuing.
+    ///
+    /// If another thread has registered itself as the next in line, this hands the lock over and queues up for it
+    /// again, like `unlocked` with an empty function. Otherwise, it returns immediately without releasing the lock.
+    #[cfg_attr(any(feature = "profile", all(feature = "lockdep", debug_assertions)), track_caller)]
+    pub fn bump(&mut self) {
+        if !self.slot.next.load(Ordering::Relaxed).is_null() {
+            self.unlocked(|| ());
         }
-        assert_eq!(num_drops.load(Ordering::SeqCst), 1);
     }
 
-    #[test]
-    fn test_get_mut() {
-        let mut m = Mutex::new(NonCopy(10));
-        *m.get_mut() = NonCopy(20);
-        assert_eq!(m.into_inner(), NonCopy(20));
+    /// Determines whether another thread is waiting for the lock.
+    ///
+    /// This can be used to keep critical sections short under contention, for example by calling `bump` or `unlocked`
+    /// when it returns `true`.
+    pub fn has_waiters(&self) -> bool {
+        !core::ptr::eq(self.lock.raw.queue.load(Ordering::Relaxed), self.slot)
     }
 
-    #[test]
-    fn test_lock_arc_nested() {
-        // Tests nested locks and access
-        // to underlying data.
-        let arc = Arc::new(Mutex::new(1));
+    /// Makes a `MappedGuard` for a part of the locked data, such as one of its fields. The lock stays held until the
+    /// returned guard is dropped.
+    ///
+    /// This is an associated function that needs to be used as `Guard::map(...)`, so that it does not get in the way
+    /// of methods on the data.
+    pub fn map<U: ?Sized, F: FnOnce(&mut T) -> &mut U>(this: Self, f: F) -> MappedGuard<'a, U, R> {
+        let data = f(unsafe { &mut *this.lock.data.get() }) as *mut U;
+        MappedGuard::new(this.into_held(), data)
+    }
+
+    /// Like `map`, but `f` may decline to pick a part of the data, in which case the original guard is given back.
+    ///
+    /// This is an associated function that needs to be used as `Guard::try_map(...)`.
+    pub fn try_map<U: ?Sized, F: FnOnce(&mut T) -> Option<&mut U>>(this: Self, f: F) -> Result<MappedGuard<'a, U, R>, Self> {
+        match f(unsafe { &mut *this.lock.data.get() }) {
+            Some(data) => {
+                let data = data as *mut U;
+                Ok(MappedGuard::new(this.into_held(), data))
+            },
+            None => Err(this)
+        }
+    }
+
+    /// Splits the guard into two `MappedGuard`s for disjoint parts of the locked data. The lock stays held until both
+    /// of them are dropped, in either order.
+    ///
+    /// This is an associated function that needs to be used as `Guard::map_split(...)`.
+    pub fn map_split<U: ?Sized, V: ?Sized, F>(this: Self, f: F) -> (MappedGuard<'a, U, R>, MappedGuard<'a, V, R>)
+        where F: FnOnce(&mut T) -> (&mut U, &mut V) {
+        let (u, v) = f(unsafe { &mut *this.lock.data.get() });
+        let (u, v) = (u as *mut U, v as *mut V);
+        let (held_u, held_v) = this.into_held().split();
+        (MappedGuard::new(held_u, u), MappedGuard::new(held_v, v))
+    }
+
+    // Gives up this guard without releasing the lock.
+    fn into_held(self) -> Held<'a, R> {
+        let this = ManuallyDrop::new(self);
+        Held {
+            raw: &this.lock.raw,
+            slot: this.slot,
+            poison: this.poison,
+            shared: false,
+            _relax: PhantomData
+        }
+    }
+
+    // Checks whether the lock was poisoned while it was released by `unlocked`.
+    pub(crate) fn into_result(self) -> LockResult<Self> {
+        if self.lock.raw.poison.get() {
+            Err(PoisonError::new(self))
+        } else {
+            Ok(self)
+        }
+    }
+}
+
+impl<'a, T: ?Sized, R: Relax> Deref for Guard<'a, T, R> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized, R: Relax> DerefMut for Guard<'a, T, R> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+guard_drop! {
+    impl<'a, T: ?Sized, R: Relax> Drop for Guard<'a, T, R> {
+        fn drop(&mut self) {
+            self.lock.raw.poison.done(&self.poison);
+            unsafe { self.lock.raw.unlock::<R>(self.slot); }
+        }
+    }
+}
+
+impl<'a, U: ?Sized, R: Relax> MappedGuard<'a, U, R> {
+    // `data` must point into the data protected by the lock in `held`.
+    fn new(held: Held<'a, R>, data: *mut U) -> MappedGuard<'a, U, R> {
+        MappedGuard {
+            held,
+            data,
+            _marker: PhantomData
+        }
+    }
+
+    /// Makes a new `MappedGuard` for a part of the data this guard points to, like `Guard::map`.
+    ///
+    /// This is an associated function that needs to be used as `MappedGuard::map(...)`.
+    pub fn map<V: ?Sized, F: FnOnce(&mut U) -> &mut V>(this: Self, f: F) -> MappedGuard<'a, V, R> {
+        let data = f(unsafe { &mut *this.data }) as *mut V;
+        MappedGuard::new(this.held, data)
+    }
+
+    /// Like `map`, but `f` may decline to pick a part of the data, in which case the original guard is given back.
+    ///
+    /// This is an associated function that needs to be used as `MappedGuard::try_map(...)`.
+    pub fn try_map<V: ?Sized, F: FnOnce(&mut U) -> Option<&mut V>>(this: Self, f: F) -> Result<MappedGuard<'a, V, R>, Self> {
+        match f(unsafe { &mut *this.data }) {
+            Some(data) => {
+                let data = data as *mut V;
+                Ok(MappedGuard::new(this.held, data))
+            },
+            None => Err(this)
+        }
+    }
+
+    /// Splits the guard into two guards for disjoint parts of the data, like `Guard::map_split`.
+    ///
+    /// This is an associated function that needs to be used as `MappedGuard::map_split(...)`.
+    pub fn map_split<V: ?Sized, W: ?Sized, F>(this: Self, f: F) -> (MappedGuard<'a, V, R>, MappedGuard<'a, W, R>)
+        where F: FnOnce(&mut U) -> (&mut V, &mut W) {
+        let (v, w) = f(unsafe { &mut *this.data });
+        let (v, w) = (v as *mut V, w as *mut W);
+        let (held_v, held_w) = this.held.split();
+        (MappedGuard::new(held_v, v), MappedGuard::new(held_w, w))
+    }
+}
+
+impl<'a, U: ?Sized, R: Relax> Deref for MappedGuard<'a, U, R> {
+    type Target = U;
+    fn deref(&self) -> &U {
+        unsafe { &*self.data }
+    }
+}
+
+impl<'a, U: ?Sized, R: Relax> DerefMut for MappedGuard<'a, U, R> {
+    fn deref_mut(&mut self) -> &mut U {
+        unsafe { &mut *self.data }
+    }
+}
+
+// Reacquires the lock for `Guard::unlocked`, even if the function it runs panics.
+struct Relock<'b, 'a: 'b, T: ?Sized + 'a, R: Relax + 'a> {
+    guard: &'b mut Guard<'a, T, R>,
+    wait: profile::Wait
+}
+
+impl<'b, 'a, T: ?Sized, R: Relax> Drop for Relock<'b, 'a, T, R> {
+    fn drop(&mut self) {
+        let lock = &self.guard.lock.raw;
+        lock.acquire::<R>(self.guard.slot, self.wait.restart());
+        self.guard.poison = lock.poison.borrow().unwrap_or_else(|poison| poison);
+    }
+}
+
+impl<'a, R: Relax> Held<'a, R> {
+    // Turns this into two parts that share the lock.
+    fn split(self) -> (Held<'a, R>, Held<'a, R>) {
+        if self.shared {
+            self.slot.split.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.slot.split.store(2, Ordering::Relaxed);
+        }
+        let this = ManuallyDrop::new(self);
+        let part = || Held {
+            raw: this.raw,
+            slot: this.slot,
+            poison: this.poison,
+            shared: true,
+            _relax: PhantomData
+        };
+        (part(), part())
+    }
+}
+
+impl<'a, R: Relax> Drop for Held<'a, R> {
+    fn drop(&mut self) {
+        self.raw.poison.done(&self.poison);
+        // The last part to go releases the lock, after everything the other parts did.
+        if self.shared && self.slot.split.fetch_sub(1, Ordering::AcqRel) != 1 {
+            return;
+        }
+        unsafe { self.raw.unlock::<R>(self.slot); }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Guard, MappedGuard, Mutex, Slot};
+    #[cfg(feature = "observer")]
+    use super::Raw;
+    use clock::Clock;
+    #[cfg(feature = "observer")]
+    use observer::{self, LockObserver};
+    #[cfg(all(feature = "stats", not(feature = "std"), not(feature = "futex")))]
+    use park;
+    use relax::{self, Relax};
+    #[cfg(feature = "std")]
+    use poison::{PoisonError, TryLockError};
+
+    // Mostly stoled from the Rust standard Mutex implementation's tests, so
+
+    // Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+    // file at http://rust-lang.org/COPYRIGHT.
+    //
+    // Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+    // http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+    // <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+    // option. This file may not be copied, modified, or distributed
+    // except according to those terms.
+
+    use std::boxed::Box;
+    use std::vec::Vec;
+    use std::future::Future;
+    use std::panic;
+    use std::sync::Arc;
+    use std::sync::mpsc::channel;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread::{self, Thread};
+    use std::time::{Duration, Instant};
+
+    #[derive(Eq, PartialEq, Debug)]
+    struct NonCopy(i32);
+
+    struct TestClock;
+
+    impl Clock for TestClock {
+        type Instant = Instant;
+        type Duration = Duration;
+
+        fn now(&self) -> Instant {
+            Instant::now()
+        }
+
+        fn after(&self, timeout: Duration) -> Instant {
+            Instant::now() + timeout
+        }
+    }
+
+    #[test]
+    fn smoke() {
+        let mut slot = Slot::new();
+        let m = Mutex::new(());
+        drop(m.lock(&mut slot).unwrap());
+        drop(m.lock(&mut slot).unwrap());
+    }
+
+    #[test]
+    fn lots_and_lots() {
+        static LOCK: Mutex<u32> = Mutex::new(0);
+
+        const ITERS: u32 = 1000;
+        const CONCURRENCY: u32 = 3;
+
+        fn inc() {
+            let mut slot = Slot::new();
+            for _ in 0..ITERS {
+                let mut g = LOCK.lock(&mut slot).unwrap();
+                *g += 1;
+            }
+        }
+
+        let (tx, rx) = channel();
+        for _ in 0..CONCURRENCY {
+            let tx2 = tx.clone();
+            thread::spawn(move|| { inc(); tx2.send(()).unwrap(); });
+            let tx2 = tx.clone();
+            thread::spawn(move|| { inc(); tx2.send(()).unwrap(); });
+        }
+
+        drop(tx);
+        for _ in 0..2 * CONCURRENCY {
+            rx.recv().unwrap();
+        }
+        let mut slot = Slot::new();
+        assert_eq!(*LOCK.lock(&mut slot).unwrap(), ITERS * CONCURRENCY * 2);
+    }
+
+    fn lots_and_lots_with<R: Relax + 'static>() {
+        const ITERS: u32 = 500;
+        const CONCURRENCY: u32 = 4;
+
+        let lock = Arc::new(Mutex::<u32, R>::with_relax(0));
+        let threads: Vec<_> = (0..CONCURRENCY).map(|_| {
+            let lock = lock.clone();
+            thread::spawn(move|| {
+                let mut slot = Slot::new();
+                for _ in 0..ITERS {
+                    *lock.lock(&mut slot).unwrap() += 1;
+                }
+            })
+        }).collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let mut slot = Slot::new();
+        assert_eq!(*lock.lock(&mut slot).unwrap(), ITERS * CONCURRENCY);
+    }
+
+    #[test]
+    fn relax_strategies() {
+        lots_and_lots_with::<relax::Spin>();
+        lots_and_lots_with::<relax::Backoff>();
+        lots_and_lots_with::<relax::SpinThenYield>();
+        lots_and_lots_with::<relax::Futex>();
+        lots_and_lots_with::<relax::Futex<relax::Spin>>();
+        #[cfg(feature = "std")]
+        lots_and_lots_with::<relax::Yield>();
+    }
+
+    #[test]
+    fn try_lock() {
+        let mut slot = Slot::new();
+        let m = Mutex::new(());
+        *m.try_lock(&mut slot).unwrap() = ();
+    }
+
+    #[test]
+    fn test_into_inner() {
+        let m = Mutex::new(NonCopy(10));
+        assert_eq!(m.into_inner(), NonCopy(10));
+    }
+
+    #[test]
+    fn test_into_inner_drop() {
+        struct Foo(Arc<AtomicUsize>);
+        impl Drop for Foo {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        let num_drops = Arc::new(AtomicUsize::new(0));
+        let m = Mutex::new(Foo(num_drops.clone()));
+        assert_eq!(num_drops.load(Ordering::SeqCst), 0);
+        {
+            let _inner = m.into_inner();
+            assert_eq!(num_drops.load(Ordering::SeqCst), 0);
+        }
+        assert_eq!(num_drops.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_get_mut() {
+        let mut m = Mutex::new(NonCopy(10));
+        *m.get_mut() = NonCopy(20);
+        assert_eq!(m.into_inner(), NonCopy(20));
+    }
+
+    #[test]
+    fn test_lock_arc_nested() {
+        // Tests nested locks and access
+        // to underlying data.
+        let arc = Arc::new(Mutex::new(1));
         let arc2 = Arc::new(Mutex::new(arc));
         let (tx, rx) = channel();
         let _t = thread::spawn(move|| {
             let mut slot1 = Slot::new();
             let mut slot2 = Slot::new();
 
-            let lock = arc2.lock(&mut slot1);
-            let lock2 = lock.lock(&mut slot2);
+            let lock = arc2.lock(&mut slot1).unwrap();
+            let lock2 = lock.lock(&mut slot2).unwrap();
             assert_eq!(*lock2, 1);
             tx.send(()).unwrap();
         });
@@ -377,27 +1259,635 @@ mod test {
             impl Drop for Unwinder {
                 fn drop(&mut self) {
                     let mut slot = Slot::new();
-                    *self.i.lock(&mut slot) += 1;
+                    *self.i.lock(&mut slot).unwrap() += 1;
                 }
             }
             let _u = Unwinder { i: arc2 };
             panic!();
         }).join();
         let mut slot = Slot::new();
-        let lock = arc.lock(&mut slot);
+        let lock = arc.lock(&mut slot).unwrap();
         assert_eq!(*lock, 2);
     }
 
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_arc_poison() {
+        let arc = Arc::new(Mutex::new(1));
+        assert!(!arc.is_poisoned());
+        let arc2 = arc.clone();
+        let _ = thread::spawn(move|| {
+            let mut slot = Slot::new();
+            let lock = arc2.lock(&mut slot).unwrap();
+            assert_eq!(*lock, 2);
+        }).join();
+
+        let mut slot = Slot::new();
+        assert!(arc.lock(&mut slot).is_err());
+        assert!(arc.is_poisoned());
+        match arc.try_lock(&mut slot) {
+            Err(TryLockError::Poisoned(err)) => assert_eq!(*err.into_inner(), 1),
+            _ => panic!("expected a poisoned lock")
+        };
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_clear_poison() {
+        let arc = Arc::new(Mutex::new(1));
+        let arc2 = arc.clone();
+        let _ = thread::spawn(move|| {
+            let mut slot = Slot::new();
+            let mut lock = arc2.lock(&mut slot).unwrap();
+            *lock = 2;
+            panic!();
+        }).join();
+
+        let mut slot = Slot::new();
+        assert!(arc.is_poisoned());
+        {
+            let mut lock = arc.lock(&mut slot).unwrap_or_else(PoisonError::into_inner);
+            assert_eq!(*lock, 2);
+            *lock = 3;
+        }
+        arc.clear_poison();
+        assert!(!arc.is_poisoned());
+        assert_eq!(*arc.lock(&mut slot).unwrap(), 3);
+    }
+
     #[test]
     fn test_lock_unsized() {
         let mut slot = Slot::new();
         let lock: &Mutex<[i32]> = &Mutex::new([1, 2, 3]);
         {
-            let b = &mut *lock.lock(&mut slot);
+            let b = &mut *lock.lock(&mut slot).unwrap();
             b[0] = 4;
             b[2] = 5;
         }
         let comp: &[i32] = &[4, 2, 5];
-        assert_eq!(&*lock.lock(&mut slot), comp);
+        assert_eq!(&*lock.lock(&mut slot).unwrap(), comp);
+    }
+
+    #[test]
+    fn test_map() {
+        let m = Arc::new(Mutex::new((1, NonCopy(2))));
+        let m2 = m.clone();
+        let (tx, rx) = channel();
+
+        let mut slot = Slot::new();
+        let mut g = Guard::map(m.lock(&mut slot).unwrap(), |pair| &mut pair.1);
+        let t = thread::spawn(move|| {
+            let mut slot = Slot::new();
+            tx.send(()).unwrap();
+            let g = m2.lock(&mut slot).unwrap();
+            assert_eq!(*g, (1, NonCopy(3)));
+        });
+        rx.recv().unwrap();
+        thread::sleep(Duration::from_millis(10));
+        *g = NonCopy(3);
+        drop(g);
+
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn test_try_map() {
+        let m = Mutex::new(Some(1));
+        let mut slot = Slot::new();
+        let mut slot2 = Slot::new();
+        let g = m.lock(&mut slot).unwrap();
+        let g = match Guard::try_map(g, |_| None::<&mut i32>) {
+            Ok(_) => panic!(),
+            Err(g) => g
+        };
+        assert!(m.try_lock(&mut slot2).is_err());
+        let mut g = Guard::try_map(g, |opt| opt.as_mut()).unwrap_or_else(|_| panic!());
+        *g += 1;
+        drop(g);
+        assert_eq!(*m.try_lock(&mut slot2).unwrap(), Some(2));
+    }
+
+    #[test]
+    fn test_map_split() {
+        let m = Mutex::new((1, (2, 3)));
+        let mut slot = Slot::new();
+        let mut slot2 = Slot::new();
+
+        let (mut a, b) = Guard::map_split(m.lock(&mut slot).unwrap(), |t| (&mut t.0, &mut t.1));
+        *a += 10;
+        drop(a);
+        assert!(m.try_lock(&mut slot2).is_err());
+        drop(b);
+        assert_eq!(*m.try_lock(&mut slot2).unwrap(), (11, (2, 3)));
+
+        // Split one half again, and drop the parts in the other order.
+        let (a, b) = Guard::map_split(m.lock(&mut slot).unwrap(), |t| (&mut t.0, &mut t.1));
+        let (mut c, d) = MappedGuard::map_split(b, |pair| (&mut pair.0, &mut pair.1));
+        *c += 20;
+        drop(c);
+        drop(a);
+        assert!(m.try_lock(&mut slot2).is_err());
+        drop(d);
+        assert_eq!(*m.try_lock(&mut slot2).unwrap(), (11, (22, 3)));
+    }
+
+    #[test]
+    fn test_map_split_contended() {
+        let m = Arc::new(Mutex::new((0, 0)));
+        let m2 = m.clone();
+        let (tx, rx) = channel();
+
+        let mut slot = Slot::new();
+        let (mut a, mut b) = Guard::map_split(m.lock(&mut slot).unwrap(), |pair| (&mut pair.0, &mut pair.1));
+        let t = thread::spawn(move|| {
+            let mut slot = Slot::new();
+            tx.send(()).unwrap();
+            let g = m2.lock(&mut slot).unwrap();
+            assert_eq!(*g, (1, 2));
+        });
+        rx.recv().unwrap();
+        thread::sleep(Duration::from_millis(10));
+        *b = 2;
+        drop(b);
+        thread::sleep(Duration::from_millis(10));
+        *a = 1;
+        drop(a);
+
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn test_map_unsized() {
+        let mut slot = Slot::new();
+        let lock: &Mutex<[i32]> = &Mutex::new([1, 2, 3]);
+        {
+            let (mut front, back) = Guard::map_split(lock.lock(&mut slot).unwrap(), |s| s.split_at_mut(1));
+            front[0] = 4;
+            let mut last = MappedGuard::map(back, |s| &mut s[1]);
+            *last = 5;
+        }
+        let comp: &[i32] = &[4, 2, 5];
+        assert_eq!(&*lock.lock(&mut slot).unwrap(), comp);
+    }
+
+    #[test]
+    fn test_unlocked() {
+        let m = Arc::new(Mutex::new(0));
+        let m2 = m.clone();
+        let (tx, rx) = channel();
+
+        let mut slot = Slot::new();
+        let mut g = m.lock(&mut slot).unwrap();
+        let t = thread::spawn(move|| {
+            let mut slot = Slot::new();
+            tx.send(()).unwrap();
+            *m2.lock(&mut slot).unwrap() += 1;
+        });
+        rx.recv().unwrap();
+        while !g.has_waiters() {
+            thread::yield_now();
+        }
+        g.unlocked(|| t.join().unwrap());
+        assert!(!g.has_waiters());
+        assert_eq!(*g, 1);
+    }
+
+    #[test]
+    fn test_unlocked_panic() {
+        let m = Mutex::new(0);
+        let mut slot = Slot::new();
+        let mut slot2 = Slot::new();
+        let mut g = m.lock(&mut slot).unwrap();
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            g.unlocked(|| panic!());
+        }));
+        assert!(result.is_err());
+        // The guard holds the lock again, and the panic happened while it was released.
+        assert!(m.try_lock(&mut slot2).is_err());
+        *g += 1;
+        drop(g);
+        assert!(!m.is_poisoned());
+        assert_eq!(*m.try_lock(&mut slot2).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_bump() {
+        let m = Arc::new(Mutex::new(0));
+        let m2 = m.clone();
+        let (tx, rx) = channel();
+
+        let mut slot = Slot::new();
+        let mut slot2 = Slot::new();
+        let mut g = m.lock(&mut slot).unwrap();
+        // With no one waiting, this keeps the lock.
+        g.bump();
+        assert!(m.try_lock(&mut slot2).is_err());
+
+        let t = thread::spawn(move|| {
+            let mut slot = Slot::new();
+            tx.send(()).unwrap();
+            *m2.lock(&mut slot).unwrap() += 1;
+        });
+        rx.recv().unwrap();
+        while *g == 0 {
+            g.bump();
+            thread::yield_now();
+        }
+        t.join().unwrap();
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn test_stats() {
+        let m = Mutex::new(());
+        let mut slot = Slot::new();
+        let mut slot2 = Slot::new();
+        let g = m.lock(&mut slot).unwrap();
+        assert!(m.try_lock(&mut slot2).is_err());
+        assert!(m.try_lock_for(&mut slot2, &TestClock, Duration::from_millis(1)).is_err());
+        drop(g);
+        drop(m.try_lock(&mut slot).unwrap());
+
+        let stats = m.stats();
+        assert_eq!(stats.acquisitions, 2);
+        assert_eq!(stats.uncontended, 2);
+        assert_eq!(stats.contended, 0);
+        assert_eq!(stats.try_lock_failures, 2);
+        assert_eq!(stats.max_queue_depth, 1);
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn test_stats_contended() {
+        let m = Arc::new(Mutex::new(()));
+        let mut slot = Slot::new();
+        let g = m.lock(&mut slot).unwrap();
+        let threads: Vec<_> = (0..2).map(|_| {
+            let m = m.clone();
+            thread::spawn(move|| {
+                let mut slot = Slot::new();
+                drop(m.lock(&mut slot).unwrap());
+            })
+        }).collect();
+        while m.stats().max_queue_depth < 2 {
+            thread::yield_now();
+        }
+        drop(g);
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let stats = m.stats();
+        assert_eq!(stats.acquisitions, 3);
+        assert_eq!(stats.uncontended, 1);
+        assert_eq!(stats.contended, 2);
+        assert_eq!(stats.max_queue_depth, 2);
+    }
+
+    // Without anything to park on, a long wait keeps spinning, and all of it is counted.
+    #[cfg(all(feature = "stats", not(feature = "std"), not(feature = "futex")))]
+    #[test]
+    fn test_stats_long_wait() {
+        let m = Arc::new(Mutex::<(), relax::Spin>::with_relax(()));
+        let mut slot = Slot::new();
+        let g = m.lock(&mut slot).unwrap();
+        let m2 = m.clone();
+        let thread = thread::spawn(move|| {
+            let mut slot = Slot::new();
+            drop(m2.lock(&mut slot).unwrap());
+        });
+        while !g.has_waiters() {
+            thread::yield_now();
+        }
+        thread::sleep(Duration::from_millis(20));
+        drop(g);
+        thread.join().unwrap();
+
+        assert!(m.stats().wait_spins > park::SPIN_LIMIT as usize);
+    }
+
+    // Counts the events of the lock with id `lock`, or of every lock if it is zero.
+    #[cfg(feature = "observer")]
+    struct Recorder {
+        lock: AtomicUsize,
+        enqueues: AtomicUsize,
+        acquires: AtomicUsize,
+        contended: AtomicUsize,
+        releases: AtomicUsize,
+        handoffs: AtomicUsize,
+        abandons: AtomicUsize
+    }
+
+    #[cfg(feature = "observer")]
+    impl Recorder {
+        const fn new() -> Recorder {
+            Recorder {
+                lock: AtomicUsize::new(0),
+                enqueues: AtomicUsize::new(0),
+                acquires: AtomicUsize::new(0),
+                contended: AtomicUsize::new(0),
+                releases: AtomicUsize::new(0),
+                handoffs: AtomicUsize::new(0),
+                abandons: AtomicUsize::new(0)
+            }
+        }
+
+        fn count(&self, lock: usize, counter: &AtomicUsize) {
+            let watched = self.lock.load(Ordering::SeqCst);
+            if watched == 0 || watched == lock {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        fn counts(&self) -> [usize; 6] {
+            [&self.enqueues, &self.acquires, &self.contended, &self.releases, &self.handoffs, &self.abandons]
+                .map(|counter| counter.load(Ordering::SeqCst))
+        }
+    }
+
+    #[cfg(feature = "observer")]
+    impl LockObserver for Recorder {
+        fn on_enqueue(&self, lock: usize) {
+            self.count(lock, &self.enqueues);
+        }
+
+        fn on_acquire(&self, lock: usize, contended: bool) {
+            self.count(lock, &self.acquires);
+            if contended {
+                self.count(lock, &self.contended);
+            }
+        }
+
+        fn on_release(&self, lock: usize) {
+            self.count(lock, &self.releases);
+        }
+
+        fn on_handoff(&self, lock: usize) {
+            self.count(lock, &self.handoffs);
+        }
+
+        fn on_abandon(&self, lock: usize) {
+            self.count(lock, &self.abandons);
+        }
+    }
+
+    #[cfg(feature = "observer")]
+    #[test]
+    fn test_observer() {
+        static RECORDER: Recorder = Recorder::new();
+        let m = Mutex::new(()).with_observer(&RECORDER);
+        let mut slot1 = Slot::new();
+        let mut slot2 = Slot::new();
+
+        let g = m.lock(&mut slot1).unwrap();
+        assert!(m.try_lock(&mut slot2).is_err());
+        assert!(m.try_lock_for(&mut slot2, &TestClock, Duration::from_millis(1)).is_err());
+        drop(g);
+        drop(m.try_lock(&mut slot2).unwrap());
+
+        // enqueues, acquires, contended, releases, handoffs, abandons
+        assert_eq!(RECORDER.counts(), [1, 2, 0, 2, 0, 1]);
+    }
+
+    #[cfg(feature = "observer")]
+    #[test]
+    fn test_observer_handoff() {
+        static RECORDER: Recorder = Recorder::new();
+        let m = Arc::new(Mutex::new(()).with_observer(&RECORDER));
+        let mut slot = Slot::new();
+        let g = m.lock(&mut slot).unwrap();
+        let m2 = m.clone();
+        let thread = thread::spawn(move|| {
+            let mut slot = Slot::new();
+            drop(m2.lock(&mut slot).unwrap());
+        });
+        while RECORDER.enqueues.load(Ordering::SeqCst) == 0 {
+            thread::yield_now();
+        }
+        drop(g);
+        thread.join().unwrap();
+
+        assert_eq!(RECORDER.counts(), [1, 2, 1, 2, 1, 0]);
+    }
+
+    #[cfg(feature = "observer")]
+    #[test]
+    fn test_global_observer() {
+        static RECORDER: Recorder = Recorder::new();
+        let m = Mutex::new(());
+        // Other tests run at the same time, so only count this mutex.
+        RECORDER.lock.store(&m.raw as *const Raw as usize, Ordering::SeqCst);
+        assert!(observer::set_global_observer(&RECORDER).is_ok());
+        assert!(observer::set_global_observer(&RECORDER).is_err());
+
+        let mut slot = Slot::new();
+        drop(m.lock(&mut slot).unwrap());
+        assert_eq!(RECORDER.counts(), [0, 1, 0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn test_lock_timeout() {
+        let mut slot1 = Slot::new();
+        let mut slot2 = Slot::new();
+        let m = Mutex::new(());
+        let g = m.lock(&mut slot1).unwrap();
+        assert!(m.try_lock_for(&mut slot2, &TestClock, Duration::from_millis(10)).is_err());
+        drop(g);
+        assert!(m.try_lock_for(&mut slot2, &TestClock, Duration::from_millis(10)).is_ok());
+        drop(m.lock(&mut slot1).unwrap());
+    }
+
+    #[test]
+    fn test_lock_timeout_middle_of_queue() {
+        let arc = Arc::new(Mutex::new(0));
+        let mut slot = Slot::new();
+        let g = arc.lock(&mut slot).unwrap();
+
+        // Queue a waiter that will time out behind us and one that won't behind it.
+        let arc2 = arc.clone();
+        let timed = thread::spawn(move|| {
+            let mut slot = Slot::new();
+            let timed_out = arc2.try_lock_for(&mut slot, &TestClock, Duration::from_millis(50)).is_err();
+            timed_out
+        });
+        thread::sleep(Duration::from_millis(10));
+        let arc3 = arc.clone();
+        let patient = thread::spawn(move|| {
+            let mut slot = Slot::new();
+            *arc3.lock(&mut slot).unwrap() += 1;
+        });
+
+        assert!(timed.join().unwrap());
+        drop(g);
+        patient.join().unwrap();
+        assert_eq!(*arc.lock(&mut slot).unwrap(), 1);
+    }
+
+    #[test]
+    fn lots_and_lots_of_timeouts() {
+        static LOCK: Mutex<u32> = Mutex::new(0);
+        static SUCCESSES: AtomicUsize = AtomicUsize::new(0);
+
+        const ITERS: u32 = 100;
+        const CONCURRENCY: u32 = 3;
+
+        fn inc() {
+            let mut slot = Slot::new();
+            for _ in 0..ITERS {
+                let mut g = LOCK.lock(&mut slot).unwrap();
+                *g += 1;
+                // Hold the lock for a while so that the other threads queue up behind us.
+                thread::yield_now();
+            }
+        }
+
+        fn inc_timeout() {
+            let mut slot = Slot::new();
+            for _ in 0..ITERS {
+                if let Ok(mut g) = LOCK.try_lock_for(&mut slot, &TestClock, Duration::from_micros(200)) {
+                    *g += 1;
+                    SUCCESSES.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        }
+
+        let (tx, rx) = channel();
+        for _ in 0..CONCURRENCY {
+            let tx2 = tx.clone();
+            thread::spawn(move|| { inc(); tx2.send(()).unwrap(); });
+            let tx2 = tx.clone();
+            thread::spawn(move|| { inc_timeout(); tx2.send(()).unwrap(); });
+        }
+
+        drop(tx);
+        for _ in 0..2 * CONCURRENCY {
+            rx.recv().unwrap();
+        }
+        let mut slot = Slot::new();
+        assert_eq!(*LOCK.lock(&mut slot).unwrap() as usize, (ITERS * CONCURRENCY) as usize + SUCCESSES.load(Ordering::SeqCst));
+    }
+
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(value) => return value,
+                Poll::Pending => thread::park()
+            }
+        }
+    }
+
+    #[test]
+    fn lock_async() {
+        let mut slot = Slot::new();
+        let m = Mutex::new(1);
+        *block_on(m.lock_async(&mut slot)).unwrap() += 1;
+        assert_eq!(*m.lock(&mut slot).unwrap(), 2);
+    }
+
+    #[test]
+    fn lock_async_contended() {
+        let m = Arc::new(Mutex::new(0));
+        let m2 = m.clone();
+        let (tx, rx) = channel();
+
+        let mut slot = Slot::new();
+        let mut g = m.lock(&mut slot).unwrap();
+        let t = thread::spawn(move|| {
+            let mut slot = Slot::new();
+            tx.send(()).unwrap();
+            let mut g = block_on(m2.lock_async(&mut slot)).unwrap();
+            assert_eq!(*g, 1);
+            *g += 1;
+        });
+        rx.recv().unwrap();
+        thread::sleep(Duration::from_millis(10));
+        *g += 1;
+        drop(g);
+
+        t.join().unwrap();
+        assert_eq!(*m.lock(&mut slot).unwrap(), 2);
+    }
+
+    #[test]
+    fn lock_async_cancel() {
+        let m = Arc::new(Mutex::new(0));
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+
+        let mut slot1 = Slot::new();
+        let mut slot2 = Slot::new();
+        let g = m.lock(&mut slot1).unwrap();
+        {
+            let mut future = Box::pin(m.lock_async(&mut slot2));
+            assert!(future.as_mut().poll(&mut cx).is_pending());
+
+            // Someone queued up behind the future should still get the lock once it is gone.
+            let m2 = m.clone();
+            let t = thread::spawn(move|| {
+                let mut slot = Slot::new();
+                *m2.lock(&mut slot).unwrap() += 1;
+            });
+            thread::sleep(Duration::from_millis(10));
+            drop(future);
+            drop(g);
+            t.join().unwrap();
+        }
+        assert_eq!(*m.lock(&mut slot1).unwrap(), 1);
+
+        // Dropping a future that has been handed the lock but not polled again releases the lock.
+        let g = m.lock(&mut slot1).unwrap();
+        let mut future = Box::pin(m.lock_async(&mut slot2));
+        assert!(future.as_mut().poll(&mut cx).is_pending());
+        drop(g);
+        drop(future);
+        assert!(m.try_lock(&mut slot1).is_ok());
+    }
+
+    #[test]
+    fn lots_and_lots_async() {
+        static LOCK: Mutex<u32> = Mutex::new(0);
+
+        const ITERS: u32 = 1000;
+        const CONCURRENCY: u32 = 3;
+
+        fn inc() {
+            let mut slot = Slot::new();
+            for _ in 0..ITERS {
+                *LOCK.lock(&mut slot).unwrap() += 1;
+            }
+        }
+
+        fn inc_async() {
+            let mut slot = Slot::new();
+            for _ in 0..ITERS {
+                *block_on(LOCK.lock_async(&mut slot)).unwrap() += 1;
+            }
+        }
+
+        let (tx, rx) = channel();
+        for _ in 0..CONCURRENCY {
+            let tx2 = tx.clone();
+            thread::spawn(move|| { inc(); tx2.send(()).unwrap(); });
+            let tx2 = tx.clone();
+            thread::spawn(move|| { inc_async(); tx2.send(()).unwrap(); });
+        }
+
+        drop(tx);
+        for _ in 0..2 * CONCURRENCY {
+            rx.recv().unwrap();
+        }
+        let mut slot = Slot::new();
+        assert_eq!(*LOCK.lock(&mut slot).unwrap(), ITERS * CONCURRENCY * 2);
     }
 }
@@ -0,0 +1,9 @@
+/// A way of telling which NUMA node the current thread is running on, for the NUMA-aware locks.
+///
+/// The answer does not need to be accurate for the locks to be correct, since a thread can move to another node at any
+/// time anyway. A wrong answer only means that the lock is passed between nodes more often than necessary. This also
+/// means that a topology can be simulated for testing by assigning made-up node ids to threads.
+pub trait NodeId {
+    /// Returns the id of the node that the current thread is running on.
+    fn node_id() -> usize;
+}
@@ -0,0 +1,152 @@
+// Hooks for watching what a `Mutex` does. Without the `observer` feature, there is nothing to call, and the hooks take
+// up no space.
+
+#[cfg(feature = "observer")]
+use core::cell::UnsafeCell;
+#[cfg(feature = "observer")]
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Callbacks for the events in the life of a `Mutex`, for metrics and tracing.
+///
+/// An observer is attached to a single mutex with `Mutex::with_observer`, or to all of them with
+/// `set_global_observer`. The callbacks run on the thread that caused the event, at the moment it happens, so timing
+/// them gives wait times (from `on_enqueue` to `on_acquire`) and hold times (from `on_acquire` to `on_release`).
+///
+/// `lock` identifies the mutex by the address of its lock state, which stays the same for as long as the mutex is not
+/// moved. The callbacks run while the lock is being acquired or released, so they must not lock the same mutex.
+///
+/// Every callback does nothing by default.
+#[cfg(feature = "observer")]
+pub trait LockObserver: Sync {
+    /// Called when the current thread finds the lock held and queues up behind the holder.
+    fn on_enqueue(&self, _lock: usize) { }
+
+    /// Called when the current thread has acquired the lock. `contended` is whether it had to queue up first, in
+    /// which case `on_enqueue` was called before.
+    fn on_acquire(&self, _lock: usize, _contended: bool) { }
+
+    /// Called when the current thread starts releasing the lock.
+    fn on_release(&self, _lock: usize) { }
+
+    /// Called after `on_release` if the lock is being handed directly to a queued thread, just before that thread is
+    /// told.
+    fn on_handoff(&self, _lock: usize) { }
+
+    /// Called when a queued thread gives up before getting the lock, because it timed out or its `lock_async` future
+    /// was dropped.
+    fn on_abandon(&self, _lock: usize) { }
+}
+
+// States of the global observer.
+#[cfg(feature = "observer")]
+const UNSET: usize = 0;
+#[cfg(feature = "observer")]
+const SETTING: usize = 1;
+#[cfg(feature = "observer")]
+const SET: usize = 2;
+
+#[cfg(feature = "observer")]
+struct Global {
+    state: AtomicUsize,
+    observer: UnsafeCell<Option<&'static dyn LockObserver>>
+}
+
+// `observer` is written once, before `state` becomes `SET`, and only read after.
+#[cfg(feature = "observer")]
+unsafe impl Sync for Global { }
+
+#[cfg(feature = "observer")]
+static GLOBAL: Global = Global {
+    state: AtomicUsize::new(UNSET),
+    observer: UnsafeCell::new(None)
+};
+
+/// Attaches `observer` to every `Mutex`, in addition to any observer attached to a particular mutex.
+///
+/// This can only be done once. If a global observer was already set, `Err` is returned.
+#[cfg(feature = "observer")]
+pub fn set_global_observer(observer: &'static dyn LockObserver) -> Result<(), ()> {
+    if GLOBAL.state.compare_exchange(UNSET, SETTING, Ordering::Acquire, Ordering::Relaxed).is_err() {
+        return Err(());
+    }
+    unsafe { *GLOBAL.observer.get() = Some(observer); }
+    GLOBAL.state.store(SET, Ordering::Release);
+    Ok(())
+}
+
+#[cfg(feature = "observer")]
+fn global() -> Option<&'static dyn LockObserver> {
+    if GLOBAL.state.load(Ordering::Acquire) == SET {
+        unsafe { *GLOBAL.observer.get() }
+    } else {
+        None
+    }
+}
+
+// The observers of a lock.
+pub struct Observers {
+    #[cfg(feature = "observer")]
+    local: Option<&'static dyn LockObserver>
+}
+
+#[cfg(feature = "observer")]
+impl Observers {
+    pub const INIT: Observers = Observers {
+        local: None
+    };
+
+    pub const fn new(observer: &'static dyn LockObserver) -> Observers {
+        Observers {
+            local: Some(observer)
+        }
+    }
+
+    fn each<F: Fn(&dyn LockObserver)>(&self, f: F) {
+        if let Some(observer) = self.local {
+            f(observer);
+        }
+        if let Some(observer) = global() {
+            f(observer);
+        }
+    }
+
+    pub fn enqueue(&self, lock: usize) {
+        self.each(|observer| observer.on_enqueue(lock));
+    }
+
+    pub fn acquire(&self, lock: usize, contended: bool) {
+        self.each(|observer| observer.on_acquire(lock, contended));
+    }
+
+    pub fn release(&self, lock: usize) {
+        self.each(|observer| observer.on_release(lock));
+    }
+
+    pub fn handoff(&self, lock: usize) {
+        self.each(|observer| observer.on_handoff(lock));
+    }
+
+    pub fn abandon(&self, lock: usize) {
+        self.each(|observer| observer.on_abandon(lock));
+    }
+}
+
+#[cfg(not(feature = "observer"))]
+impl Observers {
+    pub const INIT: Observers = Observers { };
+
+    #[inline(always)]
+    pub fn enqueue(&self, _lock: usize) { }
+
+    #[inline(always)]
+    pub fn acquire(&self, _lock: usize, _contended: bool) { }
+
+    #[inline(always)]
+    pub fn release(&self, _lock: usize) { }
+
+    #[inline(always)]
+    pub fn handoff(&self, _lock: usize) { }
+
+    #[inline(always)]
+    pub fn abandon(&self, _lock: usize) { }
+}
@@ -0,0 +1,156 @@
+// Support for waiters that have been spinning for too long. Depending on what is available, they:
+//
+//  - if their mutex asks for it on Linux, sleep on their state word with futex(2). See `Relax::FUTEX`.
+//  - otherwise with `std`, park their thread until whoever changes their state unparks it.
+//  - otherwise, just keep spinning, as there is nothing to sleep on.
+//
+// Whoever wakes a waiter can't know how it went to sleep, so with `std` the parker records the thread handle only if
+// the waiter parked its thread, and a parked waiter without one is sleeping on a futex.
+
+#[cfg(feature = "std")]
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+#[cfg(feature = "std")]
+use std::thread;
+#[cfg(feature = "std")]
+use std::thread::Thread;
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "riscv64")))]
+use futex;
+use pause::pause;
+
+// How many times a waiter spins before it parks.
+pub const SPIN_LIMIT: u32 = 100;
+
+// Whether waiters sleep on a futex unless their mutex says otherwise.
+pub const FUTEX: bool = cfg!(feature = "futex");
+
+// Whether futexes are available at all.
+const HAS_FUTEX: bool = cfg!(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "riscv64")));
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "riscv64")))]
+fn futex_wait(state: &AtomicU32, parked: u32) {
+    futex::wait(state, parked);
+}
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "riscv64")))]
+fn futex_wake(word: *const AtomicU32) {
+    futex::wake_one(word);
+}
+
+#[cfg(not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "riscv64"))))]
+fn futex_wait(_state: &AtomicU32, _parked: u32) {
+    pause();
+}
+
+#[cfg(not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "riscv64"))))]
+fn futex_wake(_word: *const AtomicU32) { }
+
+// Changes `state` from `waiting` to `parked`, then sleeps on it with futex(2) until it changes. If `state` was not
+// `waiting`, this returns immediately.
+fn futex_park(state: &AtomicU32, waiting: u32, parked: u32) {
+    if state.compare_exchange(waiting, parked, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+        while state.load(Ordering::Relaxed) == parked {
+            futex_wait(state, parked);
+        }
+    }
+}
+
+// The handle that a waiter node uses to park its thread.
+pub struct Parker {
+    #[cfg(feature = "std")]
+    thread: UnsafeCell<Option<Thread>>
+}
+
+#[cfg(feature = "std")]
+impl Parker {
+    pub fn new() -> Parker {
+        Parker {
+            thread: UnsafeCell::new(None)
+        }
+    }
+
+    // Changes `state` from `waiting` to `parked`, then sleeps until someone calls `set`. If `state` was not `waiting`,
+    // this returns immediately. With `futex`, this sleeps on `state` with futex(2) where that is available.
+    pub fn park(&self, state: &AtomicU32, waiting: u32, parked: u32, futex: bool) {
+        if futex && HAS_FUTEX {
+            return futex_park(state, waiting, parked);
+        }
+        unsafe { *self.thread.get() = Some(thread::current()); }
+        if state.compare_exchange(waiting, parked, Ordering::Release, Ordering::Relaxed).is_ok() {
+            while state.load(Ordering::Relaxed) == parked {
+                thread::park();
+            }
+        }
+    }
+
+    // Sets `state` to `value`, returning its old value. If it was `parked`, this wakes up the waiting thread.
+    //
+    // `state` and `self` must belong to the same waiter, which may be gone as soon as `state` changes.
+    pub unsafe fn set(&self, state: &AtomicU32, value: u32, parked: u32) -> u32 {
+        let word = state as *const AtomicU32;
+        // Acquire, so that once we see `parked`, we also see the thread handle that was stored before it.
+        let mut current = state.load(Ordering::Acquire);
+        loop {
+            if current == parked {
+                // The waiter won't go anywhere until we change its state, so we can still take its thread handle.
+                let thread = (*self.thread.get()).take();
+                state.store(value, Ordering::Release);
+                match thread {
+                    Some(thread) => thread.unpark(),
+                    None => futex_wake(word)
+                }
+                return current;
+            }
+
+            match state.compare_exchange_weak(current, value, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return current,
+                Err(actual) => current = actual
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl Parker {
+    pub fn new() -> Parker {
+        Parker { }
+    }
+
+    // Changes `state` from `waiting` to `parked`, then sleeps until someone calls `set`. If `state` was not `waiting`,
+    // this returns immediately. Without `futex`, or where futex(2) is not available, this just spins once.
+    pub fn park(&self, state: &AtomicU32, waiting: u32, parked: u32, futex: bool) {
+        if futex && HAS_FUTEX {
+            futex_park(state, waiting, parked);
+        } else {
+            pause();
+        }
+    }
+
+    // Sets `state` to `value`, returning its old value. If it was `parked`, this wakes up the waiting thread.
+    //
+    // `state` and `self` must belong to the same waiter, which may be gone as soon as `state` changes.
+    pub unsafe fn set(&self, state: &AtomicU32, value: u32, parked: u32) -> u32 {
+        let word = state as *const AtomicU32;
+        let old = state.swap(value, Ordering::AcqRel);
+        if old == parked {
+            futex_wake(word);
+        }
+        old
+    }
+}
+
+// Spins for a bit, then with `std` starts yielding to other threads. For waits that should be short, but might not be
+// if a thread gets descheduled at the wrong moment.
+pub fn snooze(spins: &mut u32) {
+    if *spins < SPIN_LIMIT {
+        *spins += 1;
+        pause();
+    } else {
+        #[cfg(feature = "std")]
+        thread::yield_now();
+        #[cfg(not(feature = "std"))]
+        pause();
+    }
+}
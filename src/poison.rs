@@ -0,0 +1,179 @@
+// Lock poisoning, as in `std::sync`. The error types are always available, so that code using them works the same
+// with or without `std`, but without `std` there is no way to tell whether a thread is panicking, so nothing is ever
+// poisoned.
+
+use core::fmt;
+#[cfg(feature = "std")]
+use core::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(feature = "std")]
+use std::error::Error;
+#[cfg(feature = "std")]
+use std::thread;
+
+/// A type of error which can be returned whenever a lock is acquired.
+///
+/// A `Mutex` is poisoned whenever a thread panics while holding the lock. Once a mutex is poisoned, all other threads
+/// are unable to access the data by default, as it is likely tainted (some invariant is not being upheld).
+///
+/// The guard is still available through `into_inner`, `get_ref`, and `get_mut`, for threads that want to access the
+/// data despite the lock being poisoned.
+pub struct PoisonError<T> {
+    guard: T
+}
+
+/// An enumeration of possible errors which can occur while calling the `try_lock` family of methods.
+pub enum TryLockError<T> {
+    /// The lock could not be acquired because another thread panicked while holding it.
+    Poisoned(PoisonError<T>),
+    /// The lock could not be acquired at this time, because the operation would otherwise block (or, for the timed
+    /// methods, because the timeout passed).
+    WouldBlock
+}
+
+/// A type alias for the result of a lock method which can be poisoned.
+pub type LockResult<Guard> = Result<Guard, PoisonError<Guard>>;
+
+/// A type alias for the result of a nonblocking locking method.
+pub type TryLockResult<Guard> = Result<Guard, TryLockError<Guard>>;
+
+impl<T> PoisonError<T> {
+    /// Creates a `PoisonError`.
+    pub fn new(guard: T) -> PoisonError<T> {
+        PoisonError {
+            guard
+        }
+    }
+
+    /// Consumes this error, returning the underlying guard to allow access regardless.
+    pub fn into_inner(self) -> T {
+        self.guard
+    }
+
+    /// Reaches into this error, returning a reference to the underlying guard.
+    pub fn get_ref(&self) -> &T {
+        &self.guard
+    }
+
+    /// Reaches into this error, returning a mutable reference to the underlying guard.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> fmt::Debug for PoisonError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PoisonError").finish()
+    }
+}
+
+impl<T> fmt::Display for PoisonError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        "poisoned lock: another task failed inside".fmt(f)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T> Error for PoisonError<T> { }
+
+impl<T> From<PoisonError<T>> for TryLockError<T> {
+    fn from(err: PoisonError<T>) -> TryLockError<T> {
+        TryLockError::Poisoned(err)
+    }
+}
+
+impl<T> fmt::Debug for TryLockError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TryLockError::Poisoned(..) => "Poisoned(..)".fmt(f),
+            TryLockError::WouldBlock => "WouldBlock".fmt(f)
+        }
+    }
+}
+
+impl<T> fmt::Display for TryLockError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TryLockError::Poisoned(..) => "poisoned lock: another task failed inside",
+            TryLockError::WouldBlock => "try_lock failed because the operation would block"
+        }.fmt(f)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T> Error for TryLockError<T> { }
+
+// The poison flag of a lock.
+pub struct Flag {
+    #[cfg(feature = "std")]
+    failed: AtomicBool
+}
+
+// Whether the thread holding a lock was already panicking when it took the lock. Dropping a guard while unwinding only
+// poisons the lock if the panic started while the lock was held.
+#[derive(Clone, Copy)]
+pub struct Panicking {
+    #[cfg(feature = "std")]
+    panicking: bool
+}
+
+#[cfg(feature = "std")]
+impl Flag {
+    // An unpoisoned flag. This is a constant instead of a function so that it can be used in `const fn`s.
+    #[allow(clippy::declare_interior_mutable_const)]
+    pub const INIT: Flag = Flag {
+        failed: AtomicBool::new(false)
+    };
+
+    // Called once the lock has been acquired.
+    pub fn borrow(&self) -> Result<Panicking, Panicking> {
+        let panicking = Panicking {
+            panicking: thread::panicking()
+        };
+        if self.get() {
+            Err(panicking)
+        } else {
+            Ok(panicking)
+        }
+    }
+
+    // Called just before the lock is released.
+    pub fn done(&self, panicking: &Panicking) {
+        if !panicking.panicking && thread::panicking() {
+            self.failed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub fn get(&self) -> bool {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    pub fn clear(&self) {
+        self.failed.store(false, Ordering::Relaxed);
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl Flag {
+    pub const INIT: Flag = Flag { };
+
+    pub fn borrow(&self) -> Result<Panicking, Panicking> {
+        Ok(Panicking { })
+    }
+
+    pub fn done(&self, _panicking: &Panicking) { }
+
+    pub fn get(&self) -> bool {
+        false
+    }
+
+    pub fn clear(&self) { }
+}
+
+// Turns the result of `Flag::borrow` into the result of a locking method.
+pub fn map_result<T, U, F: FnOnce(T) -> U>(result: Result<T, T>, f: F) -> LockResult<U> {
+    match result {
+        Ok(t) => Ok(f(t)),
+        Err(t) => Err(PoisonError::new(f(t)))
+    }
+}
@@ -0,0 +1,308 @@
+//! A profiler that finds the places in a program that wait for `Mutex`es, enabled by the `profile` feature.
+//!
+//! Every method of `Mutex` that acquires the lock is `#[track_caller]`, so each acquisition is attributed to the source
+//! location it was made from. For each location, the profiler adds up how long threads waited there for the lock, and
+//! how long they then held it. The totals are kept in a single global table for the whole program, which can be read
+//! with `sites`, printed with `report`, or turned into input for flame graph tools with `folded`.
+//!
+//! The reacquisition in `Guard::unlocked` (and so in `Condvar::wait`) is attributed to the place that called it.
+//!
+//! Recording takes a global lock twice for every acquisition, so this is meant for finding contention, not for leaving
+//! on in production.
+
+#[cfg(feature = "profile")]
+use core::cell::UnsafeCell;
+#[cfg(feature = "profile")]
+use core::fmt::Write;
+#[cfg(any(feature = "profile", all(feature = "lockdep", debug_assertions)))]
+use core::panic::Location;
+#[cfg(feature = "profile")]
+use core::time::Duration;
+
+#[cfg(feature = "profile")]
+use std::collections::BTreeMap;
+#[cfg(feature = "profile")]
+use std::string::{String, ToString};
+#[cfg(feature = "profile")]
+use std::sync;
+#[cfg(feature = "profile")]
+use std::time::Instant;
+#[cfg(feature = "profile")]
+use std::vec::Vec;
+
+/// The totals for one source location that acquires a lock.
+#[cfg(feature = "profile")]
+#[derive(Clone, Copy, Debug)]
+pub struct Site {
+    /// Where the lock was acquired.
+    pub location: &'static Location<'static>,
+    /// The number of times the lock was acquired here.
+    pub acquisitions: u64,
+    /// The number of those acquisitions that had to queue up behind another thread.
+    pub contended: u64,
+    /// The total time spent waiting for the lock here.
+    pub total_wait: Duration,
+    /// The longest single wait.
+    pub max_wait: Duration,
+    /// The total time the lock was held after being acquired here.
+    pub total_hold: Duration,
+    /// The longest single hold.
+    pub max_hold: Duration
+}
+
+#[cfg(feature = "profile")]
+impl Site {
+    fn new(location: &'static Location<'static>) -> Site {
+        Site {
+            location,
+            acquisitions: 0,
+            contended: 0,
+            total_wait: Duration::ZERO,
+            max_wait: Duration::ZERO,
+            total_hold: Duration::ZERO,
+            max_hold: Duration::ZERO
+        }
+    }
+}
+
+#[cfg(feature = "profile")]
+static SITES: sync::Mutex<BTreeMap<&'static Location<'static>, Site>> = sync::Mutex::new(BTreeMap::new());
+
+#[cfg(feature = "profile")]
+fn record<F: FnOnce(&mut Site)>(location: &'static Location<'static>, f: F) {
+    let mut sites = SITES.lock().unwrap_or_else(sync::PoisonError::into_inner);
+    f(sites.entry(location).or_insert_with(|| Site::new(location)));
+}
+
+/// Returns the totals for every location that has acquired a lock, the ones that waited the longest first.
+#[cfg(feature = "profile")]
+pub fn sites() -> Vec<Site> {
+    let mut sites: Vec<Site> = SITES.lock().unwrap_or_else(sync::PoisonError::into_inner).values().cloned().collect();
+    sites.sort_by(|a, b| b.total_wait.cmp(&a.total_wait).then(b.total_hold.cmp(&a.total_hold)));
+    sites
+}
+
+/// Forgets everything recorded so far.
+#[cfg(feature = "profile")]
+pub fn reset() {
+    SITES.lock().unwrap_or_else(sync::PoisonError::into_inner).clear();
+}
+
+/// Formats `sites` as a table, with one line per location and times in microseconds.
+///
+/// ```
+/// # extern crate mcs;
+/// use mcs::{Mutex, Slot};
+///
+/// # fn main() {
+/// let lock = Mutex::new(0);
+/// let mut slot = Slot::new();
+/// *lock.lock(&mut slot).unwrap() += 1;
+///
+/// print!("{}", mcs::profile::report());
+/// # }
+/// ```
+#[cfg(feature = "profile")]
+pub fn report() -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "{:>12} {:>12} {:>12} {:>12} {:>10} {:>10}  location",
+                     "wait_us", "max_wait_us", "hold_us", "max_hold_us", "acquired", "contended");
+    for site in sites() {
+        let _ = writeln!(out, "{:>12} {:>12} {:>12} {:>12} {:>10} {:>10}  {}",
+                         site.total_wait.as_micros(), site.max_wait.as_micros(),
+                         site.total_hold.as_micros(), site.max_hold.as_micros(),
+                         site.acquisitions, site.contended, site.location);
+    }
+    out
+}
+
+/// Formats `sites` in the folded stack format read by flame graph tools such as `flamegraph.pl` and `inferno`.
+///
+/// Each location gets a `wait;<location>` line with its total wait time and a `hold;<location>` line with its total
+/// hold time, both in nanoseconds, so the two show up as separate towers in the graph.
+#[cfg(feature = "profile")]
+pub fn folded() -> String {
+    let mut out = String::new();
+    for site in sites() {
+        // Spaces would end the stack early.
+        let location = site.location.to_string().replace(' ', "_");
+        if site.total_wait > Duration::ZERO {
+            let _ = writeln!(out, "wait;{} {}", location, site.total_wait.as_nanos());
+        }
+        if site.total_hold > Duration::ZERO {
+            let _ = writeln!(out, "hold;{} {}", location, site.total_hold.as_nanos());
+        }
+    }
+    out
+}
+
+// An attempt to acquire a lock: where it was made from, and when it started waiting. The location is also used by
+// `lockdep`.
+#[derive(Clone, Copy)]
+pub(crate) struct Wait {
+    #[cfg(any(feature = "profile", all(feature = "lockdep", debug_assertions)))]
+    location: &'static Location<'static>,
+    #[cfg(feature = "profile")]
+    start: Instant
+}
+
+impl Wait {
+    #[cfg_attr(any(feature = "profile", all(feature = "lockdep", debug_assertions)), track_caller)]
+    #[inline(always)]
+    pub(crate) fn start() -> Wait {
+        Wait {
+            #[cfg(any(feature = "profile", all(feature = "lockdep", debug_assertions)))]
+            location: Location::caller(),
+            #[cfg(feature = "profile")]
+            start: Instant::now()
+        }
+    }
+
+    // The same attempt, but only starting to wait now.
+    #[cfg(feature = "profile")]
+    pub(crate) fn restart(self) -> Wait {
+        Wait {
+            start: Instant::now(),
+            ..self
+        }
+    }
+
+    #[cfg(not(feature = "profile"))]
+    #[inline(always)]
+    pub(crate) fn restart(self) -> Wait {
+        self
+    }
+
+    #[cfg(any(feature = "profile", all(feature = "lockdep", debug_assertions)))]
+    pub(crate) fn location(&self) -> &'static Location<'static> {
+        self.location
+    }
+}
+
+// Where and when the current holder of a lock acquired it.
+pub(crate) struct Holder {
+    #[cfg(feature = "profile")]
+    held: UnsafeCell<Option<(&'static Location<'static>, Instant)>>
+}
+
+// `held` is only touched by the thread holding the lock.
+#[cfg(feature = "profile")]
+unsafe impl Sync for Holder { }
+
+#[cfg(feature = "profile")]
+impl Holder {
+    #[allow(clippy::declare_interior_mutable_const)]
+    pub(crate) const INIT: Holder = Holder {
+        held: UnsafeCell::new(None)
+    };
+
+    // Must be called by the thread that just acquired the lock with `wait`.
+    pub(crate) unsafe fn acquired(&self, wait: Wait, contended: bool) {
+        let now = Instant::now();
+        let waited = now.saturating_duration_since(wait.start);
+        record(wait.location(), |site| {
+            site.acquisitions += 1;
+            if contended {
+                site.contended += 1;
+            }
+            site.total_wait += waited;
+            site.max_wait = site.max_wait.max(waited);
+        });
+        *self.held.get() = Some((wait.location(), now));
+    }
+
+    // Must be called by the thread holding the lock, before it releases it.
+    pub(crate) unsafe fn releasing(&self) {
+        if let Some((location, since)) = (*self.held.get()).take() {
+            let held = since.elapsed();
+            record(location, |site| {
+                site.total_hold += held;
+                site.max_hold = site.max_hold.max(held);
+            });
+        }
+    }
+}
+
+#[cfg(not(feature = "profile"))]
+impl Holder {
+    pub(crate) const INIT: Holder = Holder { };
+
+    #[inline(always)]
+    pub(crate) unsafe fn acquired(&self, _wait: Wait, _contended: bool) { }
+
+    #[inline(always)]
+    pub(crate) unsafe fn releasing(&self) { }
+}
+
+#[cfg(all(test, feature = "profile"))]
+mod test {
+    use super::{folded, report, sites};
+    use mutex::{Mutex, Slot};
+
+    use core::time::Duration;
+    use std::string::ToString;
+    use std::sync::Arc;
+    use std::vec::Vec;
+    use std::thread;
+
+    // The profile is global, so each test only looks at locations in its own body.
+    fn in_lines(lines: (u32, u32)) -> impl Fn(&&super::Site) -> bool {
+        move |site| site.location.file() == file!() && site.location.line() >= lines.0 && site.location.line() <= lines.1
+    }
+
+    #[test]
+    fn attributes_call_sites() {
+        let start = line!();
+        let m = Mutex::new(0);
+        let mut slot = Slot::new();
+        for _ in 0..3 {
+            *m.lock(&mut slot).unwrap() += 1;
+        }
+        drop(m.try_lock(&mut slot).unwrap());
+        let end = line!();
+
+        let sites = sites();
+        let mine: Vec<_> = sites.iter().filter(in_lines((start, end))).collect();
+        assert_eq!(mine.len(), 2);
+        assert_eq!(mine.iter().map(|site| site.acquisitions).sum::<u64>(), 4);
+        assert!(mine.iter().all(|site| site.contended == 0));
+        let lock_site = mine.iter().find(|site| site.acquisitions == 3).unwrap();
+        assert_eq!(lock_site.location.line(), start + 4);
+
+        let location = lock_site.location.to_string();
+        assert!(report().contains(&location));
+        assert!(folded().lines().any(|line| line.starts_with("hold;") && line.contains(&location)));
+    }
+
+    #[test]
+    fn measures_contention() {
+        let start = line!();
+        let m = Arc::new(Mutex::new(()));
+        let mut slot = Slot::new();
+        let mut g = m.lock(&mut slot).unwrap();
+        let m2 = m.clone();
+        let thread = thread::spawn(move|| {
+            let mut slot = Slot::new();
+            drop(m2.lock(&mut slot).unwrap());
+        });
+        while !g.has_waiters() {
+            thread::yield_now();
+        }
+        g.unlocked(|| thread.join().unwrap());
+        drop(g);
+        let end = line!();
+
+        let sites = sites();
+        let mine: Vec<_> = sites.iter().filter(in_lines((start, end))).collect();
+        let waiter = mine.iter().find(|site| site.location.line() == start + 7).unwrap();
+        assert_eq!(waiter.acquisitions, 1);
+        assert_eq!(waiter.contended, 1);
+        assert!(waiter.total_wait > Duration::ZERO);
+        let holder = mine.iter().find(|site| site.location.line() == start + 3).unwrap();
+        assert_eq!(holder.acquisitions, 1);
+        assert_eq!(holder.contended, 0);
+        assert!(holder.total_hold > Duration::ZERO);
+        let relock = mine.iter().find(|site| site.location.line() == start + 12).unwrap();
+        assert_eq!(relock.acquisitions, 1);
+    }
+}
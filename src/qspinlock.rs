@@ -0,0 +1,518 @@
+//! A compact queued spinlock, modeled on Linux's qspinlock.
+//!
+//! The whole lock is one 32-bit word, laid out as
+//!
+//! ```text
+//!  31                16 15      9    8    7        0
+//! +--------------------+---------+-----+----------+
+//! |        tail        | (unused)| pnd |  locked  |
+//! +--------------------+---------+-----+----------+
+//! ```
+//!
+//! An uncontended lock and unlock are a single compare-and-swap and a single subtraction. The first thread to find the
+//! lock held sets the pending bit and spins on the lock word itself, since it will likely get the lock soon. Anyone else
+//! queues up MCS-style, but instead of a caller-supplied slot, the queue nodes come from a table of nodes owned by each
+//! thread, and the tail of the queue is stored as an index into those tables. That is what lets the tail fit in 16 bits.
+//!
+//! Each thread has `MAX_CONTEXTS` nodes, one for each lock it can be queued for at once, which only matters if a thread
+//! waits for a lock while already waiting for another one (for example, from a signal handler). As in Linux, a thread
+//! that runs out of nodes falls back to spinning until the lock is completely free, which is correct but not fair.
+//!
+//! This needs the `std` feature, which is used to give each thread its own index.
+
+use core::cell::{Cell, UnsafeCell};
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU32, AtomicUsize, Ordering, fence};
+
+use std::boxed::Box;
+use std::sync;
+use std::thread_local;
+use std::vec::Vec;
+
+use pause::pause;
+
+// Lock word layout
+const LOCKED: u32 = 1;
+const LOCKED_MASK: u32 = 0xff;
+const PENDING: u32 = 1 << 8;
+const TAIL_SHIFT: u32 = 16;
+const TAIL_MASK: u32 = 0xffff << TAIL_SHIFT;
+
+/// The number of queue nodes each thread has, and so the number of locks that a thread can be queued for at once before
+/// it falls back to unfair spinning.
+pub const MAX_CONTEXTS: usize = 4;
+
+// A tail is `(thread index + 1) << 2 | context`, so that zero can mean that there is no tail.
+const CONTEXT_BITS: u32 = 2;
+const MAX_THREADS: usize = (1 << (16 - CONTEXT_BITS)) - 1;
+
+struct Node {
+    next: AtomicPtr<Node>,
+    // Set once this node is at the head of the queue.
+    head: AtomicU32
+}
+
+struct Block {
+    nodes: [Node; MAX_CONTEXTS]
+}
+
+// The node tables, indexed by thread index. A thread allocates its table the first time it needs it, and leaves it
+// here when it exits, for the next thread given the same index.
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_BLOCK: AtomicPtr<Block> = AtomicPtr::new(ptr::null_mut());
+static BLOCKS: [AtomicPtr<Block>; MAX_THREADS] = [NO_BLOCK; MAX_THREADS];
+
+// Thread indices that have never been handed out start at `NEXT_INDEX`. Indices of threads that have exited are kept in
+// `FREE_INDICES`.
+static NEXT_INDEX: AtomicUsize = AtomicUsize::new(0);
+static FREE_INDICES: sync::Mutex<Vec<usize>> = sync::Mutex::new(Vec::new());
+
+// This thread's place in `BLOCKS`, if there was room for it.
+struct ThreadNodes {
+    index: Option<usize>,
+    // How many of this thread's nodes are in use.
+    depth: Cell<usize>
+}
+
+thread_local! {
+    static THREAD_NODES: ThreadNodes = ThreadNodes::new();
+}
+
+/// An RAII implementation of a "scoped lock" of a `QSpinLock`. When this structure is
+/// dropped (falls out of scope), the lock will be unlocked.
+///
+/// The data protected by the lock can be access through this guard via its
+/// `Deref` and `DerefMut` implementations
+#[must_use]
+pub struct Guard<'a, T: ?Sized + 'a> {
+    lock: &'a QSpinLock<T>
+}
+
+/// A spinlock that takes up only 4 bytes plus the data it protects, but still queues up waiters under contention.
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::Arc;
+/// use std::thread;
+/// use mcs::qspinlock::QSpinLock;
+///
+/// let data = Arc::new(QSpinLock::new(0));
+///
+/// let threads: Vec<_> = (0..10).map(|_| {
+///     let data = data.clone();
+///     thread::spawn(move || {
+///         *data.lock() += 1;
+///     })
+/// }).collect();
+///
+/// for thread in threads {
+///     thread.join().unwrap();
+/// }
+///
+/// assert_eq!(*data.lock(), 10);
+/// ```
+pub struct QSpinLock<T: ?Sized> {
+    word: AtomicU32,
+    data: UnsafeCell<T>
+}
+
+unsafe impl<T: Send> Sync for QSpinLock<T> { }
+unsafe impl<T: Send> Send for QSpinLock<T> { }
+
+impl Block {
+    fn new() -> Block {
+        Block {
+            nodes: [
+                Node { next: AtomicPtr::new(ptr::null_mut()), head: AtomicU32::new(0) },
+                Node { next: AtomicPtr::new(ptr::null_mut()), head: AtomicU32::new(0) },
+                Node { next: AtomicPtr::new(ptr::null_mut()), head: AtomicU32::new(0) },
+                Node { next: AtomicPtr::new(ptr::null_mut()), head: AtomicU32::new(0) }
+            ]
+        }
+    }
+}
+
+impl ThreadNodes {
+    fn new() -> ThreadNodes {
+        let free = FREE_INDICES.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).pop();
+        let index = free.or_else(|| {
+            let index = NEXT_INDEX.fetch_add(1, Ordering::Relaxed);
+            if index < MAX_THREADS {
+                Some(index)
+            } else {
+                // Don't let the counter wrap around to indices that are in use.
+                NEXT_INDEX.store(MAX_THREADS, Ordering::Relaxed);
+                None
+            }
+        });
+
+        if let Some(index) = index {
+            if BLOCKS[index].load(Ordering::Acquire).is_null() {
+                BLOCKS[index].store(Box::into_raw(Box::new(Block::new())), Ordering::Release);
+            }
+        }
+
+        ThreadNodes {
+            index,
+            depth: Cell::new(0)
+        }
+    }
+}
+
+impl Drop for ThreadNodes {
+    fn drop(&mut self) {
+        if let Some(index) = self.index {
+            FREE_INDICES.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(index);
+        }
+    }
+}
+
+fn encode_tail(index: usize, context: usize) -> u32 {
+    (((index as u32 + 1) << CONTEXT_BITS) | context as u32) << TAIL_SHIFT
+}
+
+fn decode_tail(tail: u32) -> *const Node {
+    let tail = tail >> TAIL_SHIFT;
+    let index = (tail >> CONTEXT_BITS) as usize - 1;
+    let context = (tail & ((1 << CONTEXT_BITS) - 1)) as usize;
+    unsafe { &(*BLOCKS[index].load(Ordering::Acquire)).nodes[context] }
+}
+
+impl<T> QSpinLock<T> {
+    /// Creates a new lock in an unlocked state ready for use.
+    pub const fn new(value: T) -> QSpinLock<T> {
+        QSpinLock {
+            word: AtomicU32::new(0),
+            data: UnsafeCell::new(value)
+        }
+    }
+
+    /// Consumes this lock, returning the underlying data.
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> QSpinLock<T> {
+    /// Attempts to acquire this lock.
+    ///
+    /// If the lock could not be acquired at this time, then `Err` is returned.
+    /// Otherwise, an RAII guard is returned. The lock will be unlocked when the
+    /// guard is dropped.
+    ///
+    /// This function does not block.
+    pub fn try_lock<'a>(&'a self) -> Result<Guard<'a, T>, ()> {
+        if self.try_acquire() {
+            Ok(Guard {
+                lock: self
+            })
+        } else {
+            Err(())
+        }
+    }
+
+    /// Acquires this lock, spinning until it is able to do so.
+    ///
+    /// Upon returning, the thread is the only thread with the lock held. An RAII guard is returned to allow scoped
+    /// unlock of the lock. When the guard goes out of scope, the lock will be unlocked.
+    pub fn lock<'a>(&'a self) -> Guard<'a, T> {
+        if self.word.compare_exchange(0, LOCKED, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            self.lock_slow();
+        }
+
+        Guard {
+            lock: self
+        }
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    ///
+    /// Since this call borrows the lock mutably, no actual locking needs to
+    /// take place---the mutable borrow statically guarantees no locks exist.
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+
+    fn try_acquire(&self) -> bool {
+        self.word.load(Ordering::Relaxed) == 0 &&
+        self.word.compare_exchange(0, LOCKED, Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+
+    fn lock_slow(&self) {
+        let mut word = self.word.load(Ordering::Relaxed);
+
+        // Someone is in the middle of turning the pending bit into the locked byte, which should not take long.
+        while word == PENDING {
+            pause();
+            word = self.word.load(Ordering::Relaxed);
+        }
+
+        if word & !LOCKED_MASK == 0 {
+            // There is a holder, but no one else waiting, so try to become the one thread that waits without queueing.
+            let old = self.word.fetch_or(PENDING, Ordering::Acquire);
+            if old & !LOCKED_MASK == 0 {
+                while self.word.load(Ordering::Relaxed) & LOCKED_MASK != 0 {
+                    pause();
+                }
+                // Clear the pending bit and take the lock in one go. Anyone who queued up in the meantime is waiting
+                // for the pending bit to clear, so nothing else can have changed the low bits.
+                self.word.fetch_add(LOCKED.wrapping_sub(PENDING), Ordering::Acquire);
+                return;
+            }
+
+            // We lost the race. Only undo our pending bit if it was ours.
+            if old & PENDING == 0 {
+                self.word.fetch_and(!PENDING, Ordering::Relaxed);
+            }
+        }
+
+        let queued = THREAD_NODES.try_with(|nodes| {
+            let index = match nodes.index {
+                Some(index) => index,
+                None => return false
+            };
+            let context = nodes.depth.get();
+            if context == MAX_CONTEXTS {
+                return false;
+            }
+
+            nodes.depth.set(context + 1);
+            unsafe { self.lock_queued(index, context); }
+            nodes.depth.set(context);
+            true
+        });
+
+        if !queued.unwrap_or(false) {
+            // There is no node to queue up with.
+            while !self.try_acquire() {
+                pause();
+            }
+        }
+    }
+
+    // Waits in line for the lock, using the given node.
+    unsafe fn lock_queued(&self, index: usize, context: usize) {
+        let node = &(*BLOCKS[index].load(Ordering::Relaxed)).nodes[context];
+        let this = node as *const _ as *mut Node;
+        node.next.store(ptr::null_mut(), Ordering::Relaxed);
+        node.head.store(0, Ordering::Relaxed);
+
+        // Become the new tail.
+        let tail = encode_tail(index, context);
+        let mut word = self.word.load(Ordering::Relaxed);
+        loop {
+            match self.word.compare_exchange_weak(word, (word & !TAIL_MASK) | tail, Ordering::AcqRel, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(actual) => word = actual
+            }
+        }
+
+        if word & TAIL_MASK != 0 {
+            let pred = decode_tail(word & TAIL_MASK);
+            (*pred).next.store(this, Ordering::Release);
+            while node.head.load(Ordering::Relaxed) == 0 {
+                pause();
+            }
+            fence(Ordering::Acquire);
+        }
+
+        // We are at the head of the queue, so we are next after the holder and the pending waiter, if there are any.
+        loop {
+            word = self.word.load(Ordering::Acquire);
+            if word & (LOCKED_MASK | PENDING) != 0 {
+                pause();
+                continue;
+            }
+            if word & TAIL_MASK != tail {
+                break;
+            }
+            // No one is queued behind us. A thread that saw the lock without a tail may still set the pending bit for a
+            // moment before it notices us and backs off, which fails this, so only assume that someone queued up
+            // behind us once the tail changes.
+            if self.word.compare_exchange(word, LOCKED, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+                return;
+            }
+        }
+
+        // Someone is queued behind us, and no one else can take the lock until they are at the head of the queue.
+        self.word.fetch_or(LOCKED, Ordering::Acquire);
+        let mut succ = node.next.load(Ordering::Acquire);
+        while succ.is_null() {
+            pause();
+            succ = node.next.load(Ordering::Acquire);
+        }
+        (*succ).head.store(1, Ordering::Release);
+    }
+
+    fn unlock(&self) {
+        self.word.fetch_sub(LOCKED, Ordering::Release);
+    }
+}
+
+impl<'a, T: ?Sized> Deref for Guard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for Guard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+guard_drop! {
+    impl<'a, T: ?Sized> Drop for Guard<'a, T> {
+        fn drop(&mut self) {
+            self.lock.unlock();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{QSpinLock, MAX_CONTEXTS, THREAD_NODES};
+
+    use std::mem;
+    use std::sync::Arc;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::Duration;
+    use std::vec::Vec;
+
+    #[derive(Eq, PartialEq, Debug)]
+    struct NonCopy(i32);
+
+    #[test]
+    fn smoke() {
+        let m = QSpinLock::new(());
+        drop(m.lock());
+        drop(m.lock());
+        assert_eq!(mem::size_of::<QSpinLock<()>>(), 4);
+    }
+
+    #[test]
+    fn lots_and_lots() {
+        static LOCK: QSpinLock<u32> = QSpinLock::new(0);
+
+        const ITERS: u32 = 1000;
+        const CONCURRENCY: u32 = 3;
+
+        fn inc() {
+            for _ in 0..ITERS {
+                let mut g = LOCK.lock();
+                *g += 1;
+            }
+        }
+
+        let (tx, rx) = channel();
+        for _ in 0..CONCURRENCY {
+            let tx2 = tx.clone();
+            thread::spawn(move|| { inc(); tx2.send(()).unwrap(); });
+            let tx2 = tx.clone();
+            thread::spawn(move|| { inc(); tx2.send(()).unwrap(); });
+        }
+
+        drop(tx);
+        for _ in 0..2 * CONCURRENCY {
+            rx.recv().unwrap();
+        }
+        assert_eq!(*LOCK.lock(), ITERS * CONCURRENCY * 2);
+    }
+
+    #[test]
+    fn nested() {
+        // More levels than there are nodes per thread. Only waiting for a lock takes up a node, not holding one, so this
+        // never runs out of nodes, but checks that holding many locks doesn't either.
+        const DEPTH: usize = MAX_CONTEXTS + 2;
+        const ITERS: u32 = 100;
+        const CONCURRENCY: usize = 3;
+
+        let locks = Arc::new((0..DEPTH).map(|_| QSpinLock::new(0)).collect::<Vec<_>>());
+        let threads: Vec<_> = (0..CONCURRENCY).map(|_| {
+            let locks = locks.clone();
+            thread::spawn(move|| {
+                for _ in 0..ITERS {
+                    let mut guards: Vec<_> = locks.iter().map(|lock| lock.lock()).collect();
+                    for guard in &mut guards {
+                        **guard += 1;
+                    }
+                }
+            })
+        }).collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        for lock in locks.iter() {
+            assert_eq!(*lock.lock(), ITERS * CONCURRENCY as u32);
+        }
+    }
+
+    #[test]
+    fn without_nodes() {
+        const ITERS: u32 = 50;
+        const CONCURRENCY: usize = 4;
+
+        // Half of the threads act as if all their nodes were in use, so they fall back to spinning while the others
+        // queue up, and keep setting and clearing the pending bit under the head of the queue.
+        let lock = Arc::new(QSpinLock::new(0));
+        let guard = lock.lock();
+        let threads: Vec<_> = (0..CONCURRENCY).map(|i| {
+            let lock = lock.clone();
+            thread::spawn(move|| {
+                if i % 2 == 0 {
+                    THREAD_NODES.with(|nodes| nodes.depth.set(MAX_CONTEXTS));
+                }
+                for _ in 0..ITERS {
+                    *lock.lock() += 1;
+                }
+            })
+        }).collect();
+
+        // Make sure that everyone starts out waiting.
+        thread::sleep(Duration::from_millis(10));
+        drop(guard);
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*lock.lock(), ITERS * CONCURRENCY as u32);
+    }
+
+    #[test]
+    fn try_lock() {
+        let m = QSpinLock::new(());
+        let g = m.try_lock().unwrap();
+        assert!(m.try_lock().is_err());
+        drop(g);
+        *m.try_lock().unwrap() = ();
+    }
+
+    #[test]
+    fn test_into_inner() {
+        let m = QSpinLock::new(NonCopy(10));
+        assert_eq!(m.into_inner(), NonCopy(10));
+    }
+
+    #[test]
+    fn test_get_mut() {
+        let mut m = QSpinLock::new(NonCopy(10));
+        *m.get_mut() = NonCopy(20);
+        assert_eq!(m.into_inner(), NonCopy(20));
+    }
+
+    #[test]
+    fn test_lock_unsized() {
+        let lock: &QSpinLock<[i32]> = &QSpinLock::new([1, 2, 3]);
+        {
+            let b = &mut *lock.lock();
+            b[0] = 4;
+            b[2] = 5;
+        }
+        let comp: &[i32] = &[4, 2, 5];
+        assert_eq!(&*lock.lock(), comp);
+    }
+}
@@ -0,0 +1,197 @@
+//! A `lock_api::RawMutex` built on the MCS lock, for code that is generic over the kind of mutex it uses.
+//!
+//! `lock_api` has nowhere to pass a `Slot`, so `RawMcs` takes its slots from a stack kept by each thread. Locking pops a
+//! slot off the stack (allocating one if the stack is empty) and unlocking pushes it back, so a thread holding `n` locks
+//! at once owns `n` slots, and reuses them afterwards.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::sync::Arc;
+//! use std::thread;
+//! use mcs::raw::Mutex;
+//!
+//! let data = Arc::new(Mutex::new(0));
+//!
+//! let threads: Vec<_> = (0..10).map(|_| {
+//!     let data = data.clone();
+//!     thread::spawn(move || {
+//!         *data.lock() += 1;
+//!     })
+//! }).collect();
+//!
+//! for thread in threads {
+//!     thread.join().unwrap();
+//! }
+//!
+//! assert_eq!(*data.lock(), 10);
+//! ```
+
+use core::cell::RefCell;
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use std::boxed::Box;
+use std::thread_local;
+use std::vec::Vec;
+
+use lock_api::{self, GuardNoSend};
+
+use mutex::{self, Slot};
+use poison::{PoisonError, TryLockError};
+
+/// A mutex that uses `RawMcs`, for use just like `parking_lot::Mutex`.
+pub type Mutex<T> = lock_api::Mutex<RawMcs, T>;
+
+/// An RAII guard for `raw::Mutex`.
+pub type MutexGuard<'a, T> = lock_api::MutexGuard<'a, RawMcs, T>;
+
+/// A raw MCS lock that gets its slots from a per-thread slot stack.
+pub struct RawMcs {
+    lock: mutex::Mutex<()>,
+    // The slot that the lock is held with. Only touched by the thread holding the lock.
+    holder: AtomicPtr<Slot>
+}
+
+thread_local! {
+    // The slots that this thread is not currently holding a lock with. They are boxed so that a slot keeps its address
+    // while it is lent out to a lock.
+    #[allow(clippy::vec_box)]
+    static SLOTS: RefCell<Vec<Box<Slot>>> = const { RefCell::new(Vec::new()) };
+}
+
+fn take_slot() -> Box<Slot> {
+    SLOTS.try_with(|slots| slots.borrow_mut().pop())
+         .ok()
+         .and_then(|slot| slot)
+         .unwrap_or_else(|| Box::new(Slot::new()))
+}
+
+fn return_slot(slot: Box<Slot>) {
+    // If the thread is exiting, just free the slot.
+    let _ = SLOTS.try_with(move |slots| slots.borrow_mut().push(slot));
+}
+
+unsafe impl lock_api::RawMutex for RawMcs {
+    const INIT: RawMcs = RawMcs {
+        lock: mutex::Mutex::new(()),
+        holder: AtomicPtr::new(ptr::null_mut())
+    };
+
+    // The slot has to be given back to the thread it came from.
+    type GuardMarker = GuardNoSend;
+
+    fn lock(&self) {
+        let mut slot = take_slot();
+        mem::forget(self.lock.lock(&mut slot).unwrap_or_else(PoisonError::into_inner));
+        self.holder.store(Box::into_raw(slot), Ordering::Relaxed);
+    }
+
+    fn try_lock(&self) -> bool {
+        let mut slot = take_slot();
+        // The guards are never dropped, so the lock is never poisoned.
+        let locked = match self.lock.try_lock(&mut slot) {
+            Ok(guard) => {
+                mem::forget(guard);
+                true
+            },
+            Err(TryLockError::Poisoned(err)) => {
+                mem::forget(err.into_inner());
+                true
+            },
+            Err(TryLockError::WouldBlock) => false
+        };
+        if locked {
+            self.holder.store(Box::into_raw(slot), Ordering::Relaxed);
+        } else {
+            return_slot(slot);
+        }
+        locked
+    }
+
+    unsafe fn unlock(&self) {
+        let slot = Box::from_raw(self.holder.load(Ordering::Relaxed));
+        self.lock.unlock(&slot);
+        return_slot(slot);
+    }
+
+    fn is_locked(&self) -> bool {
+        self.lock.is_locked()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Mutex;
+
+    use std::sync::Arc;
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    #[test]
+    fn smoke() {
+        let m = Mutex::new(());
+        drop(m.lock());
+        drop(m.lock());
+        assert!(!m.is_locked());
+    }
+
+    #[test]
+    fn lots_and_lots() {
+        static LOCK: Mutex<u32> = Mutex::new(0);
+
+        const ITERS: u32 = 1000;
+        const CONCURRENCY: u32 = 3;
+
+        fn inc() {
+            for _ in 0..ITERS {
+                *LOCK.lock() += 1;
+            }
+        }
+
+        let (tx, rx) = channel();
+        for _ in 0..CONCURRENCY {
+            let tx2 = tx.clone();
+            thread::spawn(move|| { inc(); tx2.send(()).unwrap(); });
+            let tx2 = tx.clone();
+            thread::spawn(move|| { inc(); tx2.send(()).unwrap(); });
+        }
+
+        drop(tx);
+        for _ in 0..2 * CONCURRENCY {
+            rx.recv().unwrap();
+        }
+        assert_eq!(*LOCK.lock(), ITERS * CONCURRENCY * 2);
+    }
+
+    #[test]
+    fn try_lock() {
+        let m = Mutex::new(());
+        let g = m.try_lock().unwrap();
+        assert!(m.is_locked());
+        assert!(m.try_lock().is_none());
+        drop(g);
+        *m.try_lock().unwrap() = ();
+    }
+
+    #[test]
+    fn nested() {
+        const DEPTH: usize = 20;
+
+        let locks: std::vec::Vec<_> = (0..DEPTH).map(Mutex::new).collect();
+        let guards: std::vec::Vec<_> = locks.iter().map(|lock| lock.lock()).collect();
+        for (i, guard) in guards.iter().enumerate() {
+            assert_eq!(**guard, i);
+        }
+        drop(guards);
+
+        let arc = Arc::new(Mutex::new(1));
+        let arc2 = Arc::new(Mutex::new(arc));
+        thread::spawn(move|| {
+            let lock = arc2.lock();
+            let lock2 = lock.lock();
+            assert_eq!(*lock2, 1);
+        }).join().unwrap();
+    }
+}
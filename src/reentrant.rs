@@ -0,0 +1,375 @@
+// A mutex that the thread holding it can lock again. It is built on `k42::Mutex`, which keeps the queue node of the
+// holder inside the lock: a nested guard can outlive the outermost one, so there is no caller-supplied slot that could
+// safely stay in the queue until the last guard is gone.
+//
+// The owner is stored as a `usize` from a `ThreadId` implementation rather than as a `std::thread::ThreadId`, both
+// because this has to work without `std` and because the only way to turn a `std::thread::ThreadId` into an integer
+// that fits in an atomic, `ThreadId::as_u64`, is unstable. `StdThreadId` hands out its own numbers instead, which are
+// never reused, just like the ones behind `std::thread::ThreadId`.
+
+use core::cell::{Cell, UnsafeCell};
+use core::marker::PhantomData;
+use core::mem;
+use core::ops::Deref;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use k42;
+
+#[cfg(feature = "std")]
+use std::thread_local;
+
+/// A way of telling threads apart, for `ReentrantMutex`.
+///
+/// With the `std` feature, `StdThreadId` is provided. Without it, implement this on top of whatever the platform uses
+/// to identify threads (or tasks, or CPUs with interrupts disabled).
+pub trait ThreadId {
+    /// Returns an id for the current thread. This must not be 0, and must never be given to two different threads, even
+    /// if one has exited before the other started: a thread that exits while holding a leaked guard still holds the
+    /// lock, and a later thread with the same id would be let in.
+    fn thread_id() -> usize;
+}
+
+/// A `ThreadId` for `std` threads.
+///
+/// Like `std::thread::ThreadId`, this numbers threads in the order that they first ask for their id, and never reuses a
+/// number. The number is cached in a thread-local variable, which is cheaper than going through
+/// `std::thread::current()`.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdThreadId;
+
+#[cfg(feature = "std")]
+static NEXT_THREAD_ID: AtomicUsize = AtomicUsize::new(1);
+
+#[cfg(feature = "std")]
+impl ThreadId for StdThreadId {
+    fn thread_id() -> usize {
+        thread_local!(static ID: usize = NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed));
+        ID.with(|&id| id)
+    }
+}
+
+/// An RAII implementation of a "scoped lock" of a reentrant mutex. When this structure is dropped (falls out of scope),
+/// the lock will be unlocked, unless the thread still holds other guards for it.
+///
+/// The data protected by the mutex can be accessed through this guard via its `Deref` implementation. Since there can
+/// be several guards for the same mutex at once, it only gives out shared references.
+#[must_use]
+pub struct ReentrantGuard<'a, T: ?Sized + 'a, I: ThreadId + 'a> {
+    lock: &'a ReentrantMutex<T, I>,
+    // The guard has to be dropped by the thread that owns the lock.
+    _not_send: PhantomData<*const ()>
+}
+
+/// A mutex which can be locked again by the thread that already holds it.
+///
+/// The first `lock` by a thread queues up for the lock like `k42::Mutex`. Further calls from the same thread only
+/// count how deeply the lock is held, and the lock is released once every guard has been dropped. No `Slot` is needed.
+///
+/// Threads are told apart by `I`.
+///
+/// # Examples
+///
+/// ```
+/// use mcs::{ReentrantMutex, ThreadId};
+/// use std::cell::RefCell;
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+///
+/// // With the `std` feature, `mcs::StdThreadId` does this.
+/// struct Threads;
+///
+/// static NEXT: AtomicUsize = AtomicUsize::new(1);
+///
+/// impl ThreadId for Threads {
+///     fn thread_id() -> usize {
+///         thread_local!(static ID: usize = NEXT.fetch_add(1, Ordering::Relaxed));
+///         ID.with(|&id| id)
+///     }
+/// }
+///
+/// let lock: ReentrantMutex<_, Threads> = ReentrantMutex::new(RefCell::new(Vec::new()));
+///
+/// let outer = lock.lock();
+/// outer.borrow_mut().push(1);
+/// {
+///     // Locking again from the same thread doesn't deadlock.
+///     let inner = lock.lock();
+///     inner.borrow_mut().push(2);
+/// }
+/// assert_eq!(*outer.borrow(), [1, 2]);
+/// ```
+pub struct ReentrantMutex<T: ?Sized, I> {
+    lock: k42::Mutex<()>,
+    // The id of the thread holding the lock, or 0.
+    owner: AtomicUsize,
+    // How many guards the owner has. Only touched by the owner.
+    count: Cell<usize>,
+    _thread_id: PhantomData<fn() -> I>,
+    data: UnsafeCell<T>
+}
+
+unsafe impl<T: ?Sized + Send, I> Sync for ReentrantMutex<T, I> { }
+unsafe impl<T: ?Sized + Send, I> Send for ReentrantMutex<T, I> { }
+
+impl<T, I> ReentrantMutex<T, I> {
+    /// Creates a new reentrant mutex in an unlocked state ready for use.
+    pub const fn new(value: T) -> ReentrantMutex<T, I> {
+        ReentrantMutex {
+            lock: k42::Mutex::new(()),
+            owner: AtomicUsize::new(0),
+            count: Cell::new(0),
+            _thread_id: PhantomData,
+            data: UnsafeCell::new(value)
+        }
+    }
+
+    /// Consumes this mutex, returning the underlying data.
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized, I: ThreadId> ReentrantMutex<T, I> {
+    /// Acquires the mutex, blocking the current thread until it is able to do so.
+    ///
+    /// If the current thread already holds the lock, this returns another guard immediately.
+    pub fn lock<'a>(&'a self) -> ReentrantGuard<'a, T, I> {
+        let me = I::thread_id();
+        if self.owner.load(Ordering::Relaxed) != me {
+            mem::forget(self.lock.lock());
+            self.owner.store(me, Ordering::Relaxed);
+        }
+        self.guard()
+    }
+
+    /// Attempts to acquire this lock.
+    ///
+    /// If the lock is held by another thread, then `Err` is returned. Otherwise, an RAII guard is returned. The lock
+    /// will be unlocked when every guard for it is dropped.
+    ///
+    /// This function does not block.
+    pub fn try_lock<'a>(&'a self) -> Result<ReentrantGuard<'a, T, I>, ()> {
+        let me = I::thread_id();
+        if self.owner.load(Ordering::Relaxed) != me {
+            mem::forget(self.lock.try_lock()?);
+            self.owner.store(me, Ordering::Relaxed);
+        }
+        Ok(self.guard())
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    ///
+    /// Since this call borrows the `ReentrantMutex` mutably, no actual locking needs to take place---the mutable borrow
+    /// statically guarantees no locks exist.
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+
+    // Makes one more guard. Must be called by the owner.
+    fn guard<'a>(&'a self) -> ReentrantGuard<'a, T, I> {
+        let count = self.count.get().checked_add(1).expect("lock count overflow in reentrant mutex");
+        self.count.set(count);
+        ReentrantGuard {
+            lock: self,
+            _not_send: PhantomData
+        }
+    }
+
+    // Gives up one guard, releasing the lock with the last one.
+    fn unlock(&self) {
+        let count = self.count.get() - 1;
+        self.count.set(count);
+        if count == 0 {
+            self.owner.store(0, Ordering::Relaxed);
+            self.lock.unlock();
+        }
+    }
+}
+
+impl<'a, T: ?Sized, I: ThreadId> Deref for ReentrantGuard<'a, T, I> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+guard_drop! {
+    impl<'a, T: ?Sized, I: ThreadId> Drop for ReentrantGuard<'a, T, I> {
+        fn drop(&mut self) {
+            self.lock.unlock();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ReentrantMutex, ThreadId};
+
+    use std::cell::RefCell;
+    use std::sync::Arc;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::thread_local;
+
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    // Works without the `std` feature too.
+    struct TestIds;
+
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+    impl ThreadId for TestIds {
+        fn thread_id() -> usize {
+            thread_local!(static ID: usize = NEXT_ID.fetch_add(1, Ordering::Relaxed));
+            ID.with(|&id| id)
+        }
+    }
+
+    type Mutex<T> = ReentrantMutex<T, TestIds>;
+
+    #[derive(Eq, PartialEq, Debug)]
+    struct NonCopy(i32);
+
+    #[test]
+    fn smoke() {
+        let m = Mutex::new(());
+        {
+            let a = m.lock();
+            {
+                let b = m.lock();
+                {
+                    let c = m.lock();
+                    assert_eq!(*c, ());
+                }
+                assert_eq!(*b, ());
+            }
+            assert_eq!(*a, ());
+        }
+        drop(m.lock());
+    }
+
+    #[test]
+    fn is_mutex() {
+        let m = Arc::new(Mutex::new(RefCell::new(0)));
+        let m2 = m.clone();
+        let lock = m.lock();
+        let child = thread::spawn(move || {
+            let lock = m2.lock();
+            assert_eq!(*lock.borrow(), 4950);
+        });
+        for i in 0..100 {
+            let lock = m.lock();
+            *lock.borrow_mut() += i;
+        }
+        drop(lock);
+        child.join().unwrap();
+    }
+
+    #[test]
+    fn trylock_works() {
+        let m = Arc::new(Mutex::new(()));
+        let m2 = m.clone();
+        let _lock = m.try_lock().unwrap();
+        let _lock2 = m.try_lock().unwrap();
+        thread::spawn(move || {
+            assert!(m2.try_lock().is_err());
+        }).join().unwrap();
+        let _lock3 = m.try_lock().unwrap();
+    }
+
+    #[test]
+    fn out_of_order_drop() {
+        let m = Arc::new(Mutex::new(()));
+        let m2 = m.clone();
+        let outer = m.lock();
+        let inner = m.lock();
+        drop(outer);
+        thread::spawn(move || {
+            assert!(m2.try_lock().is_err());
+        }).join().unwrap();
+        drop(inner);
+        assert!(m.try_lock().is_ok());
+    }
+
+    #[test]
+    fn lots_and_lots() {
+        static LOCK: Mutex<RefCell<u32>> = Mutex::new(RefCell::new(0));
+
+        const ITERS: u32 = 1000;
+        const CONCURRENCY: u32 = 3;
+
+        fn inc() {
+            for _ in 0..ITERS {
+                let g = LOCK.lock();
+                let g2 = LOCK.lock();
+                *g2.borrow_mut() += 1;
+                drop(g);
+            }
+        }
+
+        let (tx, rx) = channel();
+        for _ in 0..CONCURRENCY {
+            let tx2 = tx.clone();
+            thread::spawn(move|| { inc(); tx2.send(()).unwrap(); });
+            let tx2 = tx.clone();
+            thread::spawn(move|| { inc(); tx2.send(()).unwrap(); });
+        }
+
+        drop(tx);
+        for _ in 0..2 * CONCURRENCY {
+            rx.recv().unwrap();
+        }
+        assert_eq!(*LOCK.lock().borrow(), ITERS * CONCURRENCY * 2);
+    }
+
+    #[test]
+    fn test_into_inner() {
+        let m = Mutex::new(NonCopy(10));
+        assert_eq!(m.into_inner(), NonCopy(10));
+    }
+
+    #[test]
+    fn test_get_mut() {
+        let mut m = Mutex::new(NonCopy(10));
+        *m.get_mut() = NonCopy(20);
+        assert_eq!(m.into_inner(), NonCopy(20));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn std_thread_id() {
+        use super::StdThreadId;
+
+        let m = Arc::new(ReentrantMutex::<_, StdThreadId>::new(0));
+        let m2 = m.clone();
+        let _lock = m.lock();
+        assert!(m.try_lock().is_ok());
+        thread::spawn(move || {
+            assert!(m2.try_lock().is_err());
+        }).join().unwrap();
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn std_thread_id_not_reused() {
+        use super::StdThreadId;
+        use std::collections::BTreeSet;
+        use std::mem;
+
+        let mut seen = BTreeSet::new();
+        assert!(seen.insert(StdThreadId::thread_id()));
+        for _ in 0..20 {
+            let id = thread::spawn(StdThreadId::thread_id).join().unwrap();
+            assert!(seen.insert(id), "thread id {} was reused", id);
+        }
+
+        // A thread that exits holding a leaked guard keeps the lock, so no later thread gets in.
+        let m = Arc::new(ReentrantMutex::<_, StdThreadId>::new(0));
+        let m2 = m.clone();
+        thread::spawn(move || mem::forget(m2.lock())).join().unwrap();
+        for _ in 0..20 {
+            let m2 = m.clone();
+            thread::spawn(move || assert!(m2.try_lock().is_err())).join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! Strategies for what a thread does while it waits for a `Mutex`.
+//!
+//! A mutex calls its strategy in the loop where a queued thread waits for the lock to be handed over, and in the loop
+//! where the releasing thread waits for its successor to finish queueing up. The strategy is a type parameter of
+//! `Mutex`, chosen with `Mutex::with_relax`; `Mutex::new` uses `SpinThenYield`.
+//!
+//! Waiters that have been spinning for a long time still park their thread if `std` is enabled, whatever the strategy.
+//! On Linux, a strategy can have them sleep on a futex instead, even without `std`: wrap it in `Futex` to do that for
+//! one mutex, or enable the `futex` feature to do it for every strategy that doesn't say otherwise.
+
+use core::hint;
+
+#[cfg(feature = "std")]
+use std::thread;
+
+use park;
+
+/// A way of waiting a little before checking a lock again.
+///
+/// A fresh value is made with `Default` every time a thread starts waiting, and `relax` is called every time it finds
+/// that it has to keep waiting, so a strategy can change its behaviour as the wait goes on.
+pub trait Relax: Default {
+    /// Whether a waiter that has been spinning for a long time sleeps on a futex, rather than parking its thread or
+    /// spinning on. This only has an effect on Linux, and is `true` by default if the `futex` feature is enabled.
+    const FUTEX: bool = park::FUTEX;
+
+    /// Waits a little.
+    fn relax(&mut self);
+}
+
+/// Spins with `core::hint::spin_loop`, which tells the processor that this is a busy-wait loop.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Spin;
+
+impl Relax for Spin {
+    #[inline(always)]
+    fn relax(&mut self) {
+        hint::spin_loop();
+    }
+}
+
+// The largest number of spins that `Backoff` does at once is `1 << BACKOFF_LIMIT`.
+const BACKOFF_LIMIT: u32 = 6;
+
+/// Spins for exponentially longer between checks, up to a limit. This cuts down on traffic to the cache line that is
+/// being waited on.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Backoff {
+    step: u32
+}
+
+impl Relax for Backoff {
+    #[inline]
+    fn relax(&mut self) {
+        for _ in 0..1u32 << self.step {
+            hint::spin_loop();
+        }
+        if self.step < BACKOFF_LIMIT {
+            self.step += 1;
+        }
+    }
+}
+
+/// Gives up the rest of the time slice with `std::thread::yield_now`. This suits machines with more threads than
+/// processors, where the thread that will hand over the lock might not be running.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Yield;
+
+#[cfg(feature = "std")]
+impl Relax for Yield {
+    #[inline]
+    fn relax(&mut self) {
+        thread::yield_now();
+    }
+}
+
+/// Spins for a while, then starts yielding to other threads. Without `std`, it just keeps spinning.
+///
+/// This is the default, since most waits are short, but one can get very long if a thread is descheduled at the wrong
+/// moment.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SpinThenYield {
+    spins: u32
+}
+
+impl Relax for SpinThenYield {
+    #[inline]
+    fn relax(&mut self) {
+        park::snooze(&mut self.spins);
+    }
+}
+
+/// Waits like `R`, but a waiter that has been spinning for a long time sleeps on a futex, whether or not the `futex`
+/// feature is enabled. Where futexes are not available, this is the same as `R`.
+///
+/// ```
+/// use mcs::Mutex;
+/// use mcs::relax::{Futex, SpinThenYield};
+///
+/// static LOCK: Mutex<u32, Futex<SpinThenYield>> = Mutex::with_relax(0);
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Futex<R = SpinThenYield> {
+    inner: R
+}
+
+impl<R: Relax> Relax for Futex<R> {
+    const FUTEX: bool = true;
+
+    #[inline]
+    fn relax(&mut self) {
+        self.inner.relax();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Backoff, BACKOFF_LIMIT, Relax, SpinThenYield};
+    use park::SPIN_LIMIT;
+
+    #[test]
+    fn backoff_is_bounded() {
+        let mut backoff = Backoff::default();
+        for step in 0..BACKOFF_LIMIT {
+            assert_eq!(backoff.step, step);
+            backoff.relax();
+        }
+        backoff.relax();
+        assert_eq!(backoff.step, BACKOFF_LIMIT);
+    }
+
+    #[test]
+    fn spin_then_yield_counts_spins() {
+        let mut relax = SpinThenYield::default();
+        for _ in 0..SPIN_LIMIT + 10 {
+            relax.relax();
+        }
+        assert_eq!(relax.spins, SPIN_LIMIT);
+    }
+}
@@ -0,0 +1,403 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+use pause::pause;
+
+// Slot classes
+const READING: usize = 0;
+const WRITING: usize = 1;
+
+// Slot state bits. The successor class needs to be updated atomically with the blocked flag, as a reader queued behind a
+// waiting reader has to know for sure whether that reader will wake it up.
+const BLOCKED: usize = 1;
+const SUCCESSOR_READER: usize = 2;
+const SUCCESSOR_WRITER: usize = 4;
+
+pub struct RwSlot {
+    class: AtomicUsize,
+    next: AtomicPtr<RwSlot>,
+    state: AtomicUsize
+}
+
+/// RAII structure used to release the shared read access of a lock when dropped.
+#[must_use]
+pub struct ReadGuard<'a, T: ?Sized + 'a> {
+    lock: &'a RwLock<T>,
+    slot: &'a RwSlot
+}
+
+/// RAII structure used to release the exclusive write access of a lock when dropped.
+#[must_use]
+pub struct WriteGuard<'a, T: ?Sized + 'a> {
+    lock: &'a RwLock<T>,
+    slot: &'a RwSlot
+}
+
+/// A reader-writer lock, based on Mellor-Crummey and Scott's fair reader-writer queueing lock.
+///
+/// Like `Mutex`, every thread waiting for the lock brings its own `RwSlot` and spins only on that slot. The lock is
+/// granted in FIFO order, except that a group of readers that are next to each other in the queue all hold the lock
+/// at the same time. A writer waits for the readers in front of it, and readers behind a writer wait for the writer.
+///
+/// # Examples
+///
+/// ```
+/// use mcs::{RwLock, RwSlot};
+///
+/// let lock = RwLock::new(5);
+///
+/// // many reader locks can be held at once
+/// {
+///     let mut slot1 = RwSlot::new();
+///     let mut slot2 = RwSlot::new();
+///     let r1 = lock.read(&mut slot1);
+///     let r2 = lock.read(&mut slot2);
+///     assert_eq!(*r1, 5);
+///     assert_eq!(*r2, 5);
+/// } // read locks are dropped at this point
+///
+/// // only one write lock may be held, however
+/// {
+///     let mut slot = RwSlot::new();
+///     let mut w = lock.write(&mut slot);
+///     *w += 1;
+///     assert_eq!(*w, 6);
+/// } // write lock is dropped here
+/// ```
+pub struct RwLock<T: ?Sized> {
+    queue: AtomicPtr<RwSlot>,
+    reader_count: AtomicUsize,
+    // A writer that is waiting for the current readers to leave.
+    next_writer: AtomicPtr<RwSlot>,
+    data: UnsafeCell<T>
+}
+
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> { }
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> { }
+
+impl RwSlot {
+    pub const fn new() -> RwSlot {
+        RwSlot {
+            class: AtomicUsize::new(READING),
+            next: AtomicPtr::new(ptr::null_mut()),
+            state: AtomicUsize::new(0)
+        }
+    }
+
+    fn reset(&mut self, class: usize) {
+        self.class = AtomicUsize::new(class);
+        self.next = AtomicPtr::new(ptr::null_mut());
+        self.state = AtomicUsize::new(BLOCKED);
+    }
+
+    fn wait_unblocked(&self) {
+        while self.state.load(Ordering::Acquire) & BLOCKED != 0 {
+            pause();
+        }
+    }
+
+    fn wait_next(&self) -> &RwSlot {
+        loop {
+            let next = self.next.load(Ordering::Acquire);
+            if !next.is_null() {
+                return unsafe { &*next };
+            }
+            pause();
+        }
+    }
+
+    fn unblock(&self) {
+        self.state.fetch_and(!BLOCKED, Ordering::Release);
+    }
+}
+
+impl Default for RwSlot {
+    fn default() -> RwSlot {
+        RwSlot::new()
+    }
+}
+
+impl<T> RwLock<T> {
+    /// Creates a new reader-writer lock in an unlocked state ready for use.
+    pub const fn new(value: T) -> RwLock<T> {
+        RwLock {
+            queue: AtomicPtr::new(ptr::null_mut()),
+            reader_count: AtomicUsize::new(0),
+            next_writer: AtomicPtr::new(ptr::null_mut()),
+            data: UnsafeCell::new(value)
+        }
+    }
+
+    /// Consumes this lock, returning the underlying data.
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Locks this lock with shared read access, blocking the current thread until it can be acquired.
+    ///
+    /// The calling thread will be blocked until there are no more writers which hold the lock or are queued in front
+    /// of it. There may be other readers currently inside the lock when this method returns.
+    pub fn read<'a>(&'a self, slot: &'a mut RwSlot) -> ReadGuard<'a, T> {
+        slot.reset(READING);
+        let pred = self.queue.swap(slot, Ordering::AcqRel);
+        if pred.is_null() {
+            self.reader_count.fetch_add(1, Ordering::SeqCst);
+            slot.unblock();
+        } else {
+            let pred = unsafe { &*pred };
+            if pred.class.load(Ordering::Relaxed) == WRITING ||
+               pred.state.compare_exchange(BLOCKED, BLOCKED | SUCCESSOR_READER, Ordering::AcqRel, Ordering::Relaxed).is_ok() {
+                // Our predecessor is a writer or a waiting reader, and it will let us in once it has the lock.
+                pred.next.store(slot, Ordering::Release);
+                slot.wait_unblocked();
+            } else {
+                // Our predecessor is a reader that already holds the lock, so we can join it.
+                self.reader_count.fetch_add(1, Ordering::SeqCst);
+                pred.next.store(slot, Ordering::Release);
+                slot.unblock();
+            }
+        }
+
+        if slot.state.load(Ordering::Acquire) & SUCCESSOR_READER != 0 {
+            // A reader queued up behind us while we were waiting, so let it in too.
+            let next = slot.wait_next();
+            self.reader_count.fetch_add(1, Ordering::SeqCst);
+            next.unblock();
+        }
+
+        ReadGuard {
+            lock: self,
+            slot
+        }
+    }
+
+    /// Locks this lock with exclusive write access, blocking the current thread until it can be acquired.
+    ///
+    /// This function will not return while other writers or other readers currently have access to the lock.
+    pub fn write<'a>(&'a self, slot: &'a mut RwSlot) -> WriteGuard<'a, T> {
+        slot.reset(WRITING);
+        let pred = self.queue.swap(slot, Ordering::AcqRel);
+        if pred.is_null() {
+            // There may still be readers that left the queue but hold the lock. If so, the last of them will let us in.
+            self.next_writer.store(slot, Ordering::SeqCst);
+            if self.reader_count.load(Ordering::SeqCst) == 0 &&
+               core::ptr::eq(self.next_writer.swap(ptr::null_mut(), Ordering::SeqCst), slot) {
+                slot.unblock();
+            }
+        } else {
+            let pred = unsafe { &*pred };
+            pred.state.fetch_or(SUCCESSOR_WRITER, Ordering::Relaxed);
+            pred.next.store(slot, Ordering::Release);
+        }
+        slot.wait_unblocked();
+
+        WriteGuard {
+            lock: self,
+            slot
+        }
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    ///
+    /// Since this call borrows the `RwLock` mutably, no actual locking needs to
+    /// take place---the mutable borrow statically guarantees no locks exist.
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+
+    fn read_unlock(&self, slot: &RwSlot) {
+        let this_slot = slot as *const _ as *mut RwSlot;
+        if !slot.next.load(Ordering::Acquire).is_null() ||
+           self.queue.compare_exchange(this_slot, ptr::null_mut(), Ordering::AcqRel, Ordering::Relaxed).is_err() {
+            // Wait for our successor to finish registering, as it might still be inspecting our slot.
+            let next = slot.wait_next();
+            if slot.state.load(Ordering::Relaxed) & SUCCESSOR_WRITER != 0 {
+                self.next_writer.store(next as *const _ as *mut _, Ordering::SeqCst);
+            }
+        }
+
+        if self.reader_count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            // We were the last reader, so let in the waiting writer, if there is one.
+            let writer = self.next_writer.load(Ordering::SeqCst);
+            if !writer.is_null() && self.reader_count.load(Ordering::SeqCst) == 0 &&
+               self.next_writer.compare_exchange(writer, ptr::null_mut(), Ordering::SeqCst, Ordering::Relaxed).is_ok() {
+                unsafe { (*writer).unblock(); }
+            }
+        }
+    }
+
+    fn write_unlock(&self, slot: &RwSlot) {
+        let this_slot = slot as *const _ as *mut RwSlot;
+        if !slot.next.load(Ordering::Acquire).is_null() ||
+           self.queue.compare_exchange(this_slot, ptr::null_mut(), Ordering::Release, Ordering::Relaxed).is_err() {
+            let next = slot.wait_next();
+            if next.class.load(Ordering::Relaxed) == READING {
+                self.reader_count.fetch_add(1, Ordering::SeqCst);
+            }
+            next.unblock();
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for ReadGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for WriteGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for WriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+guard_drop! {
+    impl<'a, T: ?Sized> Drop for ReadGuard<'a, T> {
+        fn drop(&mut self) {
+            self.lock.read_unlock(self.slot);
+        }
+    }
+}
+
+guard_drop! {
+    impl<'a, T: ?Sized> Drop for WriteGuard<'a, T> {
+        fn drop(&mut self) {
+            self.lock.write_unlock(self.slot);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RwLock, RwSlot};
+
+    use std::sync::Arc;
+    use std::sync::mpsc::channel;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::vec::Vec;
+
+    #[derive(Eq, PartialEq, Debug)]
+    struct NonCopy(i32);
+
+    #[test]
+    fn smoke() {
+        let mut slot = RwSlot::new();
+        let l = RwLock::new(());
+        drop(l.read(&mut slot));
+        drop(l.write(&mut slot));
+        {
+            let mut slot2 = RwSlot::new();
+            let _a = l.read(&mut slot);
+            let _b = l.read(&mut slot2);
+        }
+        drop(l.write(&mut slot));
+    }
+
+    #[test]
+    fn frob() {
+        static LOCK: RwLock<()> = RwLock::new(());
+        static WRITERS: AtomicUsize = AtomicUsize::new(0);
+
+        const N: u32 = 6;
+        const M: u32 = 300;
+
+        let threads: Vec<_> = (0..N).map(|i| {
+            thread::spawn(move|| {
+                let mut slot = RwSlot::new();
+                for j in 0..M {
+                    if (i + j) % 3 == 0 {
+                        let _g = LOCK.write(&mut slot);
+                        assert_eq!(WRITERS.fetch_add(1, Ordering::SeqCst), 0);
+                        WRITERS.fetch_sub(1, Ordering::SeqCst);
+                    } else {
+                        let _g = LOCK.read(&mut slot);
+                        assert_eq!(WRITERS.load(Ordering::SeqCst), 0);
+                    }
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_rw_arc() {
+        let arc = Arc::new(RwLock::new(0));
+        let arc2 = arc.clone();
+        let (tx, rx) = channel();
+
+        thread::spawn(move|| {
+            let mut slot = RwSlot::new();
+            let mut lock = arc2.write(&mut slot);
+            for _ in 0..10 {
+                let tmp = *lock;
+                *lock = -1;
+                thread::yield_now();
+                *lock = tmp + 1;
+            }
+            tx.send(()).unwrap();
+        });
+
+        // Readers try to catch the writer in the act
+        let mut children = Vec::new();
+        for _ in 0..5 {
+            let arc3 = arc.clone();
+            children.push(thread::spawn(move|| {
+                let mut slot = RwSlot::new();
+                let lock = arc3.read(&mut slot);
+                assert!(*lock >= 0);
+            }));
+        }
+
+        // Wait for children to pass their asserts
+        for r in children {
+            assert!(r.join().is_ok());
+        }
+
+        // Wait for writer to finish
+        rx.recv().unwrap();
+        let mut slot = RwSlot::new();
+        let lock = arc.read(&mut slot);
+        assert_eq!(*lock, 10);
+    }
+
+    #[test]
+    fn test_into_inner() {
+        let m = RwLock::new(NonCopy(10));
+        assert_eq!(m.into_inner(), NonCopy(10));
+    }
+
+    #[test]
+    fn test_get_mut() {
+        let mut m = RwLock::new(NonCopy(10));
+        *m.get_mut() = NonCopy(20);
+        assert_eq!(m.into_inner(), NonCopy(20));
+    }
+
+    #[test]
+    fn test_rwlock_unsized() {
+        let mut slot = RwSlot::new();
+        let rw: &RwLock<[i32]> = &RwLock::new([1, 2, 3]);
+        {
+            let b = &mut *rw.write(&mut slot);
+            b[0] = 4;
+            b[2] = 5;
+        }
+        let comp: &[i32] = &[4, 2, 5];
+        assert_eq!(&*rw.read(&mut slot), comp);
+    }
+}
@@ -0,0 +1,133 @@
+// Contention statistics for `Mutex`. Without the `stats` feature, the counters take up no space and counting does
+// nothing, so the lock paths compile to exactly what they would be without them.
+
+#[cfg(feature = "stats")]
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A snapshot of the contention statistics of a `Mutex`, from `Mutex::stats`.
+///
+/// The counters are updated without synchronizing with each other, so a snapshot taken while the mutex is in use may
+/// be slightly inconsistent, for example with `acquisitions` not quite equal to `uncontended + contended`.
+#[cfg(feature = "stats")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// The number of times the lock was acquired, by any means.
+    pub acquisitions: usize,
+    /// The number of acquisitions that found the lock free.
+    pub uncontended: usize,
+    /// The number of acquisitions that had to queue up behind another thread.
+    pub contended: usize,
+    /// The number of times `try_lock` found the lock held, or a timed lock gave up.
+    pub try_lock_failures: usize,
+    /// The number of times a queued thread spun while waiting for the lock to be handed over. Once a thread has spun for
+    /// a while it parks, and each time it is woken up without the lock counts too.
+    pub wait_spins: usize,
+    /// The number of times a releasing thread spun while waiting for its successor to finish queueing up.
+    pub release_spins: usize,
+    /// The largest number of threads that were seen queued behind the holder of the lock at once.
+    pub max_queue_depth: usize
+}
+
+// The counters of a lock.
+pub struct Counters {
+    #[cfg(feature = "stats")]
+    acquisitions: AtomicUsize,
+    #[cfg(feature = "stats")]
+    uncontended: AtomicUsize,
+    #[cfg(feature = "stats")]
+    contended: AtomicUsize,
+    #[cfg(feature = "stats")]
+    try_lock_failures: AtomicUsize,
+    #[cfg(feature = "stats")]
+    wait_spins: AtomicUsize,
+    #[cfg(feature = "stats")]
+    release_spins: AtomicUsize,
+    #[cfg(feature = "stats")]
+    max_queue_depth: AtomicUsize,
+    // The number of threads queued behind the holder right now.
+    #[cfg(feature = "stats")]
+    queued: AtomicUsize
+}
+
+#[cfg(feature = "stats")]
+impl Counters {
+    #[allow(clippy::declare_interior_mutable_const)]
+    pub const INIT: Counters = Counters {
+        acquisitions: AtomicUsize::new(0),
+        uncontended: AtomicUsize::new(0),
+        contended: AtomicUsize::new(0),
+        try_lock_failures: AtomicUsize::new(0),
+        wait_spins: AtomicUsize::new(0),
+        release_spins: AtomicUsize::new(0),
+        max_queue_depth: AtomicUsize::new(0),
+        queued: AtomicUsize::new(0)
+    };
+
+    // Called when a thread joins the queue behind another one.
+    pub fn enqueued(&self) {
+        let depth = self.queued.fetch_add(1, Ordering::Relaxed) + 1;
+        self.max_queue_depth.fetch_max(depth, Ordering::Relaxed);
+    }
+
+    // Called when the lock is acquired. If it was `contended`, the thread had called `enqueued` first.
+    pub fn acquired(&self, contended: bool, spins: usize) {
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        if contended {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+            self.contended.fetch_add(1, Ordering::Relaxed);
+            self.wait_spins.fetch_add(spins, Ordering::Relaxed);
+        } else {
+            self.uncontended.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Called when a thread that called `enqueued` leaves the queue without taking the lock.
+    pub fn abandoned(&self, spins: usize) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+        self.wait_spins.fetch_add(spins, Ordering::Relaxed);
+    }
+
+    // Called when `try_lock` or a timed lock fails.
+    pub fn failed(&self) {
+        self.try_lock_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Called when the lock has been released, after spinning `spins` times for a successor.
+    pub fn released(&self, spins: usize) {
+        if spins > 0 {
+            self.release_spins.fetch_add(spins, Ordering::Relaxed);
+        }
+    }
+
+    pub fn snapshot(&self) -> Stats {
+        Stats {
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            uncontended: self.uncontended.load(Ordering::Relaxed),
+            contended: self.contended.load(Ordering::Relaxed),
+            try_lock_failures: self.try_lock_failures.load(Ordering::Relaxed),
+            wait_spins: self.wait_spins.load(Ordering::Relaxed),
+            release_spins: self.release_spins.load(Ordering::Relaxed),
+            max_queue_depth: self.max_queue_depth.load(Ordering::Relaxed)
+        }
+    }
+}
+
+#[cfg(not(feature = "stats"))]
+impl Counters {
+    pub const INIT: Counters = Counters { };
+
+    #[inline(always)]
+    pub fn enqueued(&self) { }
+
+    #[inline(always)]
+    pub fn acquired(&self, _contended: bool, _spins: usize) { }
+
+    #[inline(always)]
+    pub fn abandoned(&self, _spins: usize) { }
+
+    #[inline(always)]
+    pub fn failed(&self) { }
+
+    #[inline(always)]
+    pub fn released(&self, _spins: usize) { }
+}
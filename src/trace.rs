@@ -0,0 +1,581 @@
+//! A trace of what every `Mutex` did, for seeing the order in which a contended lock was handed around. Enabled by the
+//! `trace` feature.
+//!
+//! Every thread that uses a mutex records its lock events into a ring buffer of its own, which holds the last
+//! `CAPACITY` of them. Recording never blocks: the exporter reads the buffers while they are being written, and skips
+//! any event that is overwritten under it. The buffer of a thread that has exited is kept until a new thread starts
+//! using locks, which then takes it over and drops the old thread's events.
+//!
+//! The recorded events can be read with `events`, or written out with `write_chrome_trace` in the Trace Event format
+//! that Perfetto (<https://ui.perfetto.dev>) and `chrome://tracing` can open. There, each mutex shows up as its own
+//! process, with a row for every thread that used it, showing when the thread was queued and when it held the lock.
+//! Arrows follow the lock from each holder to the thread it was handed to.
+
+#[cfg(feature = "trace")]
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering, fence};
+#[cfg(feature = "trace")]
+use core::time::Duration;
+
+#[cfg(feature = "trace")]
+use std::boxed::Box;
+#[cfg(feature = "trace")]
+use std::collections::BTreeMap;
+#[cfg(feature = "trace")]
+use std::format;
+#[cfg(feature = "trace")]
+use std::io;
+#[cfg(feature = "trace")]
+use std::string::{String, ToString};
+#[cfg(feature = "trace")]
+use std::sync::{self, Arc, OnceLock};
+#[cfg(feature = "trace")]
+use std::thread;
+#[cfg(feature = "trace")]
+use std::thread_local;
+#[cfg(feature = "trace")]
+use std::time::Instant;
+#[cfg(feature = "trace")]
+use std::vec::Vec;
+
+/// The number of events each thread keeps.
+#[cfg(feature = "trace")]
+pub const CAPACITY: usize = 4096;
+
+/// What happened to a lock.
+#[cfg(feature = "trace")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    /// The thread found the lock held and queued up behind the holder.
+    Enqueue,
+    /// The thread acquired the lock.
+    Acquire,
+    /// The thread started releasing the lock.
+    Release,
+    /// The thread handed the lock to the next thread in the queue, which will record an `Acquire`.
+    Handoff,
+    /// The thread left the queue without getting the lock.
+    Abandon
+}
+
+#[cfg(feature = "trace")]
+const KINDS: [EventKind; 5] = [EventKind::Enqueue, EventKind::Acquire, EventKind::Release, EventKind::Handoff, EventKind::Abandon];
+
+/// A recorded event.
+#[cfg(feature = "trace")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Event {
+    /// The thread that recorded it, numbered in the order that threads first used a lock.
+    pub thread: usize,
+    /// The mutex, identified by the address of its lock state, as for `LockObserver`.
+    pub lock: usize,
+    pub kind: EventKind,
+    /// When it happened, since the first event of the program.
+    pub time: Duration
+}
+
+#[cfg(feature = "trace")]
+struct Entry {
+    // The index of the event in this entry plus one, or zero while it is being written.
+    stamp: AtomicUsize,
+    time: AtomicU64,
+    lock: AtomicUsize,
+    kind: AtomicUsize
+}
+
+#[cfg(feature = "trace")]
+struct Ring {
+    // The index of the next event. Only the owning thread writes.
+    head: AtomicUsize,
+    // Events before this were cleared.
+    tail: AtomicUsize,
+    entries: Box<[Entry]>
+}
+
+#[cfg(feature = "trace")]
+impl Ring {
+    fn push(&self, lock: usize, kind: EventKind) {
+        let index = self.head.load(Ordering::Relaxed);
+        let entry = &self.entries[index % CAPACITY];
+        entry.stamp.store(0, Ordering::Relaxed);
+        fence(Ordering::Release);
+        entry.time.store(now(), Ordering::Relaxed);
+        entry.lock.store(lock, Ordering::Relaxed);
+        entry.kind.store(kind as usize, Ordering::Relaxed);
+        entry.stamp.store(index + 1, Ordering::Release);
+        self.head.store(index + 1, Ordering::Release);
+    }
+
+    fn read(&self, thread: usize, events: &mut Vec<Event>) {
+        let head = self.head.load(Ordering::Acquire);
+        let start = self.tail.load(Ordering::Relaxed).max(head.saturating_sub(CAPACITY));
+        for index in start..head {
+            let entry = &self.entries[index % CAPACITY];
+            if entry.stamp.load(Ordering::Acquire) != index + 1 {
+                continue;
+            }
+            let time = entry.time.load(Ordering::Relaxed);
+            let lock = entry.lock.load(Ordering::Relaxed);
+            let kind = entry.kind.load(Ordering::Relaxed);
+            fence(Ordering::Acquire);
+            // The entry might have been reused while we read it.
+            if entry.stamp.load(Ordering::Relaxed) != index + 1 {
+                continue;
+            }
+            events.push(Event {
+                thread,
+                lock,
+                kind: KINDS[kind],
+                time: Duration::from_nanos(time)
+            });
+        }
+    }
+}
+
+#[cfg(feature = "trace")]
+static EPOCH: OnceLock<Instant> = OnceLock::new();
+
+// A ring, and the thread that is using it, or was the last to.
+#[cfg(feature = "trace")]
+struct Owned {
+    ring: Arc<Ring>,
+    thread: usize,
+    name: Option<String>
+}
+
+#[cfg(feature = "trace")]
+struct Rings {
+    owned: Vec<Owned>,
+    // The indices in `owned` of the rings whose threads have exited.
+    free: Vec<usize>,
+    next_thread: usize
+}
+
+#[cfg(feature = "trace")]
+static RINGS: sync::Mutex<Rings> = sync::Mutex::new(Rings {
+    owned: Vec::new(),
+    free: Vec::new(),
+    next_thread: 0
+});
+
+// A thread's claim on a ring, which gives it up when the thread exits.
+#[cfg(feature = "trace")]
+struct Registration {
+    index: usize,
+    ring: Arc<Ring>
+}
+
+#[cfg(feature = "trace")]
+impl Drop for Registration {
+    fn drop(&mut self) {
+        RINGS.lock().unwrap_or_else(sync::PoisonError::into_inner).free.push(self.index);
+    }
+}
+
+#[cfg(feature = "trace")]
+thread_local! {
+    static RING: Registration = register();
+}
+
+#[cfg(feature = "trace")]
+fn now() -> u64 {
+    EPOCH.get_or_init(Instant::now).elapsed().as_nanos() as u64
+}
+
+#[cfg(feature = "trace")]
+fn register() -> Registration {
+    let mut rings = RINGS.lock().unwrap_or_else(sync::PoisonError::into_inner);
+    let thread = rings.next_thread;
+    rings.next_thread += 1;
+    let name = thread::current().name().map(|name| name.to_string());
+
+    if let Some(index) = rings.free.pop() {
+        // Readers hold `RINGS` too, so none of them will see the old thread's events attributed to us.
+        let owned = &mut rings.owned[index];
+        owned.ring.tail.store(owned.ring.head.load(Ordering::Relaxed), Ordering::Relaxed);
+        owned.thread = thread;
+        owned.name = name;
+        return Registration { index, ring: owned.ring.clone() };
+    }
+
+    let ring = Arc::new(Ring {
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+        entries: (0..CAPACITY).map(|_| Entry {
+            stamp: AtomicUsize::new(0),
+            time: AtomicU64::new(0),
+            lock: AtomicUsize::new(0),
+            kind: AtomicUsize::new(0)
+        }).collect()
+    });
+    let index = rings.owned.len();
+    rings.owned.push(Owned { ring: ring.clone(), thread, name });
+    Registration { index, ring }
+}
+
+#[cfg(feature = "trace")]
+fn record(lock: usize, kind: EventKind) {
+    // This fails if a lock is used from a thread-local destructor after ours has run.
+    let _ = RING.try_with(|registration| registration.ring.push(lock, kind));
+}
+
+/// Returns every recorded event that is still in the buffers, in the order they happened.
+#[cfg(feature = "trace")]
+pub fn events() -> Vec<Event> {
+    let mut events = Vec::new();
+    let rings = RINGS.lock().unwrap_or_else(sync::PoisonError::into_inner);
+    for owned in &rings.owned {
+        owned.ring.read(owned.thread, &mut events);
+    }
+    drop(rings);
+    events.sort_by_key(|event| event.time);
+    events
+}
+
+/// Forgets all the events recorded so far.
+#[cfg(feature = "trace")]
+pub fn clear() {
+    for owned in &RINGS.lock().unwrap_or_else(sync::PoisonError::into_inner).owned {
+        let ring = &owned.ring;
+        ring.tail.store(ring.head.load(Ordering::Acquire), Ordering::Relaxed);
+    }
+}
+
+// A stretch of time that a thread spent queued for or holding a lock.
+#[cfg(feature = "trace")]
+struct Span {
+    thread: usize,
+    start: Duration,
+    end: Duration,
+    // The chain of handoffs this is part of, and whether it was handed the lock and handed it on.
+    chain: Option<u64>,
+    flow_in: bool,
+    flow_out: bool
+}
+
+#[cfg(feature = "trace")]
+#[derive(Default)]
+struct LockState {
+    pid: usize,
+    queued: BTreeMap<usize, Duration>,
+    held: Option<Span>,
+    // The last holder, kept until we know whether it handed the lock on.
+    released: Option<Span>,
+    handed_off: Option<u64>
+}
+
+/// Writes the recorded events as a JSON trace in the Chrome Trace Event format.
+///
+/// ```
+/// # extern crate mcs;
+/// use mcs::{Mutex, Slot};
+/// use std::fs::File;
+///
+/// # fn main() {
+/// # fn f() -> std::io::Result<()> {
+/// let lock = Mutex::new(0);
+/// let mut slot = Slot::new();
+/// *lock.lock(&mut slot).unwrap() += 1;
+///
+/// mcs::trace::write_chrome_trace(File::create("locks.json")?)?;
+/// # Ok(())
+/// # }
+/// # }
+/// ```
+#[cfg(feature = "trace")]
+pub fn write_chrome_trace<W: io::Write>(mut out: W) -> io::Result<()> {
+    let names: BTreeMap<usize, Option<String>> = RINGS.lock().unwrap_or_else(sync::PoisonError::into_inner)
+        .owned.iter().map(|owned| (owned.thread, owned.name.clone())).collect();
+
+    let mut locks: BTreeMap<usize, LockState> = BTreeMap::new();
+    let mut threads = Vec::new();
+    let mut chains = 0;
+    let mut first = true;
+    out.write_all(b"{\"traceEvents\":[\n")?;
+
+    for event in events() {
+        let next_pid = locks.len() + 1;
+        let state = locks.entry(event.lock).or_insert_with(|| LockState { pid: next_pid, ..LockState::default() });
+        if state.pid == next_pid {
+            let name = format!("mutex {:#x}", event.lock);
+            write_metadata(&mut out, &mut first, "process_name", state.pid, None, &name)?;
+        }
+        if !threads.contains(&(state.pid, event.thread)) {
+            threads.push((state.pid, event.thread));
+            let name = match names.get(&event.thread) {
+                Some(Some(name)) => format!("{} (thread {})", name, event.thread),
+                _ => format!("thread {}", event.thread)
+            };
+            write_metadata(&mut out, &mut first, "thread_name", state.pid, Some(event.thread), &name)?;
+        }
+
+        match event.kind {
+            EventKind::Enqueue => {
+                state.queued.insert(event.thread, event.time);
+            },
+            EventKind::Acquire => {
+                if let Some(span) = state.released.take() {
+                    write_span(&mut out, &mut first, "held", state.pid, &span)?;
+                }
+                if let Some(start) = state.queued.remove(&event.thread) {
+                    let span = Span { thread: event.thread, start, end: event.time, chain: None, flow_in: false, flow_out: false };
+                    write_span(&mut out, &mut first, "queued", state.pid, &span)?;
+                }
+                let chain = state.handed_off.take();
+                state.held = Some(Span {
+                    thread: event.thread,
+                    start: event.time,
+                    end: event.time,
+                    chain,
+                    flow_in: chain.is_some(),
+                    flow_out: false
+                });
+            },
+            EventKind::Release => {
+                if let Some(mut span) = state.held.take() {
+                    span.end = event.time;
+                    if let Some(span) = state.released.replace(span) {
+                        write_span(&mut out, &mut first, "held", state.pid, &span)?;
+                    }
+                }
+            },
+            EventKind::Handoff => {
+                if let Some(span) = state.released.as_mut() {
+                    let chain = *span.chain.get_or_insert_with(|| { chains += 1; chains });
+                    span.end = event.time;
+                    span.flow_out = true;
+                    state.handed_off = Some(chain);
+                }
+            },
+            EventKind::Abandon => {
+                if let Some(start) = state.queued.remove(&event.thread) {
+                    let span = Span { thread: event.thread, start, end: event.time, chain: None, flow_in: false, flow_out: false };
+                    write_span(&mut out, &mut first, "gave up", state.pid, &span)?;
+                }
+            }
+        }
+    }
+
+    for state in locks.values() {
+        if let Some(ref span) = state.released {
+            write_span(&mut out, &mut first, "held", state.pid, span)?;
+        }
+    }
+
+    out.write_all(b"\n]}\n")
+}
+
+#[cfg(feature = "trace")]
+fn separator<W: io::Write>(out: &mut W, first: &mut bool) -> io::Result<()> {
+    if !*first {
+        out.write_all(b",\n")?;
+    }
+    *first = false;
+    Ok(())
+}
+
+#[cfg(feature = "trace")]
+fn write_metadata<W: io::Write>(out: &mut W, first: &mut bool, kind: &str, pid: usize, tid: Option<usize>, name: &str) -> io::Result<()> {
+    separator(out, first)?;
+    write!(out, "{{\"name\":\"{}\",\"ph\":\"M\",\"pid\":{},\"tid\":{},\"args\":{{\"name\":\"", kind, pid, tid.unwrap_or(0))?;
+    write_escaped(out, name)?;
+    out.write_all(b"\"}}")
+}
+
+#[cfg(feature = "trace")]
+fn write_span<W: io::Write>(out: &mut W, first: &mut bool, name: &str, pid: usize, span: &Span) -> io::Result<()> {
+    separator(out, first)?;
+    write!(out, "{{\"name\":\"{}\",\"ph\":\"X\",\"pid\":{},\"tid\":{},\"ts\":", name, pid, span.thread)?;
+    write_micros(out, span.start)?;
+    out.write_all(b",\"dur\":")?;
+    write_micros(out, span.end.saturating_sub(span.start))?;
+    if let Some(chain) = span.chain {
+        write!(out, ",\"bind_id\":{}", chain)?;
+        if span.flow_in {
+            out.write_all(b",\"flow_in\":true")?;
+        }
+        if span.flow_out {
+            out.write_all(b",\"flow_out\":true")?;
+        }
+    }
+    out.write_all(b"}")
+}
+
+// Trace timestamps are in microseconds.
+#[cfg(feature = "trace")]
+fn write_micros<W: io::Write>(out: &mut W, time: Duration) -> io::Result<()> {
+    let nanos = time.as_nanos();
+    write!(out, "{}.{:03}", nanos / 1000, nanos % 1000)
+}
+
+#[cfg(feature = "trace")]
+fn write_escaped<W: io::Write>(out: &mut W, s: &str) -> io::Result<()> {
+    for c in s.chars() {
+        match c {
+            '"' => out.write_all(b"\\\"")?,
+            '\\' => out.write_all(b"\\\\")?,
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32)?,
+            c => write!(out, "{}", c)?
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "trace")]
+pub(crate) fn enqueue(lock: usize) {
+    record(lock, EventKind::Enqueue);
+}
+
+#[cfg(feature = "trace")]
+pub(crate) fn acquire(lock: usize) {
+    record(lock, EventKind::Acquire);
+}
+
+#[cfg(feature = "trace")]
+pub(crate) fn release(lock: usize) {
+    record(lock, EventKind::Release);
+}
+
+#[cfg(feature = "trace")]
+pub(crate) fn handoff(lock: usize) {
+    record(lock, EventKind::Handoff);
+}
+
+#[cfg(feature = "trace")]
+pub(crate) fn abandon(lock: usize) {
+    record(lock, EventKind::Abandon);
+}
+
+#[cfg(not(feature = "trace"))]
+#[inline(always)]
+pub(crate) fn enqueue(_lock: usize) { }
+
+#[cfg(not(feature = "trace"))]
+#[inline(always)]
+pub(crate) fn acquire(_lock: usize) { }
+
+#[cfg(not(feature = "trace"))]
+#[inline(always)]
+pub(crate) fn release(_lock: usize) { }
+
+#[cfg(not(feature = "trace"))]
+#[inline(always)]
+pub(crate) fn handoff(_lock: usize) { }
+
+#[cfg(not(feature = "trace"))]
+#[inline(always)]
+pub(crate) fn abandon(_lock: usize) { }
+
+#[cfg(all(test, feature = "trace"))]
+mod test {
+    use super::{CAPACITY, EventKind, RING, RINGS, events, now, write_chrome_trace};
+    use mutex::{Mutex, Slot};
+
+    use std::format;
+    use std::string::String;
+    use std::sync::Arc;
+    use std::thread;
+    use std::vec::Vec;
+
+    // Other tests record events at the same time, so only look at `lock`. Earlier tests might have had a mutex at the
+    // same address, so only look after `since`.
+    fn kinds_of(lock: &Mutex<()>, since: u64) -> Vec<(usize, EventKind)> {
+        let id = lock.id();
+        events().into_iter()
+            .filter(|event| event.lock == id && event.time.as_nanos() as u64 >= since)
+            .map(|event| (event.thread, event.kind))
+            .collect()
+    }
+
+    fn current_thread() -> usize {
+        RING.with(|registration| RINGS.lock().unwrap().owned[registration.index].thread)
+    }
+
+    #[test]
+    fn records_uncontended() {
+        let since = now();
+        let m = Mutex::new(());
+        let mut slot = Slot::new();
+        drop(m.lock(&mut slot).unwrap());
+        drop(m.try_lock(&mut slot).unwrap());
+        let me = current_thread();
+        assert_eq!(kinds_of(&m, since), [
+            (me, EventKind::Acquire), (me, EventKind::Release),
+            (me, EventKind::Acquire), (me, EventKind::Release)
+        ]);
+    }
+
+    #[test]
+    fn records_handoff() {
+        let since = now();
+        let m = Arc::new(Mutex::new(()));
+        let mut slot = Slot::new();
+        let g = m.lock(&mut slot).unwrap();
+        let m2 = m.clone();
+        let thread = thread::spawn(move|| {
+            let mut slot = Slot::new();
+            drop(m2.lock(&mut slot).unwrap());
+            current_thread()
+        });
+        while !g.has_waiters() {
+            thread::yield_now();
+        }
+        drop(g);
+        let other = thread.join().unwrap();
+        let me = current_thread();
+
+        let kinds = kinds_of(&m, since);
+        // The other thread is waiting as soon as it joins the queue, which might be before it records that it did.
+        let enqueue = kinds.iter().position(|&kind| kind == (other, EventKind::Enqueue)).unwrap();
+        let mut rest = kinds.clone();
+        rest.remove(enqueue);
+        assert_eq!(rest, [
+            (me, EventKind::Acquire), (me, EventKind::Release), (me, EventKind::Handoff),
+            (other, EventKind::Acquire), (other, EventKind::Release)
+        ]);
+
+        let mut json = Vec::new();
+        write_chrome_trace(&mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.starts_with("{\"traceEvents\":["));
+        assert!(json.contains("\"name\":\"process_name\",\"ph\":\"M\",\"pid\":"));
+        assert!(json.contains(&format!("mutex {:#x}", m.id())));
+        assert!(json.contains("\"flow_out\":true"));
+        assert!(json.contains("\"flow_in\":true"));
+        assert!(json.contains("\"name\":\"queued\""));
+    }
+
+    #[test]
+    fn ring_wraps() {
+        let since = now();
+        let m = Mutex::new(());
+        let mut slot = Slot::new();
+        for _ in 0..CAPACITY {
+            drop(m.lock(&mut slot).unwrap());
+        }
+        let kinds = kinds_of(&m, since);
+        assert!(kinds.len() <= CAPACITY);
+        assert!(kinds.len() >= CAPACITY / 2);
+        assert_eq!(kinds.last().unwrap().1, EventKind::Release);
+    }
+
+    #[test]
+    fn reuses_rings_of_exited_threads() {
+        let rings = || RINGS.lock().unwrap().owned.len();
+        let before = rings();
+        let m = Arc::new(Mutex::new(()));
+        let mut threads = Vec::new();
+        for _ in 0..50 {
+            let m = m.clone();
+            threads.push(thread::spawn(move|| {
+                let mut slot = Slot::new();
+                drop(m.lock(&mut slot).unwrap());
+                current_thread()
+            }).join().unwrap());
+        }
+        // Other tests might be starting threads at the same time, but not nearly this many.
+        assert!(rings() - before < 10);
+        threads.sort();
+        threads.dedup();
+        assert_eq!(threads.len(), 50);
+    }
+}
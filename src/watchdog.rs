@@ -0,0 +1,645 @@
+//! A watchdog that reports threads stuck waiting for a `Mutex`, enabled by the `watchdog` feature.
+//!
+//! Once started with `start` or `start_with`, the watchdog keeps track of every thread or task waiting for a `Mutex` to
+//! be handed over, whether in `lock`, `lock_timeout` or `lock_async`, of every thread that is releasing a lock and
+//! waiting for its successor to finish queueing up, and of every thread waiting in `Condvar::wait`. A background thread
+//! checks on them, and reports each wait that has gone on for longer than the threshold once, with the mutex, the
+//! thread that holds it and how many threads are queued for it. This catches guards that were leaked with `mem::forget`
+//! and holders that have stalled. The watchdog runs until the `Watchdog` returned by `start` is dropped.
+//!
+//! Until the watchdog is started, locks only check whether it has been. After that, every acquisition notes which thread
+//! holds the lock, so that holders can be named in reports, and every thread that waits publishes what it is waiting
+//! for in a record of its own, which the watchdog thread scans. Waiting never takes a lock shared with other threads,
+//! except that an async task gets a record of its own when it first has to wait. Locks acquired before the watchdog
+//! was started are reported without a holder.
+
+#[cfg(feature = "watchdog")]
+use core::fmt;
+#[cfg(feature = "watchdog")]
+use core::hint;
+#[cfg(feature = "watchdog")]
+use core::ptr;
+#[cfg(feature = "watchdog")]
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+#[cfg(feature = "watchdog")]
+use core::time::Duration;
+
+#[cfg(feature = "watchdog")]
+use std::boxed::Box;
+#[cfg(feature = "watchdog")]
+use std::collections::BTreeMap;
+#[cfg(feature = "watchdog")]
+use std::eprintln;
+#[cfg(feature = "watchdog")]
+use std::format;
+#[cfg(feature = "watchdog")]
+use std::io;
+#[cfg(feature = "watchdog")]
+use std::string::{String, ToString};
+#[cfg(feature = "watchdog")]
+use std::sync::{self, Arc, OnceLock};
+#[cfg(feature = "watchdog")]
+use std::sync::mpsc::{self, RecvTimeoutError};
+#[cfg(feature = "watchdog")]
+use std::thread;
+#[cfg(feature = "watchdog")]
+use std::thread_local;
+#[cfg(feature = "watchdog")]
+use std::time::Instant;
+#[cfg(feature = "watchdog")]
+use std::vec::Vec;
+
+/// What a stuck thread is waiting for.
+#[cfg(feature = "watchdog")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WaitKind {
+    /// It is queued for the lock, waiting for it to be handed over.
+    Acquire,
+    /// It is releasing the lock, and waiting for the thread queued behind it to finish queueing up.
+    Release,
+    /// It is waiting in `Condvar::wait` to be notified.
+    Notify
+}
+
+/// A report of a thread that has been waiting for too long.
+#[cfg(feature = "watchdog")]
+#[derive(Clone, Debug)]
+pub struct Stall {
+    /// The mutex, identified by the address of its lock state, as for `LockObserver`. For `WaitKind::Notify`, this is
+    /// the address of the condition variable instead.
+    pub lock: usize,
+    /// Whether the thread is waiting to acquire the lock or to finish releasing it.
+    pub kind: WaitKind,
+    /// How long the thread had been waiting when it was reported.
+    pub waited: Duration,
+    /// The waiting thread, or async task.
+    pub waiter: String,
+    /// The thread holding the lock, if it was known. Always `None` for `WaitKind::Notify`.
+    pub holder: Option<String>,
+    /// The number of threads and tasks queued for the lock, or waiting on the condition variable, that are being
+    /// watched.
+    pub queue_depth: usize
+}
+
+#[cfg(feature = "watchdog")]
+impl fmt::Display for Stall {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let doing = match self.kind {
+            WaitKind::Acquire => "waiting to acquire mutex",
+            WaitKind::Release => "waiting for its successor while releasing mutex",
+            WaitKind::Notify => "waiting for a notification on condvar"
+        };
+        write!(f, "mcs watchdog: {} has been {} {:#x} for {:?}", self.waiter, doing, self.lock, self.waited)?;
+        if self.kind == WaitKind::Notify {
+            return write!(f, " ({} waiting)", self.queue_depth);
+        }
+        match self.holder {
+            Some(ref holder) => write!(f, " (held by {}", holder)?,
+            None => write!(f, " (holder unknown")?
+        }
+        write!(f, ", {} queued)", self.queue_depth)
+    }
+}
+
+// Watcher states
+#[cfg(feature = "watchdog")]
+const IDLE: usize = 0;
+#[cfg(feature = "watchdog")]
+const WAITING: usize = 1;
+// The watchdog is looking at the wait, which can't end until it is done.
+#[cfg(feature = "watchdog")]
+const PINNED: usize = 2;
+
+// A thread that has used a lock since the watchdog was started, or an async task waiting for a lock, and what it is
+// waiting for. Only the thread or task itself writes to it, so waiting never takes a shared lock; the watchdog pins a
+// wait while it reads it, which keeps the lock it is for alive.
+#[cfg(feature = "watchdog")]
+struct Watcher {
+    // Ids start at 1 and are never reused, so a lock leaked by a thread that has exited can't be blamed on another.
+    id: usize,
+    name: Option<String>,
+    // Whether this is an async task, which only has a watcher for as long as it is waiting.
+    task: bool,
+    state: AtomicUsize,
+    // The number of waits so far, so that each one is reported only once.
+    waits: AtomicUsize,
+    lock: AtomicUsize,
+    holder: AtomicPtr<Holder>,
+    kind: AtomicUsize,
+    // When the wait started, in nanoseconds since `EPOCH`.
+    since: AtomicU64
+}
+
+// A thread's watcher, which leaves `THREADS` when the thread exits.
+#[cfg(feature = "watchdog")]
+struct Registration {
+    watcher: Arc<Watcher>
+}
+
+#[cfg(feature = "watchdog")]
+impl Drop for Registration {
+    fn drop(&mut self) {
+        THREADS.lock().unwrap_or_else(sync::PoisonError::into_inner).retain(|watcher| !Arc::ptr_eq(watcher, &self.watcher));
+    }
+}
+
+#[cfg(feature = "watchdog")]
+static NEXT_THREAD: AtomicUsize = AtomicUsize::new(1);
+
+// The threads that are alive and have used a lock. Only changed when a thread first uses a lock, or exits.
+#[cfg(feature = "watchdog")]
+static THREADS: sync::Mutex<Vec<Arc<Watcher>>> = sync::Mutex::new(Vec::new());
+
+#[cfg(feature = "watchdog")]
+thread_local! {
+    static THREAD: Registration = Registration {
+        watcher: Watcher::register(thread::current().name().map(|name| name.to_string()), false)
+    };
+}
+
+#[cfg(feature = "watchdog")]
+impl Watcher {
+    // Makes a new watcher, and adds it to `THREADS`.
+    fn register(name: Option<String>, task: bool) -> Arc<Watcher> {
+        let watcher = Arc::new(Watcher {
+            id: NEXT_THREAD.fetch_add(1, Ordering::Relaxed),
+            name,
+            task,
+            state: AtomicUsize::new(IDLE),
+            waits: AtomicUsize::new(0),
+            lock: AtomicUsize::new(0),
+            holder: AtomicPtr::new(ptr::null_mut()),
+            kind: AtomicUsize::new(0),
+            since: AtomicU64::new(0)
+        });
+        THREADS.lock().unwrap_or_else(sync::PoisonError::into_inner).push(watcher.clone());
+        watcher
+    }
+
+    // Publishes a wait for `lock`, held by `holder` if that is known, until the returned `Watch` is dropped.
+    fn watch(this: Arc<Watcher>, lock: usize, holder: *const Holder, kind: WaitKind) -> Watch {
+        this.lock.store(lock, Ordering::Relaxed);
+        this.holder.store(holder as *mut Holder, Ordering::Relaxed);
+        this.kind.store(kind as usize, Ordering::Relaxed);
+        this.since.store(now(), Ordering::Relaxed);
+        this.waits.store(this.waits.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+        this.state.store(WAITING, Ordering::Release);
+        Watch { watcher: Some(this) }
+    }
+}
+
+// The current thread's watcher, unless its thread-local destructors have already run.
+#[cfg(feature = "watchdog")]
+fn thread_watcher() -> Option<Arc<Watcher>> {
+    THREAD.try_with(|registration| registration.watcher.clone()).ok()
+}
+
+#[cfg(feature = "watchdog")]
+fn current_thread() -> usize {
+    // This fails if a lock is used from a thread-local destructor after ours has run.
+    THREAD.try_with(|registration| registration.watcher.id).unwrap_or(0)
+}
+
+#[cfg(feature = "watchdog")]
+fn describe(id: usize, threads: &[Arc<Watcher>]) -> String {
+    match threads.iter().find(|watcher| watcher.id == id) {
+        Some(watcher) if watcher.task => format!("async task #{}", id),
+        Some(watcher) => match watcher.name {
+            Some(ref name) => format!("thread {:?} (#{})", name, id),
+            None => format!("unnamed thread #{}", id)
+        },
+        None => format!("thread #{}, which has exited", id)
+    }
+}
+
+#[cfg(feature = "watchdog")]
+static EPOCH: OnceLock<Instant> = OnceLock::new();
+
+#[cfg(feature = "watchdog")]
+fn now() -> u64 {
+    EPOCH.get_or_init(Instant::now).elapsed().as_nanos() as u64
+}
+
+#[cfg(feature = "watchdog")]
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// The running watchdog, returned by `start` and `start_with`. Dropping this stops the watchdog, and waits for its
+/// thread to exit.
+#[cfg(feature = "watchdog")]
+#[derive(Debug)]
+#[must_use = "the watchdog stops as soon as this is dropped"]
+pub struct Watchdog {
+    stop: mpsc::Sender<()>,
+    thread: Option<thread::JoinHandle<()>>
+}
+
+#[cfg(feature = "watchdog")]
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+        if let Some(thread) = self.thread.take() {
+            // If `report` panicked, the watchdog has already stopped.
+            let _ = thread.join();
+        }
+        STARTED.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Starts the watchdog, printing a report to stderr for every thread that has waited for longer than `threshold`.
+///
+/// # Errors
+///
+/// Returns an error if `threshold` is zero, if the watchdog is already running, or if its thread could not be spawned.
+#[cfg(feature = "watchdog")]
+pub fn start(threshold: Duration) -> io::Result<Watchdog> {
+    start_with(threshold, |stall| eprintln!("{}", stall))
+}
+
+/// Starts the watchdog, calling `report` for every thread that has waited for longer than `threshold`.
+///
+/// `report` is called from the watchdog's own thread. Each wait is reported at most once. The watchdog runs until the
+/// returned `Watchdog` is dropped, after which it can be started again.
+///
+/// ```
+/// # extern crate mcs;
+/// use std::time::Duration;
+///
+/// # fn main() {
+/// let watchdog = mcs::watchdog::start_with(Duration::from_secs(10), |stall| {
+///     eprintln!("{}", stall);
+/// }).unwrap();
+/// // ...
+/// drop(watchdog);
+/// # }
+/// ```
+///
+/// # Errors
+///
+/// Returns an error if `threshold` is zero, if the watchdog is already running, or if its thread could not be spawned.
+#[cfg(feature = "watchdog")]
+pub fn start_with<F: Fn(&Stall) + Send + 'static>(threshold: Duration, report: F) -> io::Result<Watchdog> {
+    if threshold.is_zero() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "the mcs watchdog threshold must not be zero"));
+    }
+    if STARTED.swap(true, Ordering::Relaxed) {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, "the mcs watchdog is already running"));
+    }
+    let (stop, stopped) = mpsc::channel();
+    let report: Box<dyn Fn(&Stall) + Send> = Box::new(report);
+    let spawned = thread::Builder::new().name("mcs-watchdog".to_string()).spawn(move|| {
+        let mut reported = BTreeMap::new();
+        while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(threshold / 2) {
+            for stall in check(threshold, &mut reported) {
+                report(&stall);
+            }
+        }
+    });
+    match spawned {
+        Ok(thread) => Ok(Watchdog {
+            stop,
+            thread: Some(thread)
+        }),
+        Err(err) => {
+            STARTED.store(false, Ordering::Relaxed);
+            Err(err)
+        }
+    }
+}
+
+// A wait, as seen by the watchdog.
+#[cfg(feature = "watchdog")]
+struct Seen {
+    thread: usize,
+    wait: usize,
+    lock: usize,
+    kind: WaitKind,
+    since: u64,
+    holder: usize
+}
+
+// Finds the waits that have gone on for longer than `threshold`, other than the ones in `reported`, which maps each
+// thread to the last of its waits that was reported.
+#[cfg(feature = "watchdog")]
+fn check(threshold: Duration, reported: &mut BTreeMap<usize, usize>) -> Vec<Stall> {
+    let threads = THREADS.lock().unwrap_or_else(sync::PoisonError::into_inner).clone();
+    let now = now();
+    let mut seen = Vec::new();
+    for watcher in &threads {
+        if watcher.state.compare_exchange(WAITING, PINNED, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            continue;
+        }
+        let kind = match watcher.kind.load(Ordering::Relaxed) {
+            0 => WaitKind::Acquire,
+            1 => WaitKind::Release,
+            _ => WaitKind::Notify
+        };
+        // A thread that is releasing the lock has already stopped being its holder, but it still has the lock.
+        let holder = match kind {
+            WaitKind::Acquire => unsafe { (*watcher.holder.load(Ordering::Relaxed)).thread.load(Ordering::Relaxed) },
+            WaitKind::Release => watcher.id,
+            WaitKind::Notify => 0
+        };
+        seen.push(Seen {
+            thread: watcher.id,
+            wait: watcher.waits.load(Ordering::Relaxed),
+            lock: watcher.lock.load(Ordering::Relaxed),
+            kind,
+            since: watcher.since.load(Ordering::Relaxed),
+            holder
+        });
+        watcher.state.store(WAITING, Ordering::Release);
+    }
+
+    let mut depths = BTreeMap::new();
+    for wait in seen.iter().filter(|wait| wait.kind != WaitKind::Release) {
+        *depths.entry(wait.lock).or_insert(0) += 1;
+    }
+    reported.retain(|thread, _| threads.iter().any(|watcher| watcher.id == *thread));
+
+    let mut stalls = Vec::new();
+    for wait in seen {
+        let waited = Duration::from_nanos(now.saturating_sub(wait.since));
+        if waited < threshold || reported.get(&wait.thread) == Some(&wait.wait) {
+            continue;
+        }
+        reported.insert(wait.thread, wait.wait);
+        stalls.push(Stall {
+            lock: wait.lock,
+            kind: wait.kind,
+            waited,
+            waiter: describe(wait.thread, &threads),
+            holder: if wait.holder == 0 { None } else { Some(describe(wait.holder, &threads)) },
+            queue_depth: depths.get(&wait.lock).cloned().unwrap_or(0)
+        });
+    }
+    stalls
+}
+
+// The thread holding a lock.
+pub(crate) struct Holder {
+    #[cfg(feature = "watchdog")]
+    thread: AtomicUsize
+}
+
+// A wait that is being watched, until this is dropped.
+pub(crate) struct Watch {
+    #[cfg(feature = "watchdog")]
+    watcher: Option<Arc<Watcher>>
+}
+
+#[cfg(feature = "watchdog")]
+impl Holder {
+    #[allow(clippy::declare_interior_mutable_const)]
+    pub(crate) const INIT: Holder = Holder {
+        thread: AtomicUsize::new(0)
+    };
+
+    // Called by the thread that just acquired the lock.
+    pub(crate) fn acquired(&self) {
+        if STARTED.load(Ordering::Relaxed) {
+            self.thread.store(current_thread(), Ordering::Relaxed);
+        }
+    }
+
+    // Called by the holder before it releases the lock.
+    pub(crate) fn releasing(&self) {
+        // Not only while the watchdog is running, so that a holder from before it was last stopped isn't left behind.
+        if self.thread.load(Ordering::Relaxed) != 0 {
+            self.thread.store(0, Ordering::Relaxed);
+        }
+    }
+
+    // Starts watching the current thread wait for the lock `lock`, which this is the holder of.
+    fn watch(&self, lock: usize, kind: WaitKind) -> Watch {
+        if !STARTED.load(Ordering::Relaxed) {
+            return Watch::NONE;
+        }
+        match thread_watcher() {
+            Some(watcher) => Watcher::watch(watcher, lock, self, kind),
+            None => Watch::NONE
+        }
+    }
+
+    pub(crate) fn watch_acquire(&self, lock: usize) -> Watch {
+        self.watch(lock, WaitKind::Acquire)
+    }
+
+    pub(crate) fn watch_release(&self, lock: usize) -> Watch {
+        self.watch(lock, WaitKind::Release)
+    }
+
+    // Starts watching an async task wait for the lock `lock`, which this is the holder of. The task may be polled on
+    // any thread, so it gets a watcher of its own.
+    pub(crate) fn watch_async(&self, lock: usize) -> Watch {
+        if !STARTED.load(Ordering::Relaxed) {
+            return Watch::NONE;
+        }
+        Watcher::watch(Watcher::register(None, true), lock, self, WaitKind::Acquire)
+    }
+}
+
+// Starts watching the current thread wait on the condition variable `condvar`.
+#[cfg(feature = "watchdog")]
+pub(crate) fn watch_notify(condvar: usize) -> Watch {
+    if !STARTED.load(Ordering::Relaxed) {
+        return Watch::NONE;
+    }
+    match thread_watcher() {
+        Some(watcher) => Watcher::watch(watcher, condvar, ptr::null(), WaitKind::Notify),
+        None => Watch::NONE
+    }
+}
+
+#[cfg(feature = "watchdog")]
+impl Watch {
+    pub(crate) const NONE: Watch = Watch { watcher: None };
+}
+
+#[cfg(feature = "watchdog")]
+impl Drop for Watch {
+    fn drop(&mut self) {
+        if let Some(ref watcher) = self.watcher {
+            // If the watchdog is looking at this wait, it will be done in a moment.
+            while watcher.state.compare_exchange_weak(WAITING, IDLE, Ordering::Acquire, Ordering::Relaxed).is_err() {
+                hint::spin_loop();
+            }
+            if watcher.task {
+                THREADS.lock().unwrap_or_else(sync::PoisonError::into_inner).retain(|other| !Arc::ptr_eq(other, watcher));
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "watchdog"))]
+impl Holder {
+    pub(crate) const INIT: Holder = Holder { };
+
+    #[inline(always)]
+    pub(crate) fn acquired(&self) { }
+
+    #[inline(always)]
+    pub(crate) fn releasing(&self) { }
+
+    #[inline(always)]
+    pub(crate) fn watch_acquire(&self, _lock: usize) -> Watch {
+        Watch { }
+    }
+
+    #[inline(always)]
+    pub(crate) fn watch_release(&self, _lock: usize) -> Watch {
+        Watch { }
+    }
+
+    #[inline(always)]
+    pub(crate) fn watch_async(&self, _lock: usize) -> Watch {
+        Watch { }
+    }
+}
+
+#[cfg(not(feature = "watchdog"))]
+#[inline(always)]
+pub(crate) fn watch_notify(_condvar: usize) -> Watch {
+    Watch { }
+}
+
+#[cfg(not(feature = "watchdog"))]
+impl Watch {
+    pub(crate) const NONE: Watch = Watch { };
+}
+
+#[cfg(all(test, feature = "watchdog"))]
+mod test {
+    use super::{Stall, WaitKind, start, start_with};
+    use clock::StdClock;
+    use condvar::Condvar;
+    use mutex::{Mutex, Slot};
+
+    use core::future::Future;
+    use core::mem;
+    use core::pin::pin;
+    use core::task::{Context, Poll, Waker};
+    use core::time::Duration;
+    use std::boxed::Box;
+    use std::io;
+    use std::string::ToString;
+    use std::sync::Arc;
+    use std::sync::mpsc::{Receiver, channel};
+    use std::thread;
+
+    // Waits for the report about `lock`. Other tests might get reported too, if they are slow.
+    fn stall_for(rx: &Receiver<Stall>, lock: usize) -> Stall {
+        loop {
+            let stall = rx.recv().unwrap();
+            if stall.lock == lock {
+                return stall;
+            }
+        }
+    }
+
+    // All in one test, since there is only one watchdog.
+    #[test]
+    fn reports_stuck_waiter() {
+        assert_eq!(start(Duration::ZERO).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        let (tx, rx) = channel();
+        let tx = ::std::sync::Mutex::new(tx);
+        let watchdog = start_with(Duration::from_millis(20), move|stall| {
+            let _ = tx.lock().unwrap().send(stall.clone());
+        }).unwrap();
+        assert_eq!(start(Duration::from_millis(20)).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+
+        let m = Arc::new(Mutex::new(()));
+        let (locked_tx, locked_rx) = channel();
+        let (release_tx, release_rx) = channel::<()>();
+        let m2 = m.clone();
+        let holder = thread::Builder::new().name("holder".to_string()).spawn(move|| {
+            let mut slot = Slot::new();
+            let _g = m2.lock(&mut slot).unwrap();
+            locked_tx.send(()).unwrap();
+            release_rx.recv().unwrap();
+        }).unwrap();
+        locked_rx.recv().unwrap();
+
+        let m3 = m.clone();
+        let waiter = thread::Builder::new().name("waiter".to_string()).spawn(move|| {
+            let mut slot = Slot::new();
+            drop(m3.lock(&mut slot).unwrap());
+        }).unwrap();
+        let m4 = m.clone();
+        let timed = thread::Builder::new().name("timed".to_string()).spawn(move|| {
+            let mut slot = Slot::new();
+            drop(m4.try_lock_for(&mut slot, &StdClock, Duration::from_secs(60)).unwrap());
+        }).unwrap();
+
+        let stall = stall_for(&rx, m.id());
+        assert_eq!(stall.kind, WaitKind::Acquire);
+        assert!(stall.waited >= Duration::from_millis(20));
+        assert!(stall.waiter.contains("\"waiter\"") || stall.waiter.contains("\"timed\""), "{}", stall);
+        assert!(stall.holder.as_ref().unwrap().contains("\"holder\""), "{}", stall);
+        assert!(stall.to_string().contains("waiting to acquire"));
+        // Both waiters get reported, whether they wait with `lock` or `lock_timeout`.
+        let other = stall_for(&rx, m.id());
+        assert_ne!(other.waiter, stall.waiter);
+        assert!(other.waiter.contains("\"waiter\"") || other.waiter.contains("\"timed\""), "{}", other);
+
+        release_tx.send(()).unwrap();
+        holder.join().unwrap();
+        waiter.join().unwrap();
+        timed.join().unwrap();
+
+        // An async task, which is reported while it is queued even though no thread is waiting.
+        {
+            let mut slot1 = Slot::new();
+            let mut slot2 = Slot::new();
+            let g = m.lock(&mut slot1).unwrap();
+            let mut future = pin!(m.lock_async(&mut slot2));
+            let mut cx = Context::from_waker(Waker::noop());
+            assert!(matches!(future.as_mut().poll(&mut cx), Poll::Pending));
+            let stall = stall_for(&rx, m.id());
+            assert!(stall.waiter.contains("async task"), "{}", stall);
+            assert_eq!(stall.queue_depth, 1);
+            drop(g);
+            assert!(matches!(future.as_mut().poll(&mut cx), Poll::Ready(Ok(_))));
+        }
+
+        // A thread waiting on a condition variable that no one notifies.
+        let pair = Arc::new((Mutex::new(false), Condvar::new()));
+        let pair2 = pair.clone();
+        let notified = thread::spawn(move|| {
+            let (lock, cvar) = &*pair2;
+            let mut slot = Slot::new();
+            let mut done = lock.lock(&mut slot).unwrap();
+            while !*done {
+                done = cvar.wait(done).unwrap();
+            }
+        });
+        let stall = stall_for(&rx, &pair.1 as *const Condvar as usize);
+        assert_eq!(stall.kind, WaitKind::Notify);
+        assert_eq!(stall.holder, None);
+        assert!(stall.to_string().contains("waiting for a notification"), "{}", stall);
+        {
+            let mut slot = Slot::new();
+            *pair.0.lock(&mut slot).unwrap() = true;
+            pair.1.notify_all();
+        }
+        notified.join().unwrap();
+
+        // A guard leaked by a thread that has exited.
+        let m: &'static Mutex<()> = Box::leak(Box::new(Mutex::new(())));
+        let leaked = thread::spawn(move|| {
+            let slot: &'static mut Slot = Box::leak(Box::new(Slot::new()));
+            mem::forget(m.lock(slot).unwrap());
+            slot as *const Slot as usize
+        }).join().unwrap();
+        let waiter = thread::spawn(move|| drop(m.lock(&mut Slot::new())));
+        let stall = stall_for(&rx, m.id());
+        assert!(stall.holder.as_ref().unwrap().contains("which has exited"), "{}", stall);
+        // Release the leaked lock, so that the waiter isn't left behind.
+        unsafe { m.unlock(&*(leaked as *const Slot)); }
+        waiter.join().unwrap();
+
+        // Once stopped, the watchdog can be started again.
+        drop(watchdog);
+        drop(start(Duration::from_secs(1)).unwrap());
+    }
+}